@@ -0,0 +1,602 @@
+//! easy-fs 镜像工具
+//!
+//! 在宿主机上创建, 查看和修改 easy-fs 镜像, 不需要启动内核:
+//!
+//! ```text
+//! efs mkfs   <image> <size> [-i inodes] [-b block_size]   创建文件系统, size 可以带 K/M/G 后缀
+//! efs import <image> <host_dir> [dir]                     将宿主机目录树递归地导入到 dir (默认为 /)
+//! efs export <image> <path> <host_path>                   将文件或目录树导出到宿主机
+//! efs ls     <image> [-l] [path]                          列出目录
+//! efs cat    <image> <path>                               输出文件内容
+//! efs mkdir  <image> <path>                               创建目录
+//! efs rm     <image> [-r] <path>                          删除文件或 (递归地删除) 目录
+//! efs mv     <image> <src> <dst>                          移动或重命名
+//! efs stat   <image> <path>                               查看索引节点信息
+//! efs df     <image>                                      查看空间使用情况
+//! efs du     <image> [path]                               统计目录树占用的空间
+//! efs tar    <image> <path> <archive>                     将文件或目录树导出为 tar 归档, archive 为 - 时写到标准输出
+//! efs untar  <image> <archive> [dir]                      将 tar 归档导入到 dir (默认为 /), archive 为 - 时从标准输入读取
+//! ```
+//!
+//! 路径总是相对于镜像的根目录, 以 / 分隔.
+
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+
+use easy_fs::{
+    block_bits, block_cache_sync_all, export_tar, import_tar, BlockDevice, BlockError, CacheMode,
+    DiskInodeType, FileSystem, Inode, TarError, TarSink, TarSource, DEFAULT_BLOCK_SIZE,
+    NAME_LENGTH_LIMIT, SECTOR_SIZE,
+};
+
+type Result<T> = core::result::Result<T, Box<dyn Error>>;
+
+/// 以宿主机上的一个普通文件作为块设备
+struct BlockFile(Mutex<File>);
+
+impl BlockFile {
+    fn seek(file: &mut File, block_id: usize) -> core::result::Result<(), BlockError> {
+        file.seek(SeekFrom::Start((block_id * SECTOR_SIZE) as u64))
+            .map(|_| ())
+            .map_err(|_| BlockError::Io(block_id))
+    }
+}
+
+impl BlockDevice for BlockFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> core::result::Result<(), BlockError> {
+        let mut file = self.0.lock().unwrap();
+        Self::seek(&mut file, block_id)?;
+        file.read_exact(buf).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => BlockError::OutOfRange(block_id),
+            _ => BlockError::Io(block_id),
+        })
+    }
+
+    /// 连续的扇区在文件中也是连续的, 一次 seek 加一次 read 即可
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> core::result::Result<(), BlockError> {
+        self.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> core::result::Result<(), BlockError> {
+        let mut file = self.0.lock().unwrap();
+        Self::seek(&mut file, block_id)?;
+        file.write_all(buf).map_err(|_| BlockError::Io(block_id))
+    }
+}
+
+/// 以宿主机上的读写流作为 tar 归档的来源或去向
+struct TarStream<T>(T);
+
+impl<W: Write> TarSink for TarStream<W> {
+    fn write_all(&mut self, data: &[u8]) -> core::result::Result<(), TarError> {
+        self.0.write_all(data).map_err(|_| TarError::Io)
+    }
+}
+
+impl<R: Read> TarSource for TarStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, TarError> {
+        self.0.read(buf).map_err(|_| TarError::Io)
+    }
+}
+
+/// 每次从宿主机文件读入或写入镜像的字节数
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const USAGE: &str = "usage:
+    efs mkfs   <image> <size> [-i inodes] [-b block_size]
+    efs import <image> <host_dir> [dir]
+    efs export <image> <path> <host_path>
+    efs ls     <image> [-l] [path]
+    efs cat    <image> <path>
+    efs mkdir  <image> <path>
+    efs rm     <image> [-r] <path>
+    efs mv     <image> <src> <dst>
+    efs stat   <image> <path>
+    efs df     <image>
+    efs du     <image> [path]
+    efs frag   <image> [path]
+    efs xattr  <image> <path> [name [value]]
+    efs xattr  <image> -d <path> <name>
+    efs tar    <image> <path> <archive|->
+    efs untar  <image> <archive|-> [dir]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        exit(2);
+    }
+    // 块缓存驻留在全局的 BLOCK_CACHE_MANAGER 中, 进程退出时不会被 drop, 需要手动写回
+    let ret = run(&args[0], &args[1], &args[2..]);
+    let synced = block_cache_sync_all();
+    if let Err(err) = ret.and(synced.map_err(Into::into)) {
+        eprintln!("efs: {}", err);
+        exit(1);
+    }
+}
+
+fn run(cmd: &str, image: &str, args: &[String]) -> Result<()> {
+    if cmd == "mkfs" {
+        return mkfs(image, args);
+    }
+    let fs = open(image)?;
+    let root = FileSystem::root_inode(&fs);
+    let (flags, args) = split_flags(args);
+    let arg =
+        |i: usize| -> Result<&str> { args.get(i).map(|s| s.as_str()).ok_or_else(|| USAGE.into()) };
+    match cmd {
+        "import" => import(&root, Path::new(arg(0)?), args.get(1).map_or("/", |s| s)),
+        "export" => export(&*lookup(&root, arg(0)?)?, Path::new(arg(1)?)),
+        "ls" => ls(&root, args.first().map_or("/", |s| s), flags.contains(&'l')),
+        "cat" => cat(&*lookup(&root, arg(0)?)?),
+        "mkdir" => mkdir(&root, arg(0)?),
+        "rm" => rm(&root, arg(0)?, flags.contains(&'r')),
+        "mv" => mv(&root, arg(0)?, arg(1)?),
+        "stat" => stat(&root, arg(0)?),
+        "df" => df(&fs),
+        "du" => du(&root, args.first().map_or("/", |s| s)),
+        "frag" => frag(&fs, &root, args.first().map_or("/", |s| s)),
+        "xattr" => xattr(&*lookup(&root, arg(0)?)?, &args[1..], flags.contains(&'d')),
+        "tar" => tar(&root, arg(0)?, arg(1)?),
+        "untar" => untar(&root, arg(0)?, args.get(1).map_or("/", |s| s)),
+        _ => Err(USAGE.into()),
+    }
+}
+
+/// 将参数分为单字母选项 (如 -l, -r) 和其余的参数
+fn split_flags(args: &[String]) -> (Vec<char>, Vec<String>) {
+    let mut flags = Vec::new();
+    let mut rest = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('-') {
+            Some(f) if !f.is_empty() => flags.extend(f.chars()),
+            _ => rest.push(arg.clone()),
+        }
+    }
+    (flags, rest)
+}
+
+/// 每条命令结束时 main 都会写回所有的块, 因此以 writeback 模式挂载, 省去每次操作之后的写回
+fn open(image: &str) -> Result<Arc<FileSystem>> {
+    let file = OpenOptions::new().read(true).write(true).open(image)?;
+    Ok(FileSystem::open_with(
+        Arc::new(BlockFile(Mutex::new(file))),
+        CacheMode::WriteBack,
+    )?)
+}
+
+/// 解析带有 K/M/G 后缀的大小
+fn parse_size(s: &str) -> Result<usize> {
+    let (num, unit) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    Ok(num.parse::<usize>()? * unit)
+}
+
+fn mkfs(image: &str, args: &[String]) -> Result<()> {
+    let mut size = None;
+    let mut inodes = None;
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-i" => inodes = Some(args.next().ok_or(USAGE)?.parse::<usize>()?),
+            "-b" => block_size = parse_size(args.next().ok_or(USAGE)?)?,
+            _ => size = Some(parse_size(arg)?),
+        }
+    }
+    let size = size.ok_or(USAGE)?;
+    if !easy_fs::BLOCK_SIZES.contains(&block_size) {
+        return Err(format!("unsupported block size {}", block_size).into());
+    }
+    let total_blocks = size / block_size;
+    // 默认使用一个块的索引节点位图
+    let bits = block_bits(block_size);
+    let inode_bitmap_blocks = inodes.map_or(1, |n| n.div_ceil(bits).max(1));
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(image)?;
+    file.set_len((total_blocks * block_size) as u64)?;
+    let fs = FileSystem::create(
+        Arc::new(BlockFile(Mutex::new(file))),
+        total_blocks as u32,
+        inode_bitmap_blocks as u32,
+        block_size as u32,
+    )?;
+    let statfs = fs.statfs();
+    println!(
+        "{}: {} blocks of {} bytes, {} data blocks, {} inodes",
+        image, total_blocks, block_size, statfs.total_blocks, statfs.total_inodes
+    );
+    Ok(())
+}
+
+/// 将路径拆分为各级名字, 忽略多余的 /
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|name| !name.is_empty()).collect()
+}
+
+/// 查找 path 对应的索引节点
+fn lookup(root: &Arc<Inode>, path: &str) -> Result<Arc<Inode>> {
+    let mut inode = Arc::clone(root);
+    for name in components(path) {
+        if !inode.is_dir()? {
+            return Err(format!("{}: not a directory", path).into());
+        }
+        inode = inode
+            .find(name)?
+            .ok_or_else(|| format!("{}: no such file or directory", path))?;
+    }
+    Ok(inode)
+}
+
+/// 查找 path 的父目录, 返回父目录和最后一级名字
+fn lookup_parent<'a>(root: &Arc<Inode>, path: &'a str) -> Result<(Arc<Inode>, &'a str)> {
+    let mut names = components(path);
+    let name = names
+        .pop()
+        .ok_or_else(|| format!("{}: invalid path", path))?;
+    let parent = lookup(root, &names.join("/"))?;
+    if !parent.is_dir()? {
+        return Err(format!("{}: not a directory", path).into());
+    }
+    Ok((parent, name))
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.len() > NAME_LENGTH_LIMIT {
+        return Err(format!("{}: name longer than {} bytes", name, NAME_LENGTH_LIMIT).into());
+    }
+    Ok(())
+}
+
+/// 在目录 dir 中查找或创建名为 name, 类型为 kind 的索引节点
+fn find_or_create(dir: &Inode, name: &str, kind: DiskInodeType) -> Result<Arc<Inode>> {
+    check_name(name)?;
+    if let Some(inode) = dir.find(name)? {
+        if inode.stat()?.type_ != kind {
+            return Err(format!("{}: already exists with another type", name).into());
+        }
+        return Ok(inode);
+    }
+    Ok(dir.create(name, kind)?.expect("name checked above"))
+}
+
+fn import(root: &Arc<Inode>, host_dir: &Path, path: &str) -> Result<()> {
+    let mut dir = Arc::clone(root);
+    for name in components(path) {
+        dir = find_or_create(&dir, name, DiskInodeType::Directory)?;
+    }
+    import_dir(&dir, host_dir)
+}
+
+fn import_dir(dir: &Inode, host_dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(host_dir)? {
+        let entry = entry?;
+        let host_path = entry.path();
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| format!("{:?}: not a UTF-8 name", name))?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let sub = find_or_create(dir, &name, DiskInodeType::Directory)?;
+            import_dir(&sub, &host_path)?;
+        } else if file_type.is_file() {
+            let inode = find_or_create(dir, &name, DiskInodeType::File)?;
+            inode.clear()?;
+            let mut host_file = File::open(&host_path)?;
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            let mut offset = 0;
+            loop {
+                let len = host_file.read(&mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                inode.write(offset, &buf[..len])?;
+                offset += len;
+            }
+        } else {
+            eprintln!("efs: skipping {}", host_path.display());
+        }
+    }
+    Ok(())
+}
+
+fn export(inode: &Inode, host_path: &Path) -> Result<()> {
+    if inode.is_dir()? {
+        fs::create_dir_all(host_path)?;
+        for name in inode.ls()? {
+            let child = inode.find(&name)?.expect("listed above");
+            export(&child, &host_path.join(name))?;
+        }
+        return Ok(());
+    }
+    let mut host_file = File::create(host_path)?;
+    copy_out(inode, &mut host_file)
+}
+
+/// 将文件内容写到 out
+fn copy_out(inode: &Inode, out: &mut impl Write) -> Result<()> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let len = inode.read(offset, &mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        out.write_all(&buf[..len])?;
+        offset += len;
+    }
+}
+
+/// 导出 path 为 tar 归档, 归档中的路径以 path 的最后一级名字开头; 导出根目录时只包含其中的内容
+fn tar(root: &Arc<Inode>, path: &str, archive: &str) -> Result<()> {
+    let inode = lookup(root, path)?;
+    let out: Box<dyn Write> = match archive {
+        "-" => Box::new(io::stdout().lock()),
+        _ => Box::new(File::create(archive)?),
+    };
+    let mut sink = TarStream(BufWriter::new(out));
+    export_tar(&inode, components(path).pop().unwrap_or(""), &mut sink)?;
+    sink.0.flush()?;
+    Ok(())
+}
+
+fn untar(root: &Arc<Inode>, archive: &str, path: &str) -> Result<()> {
+    let mut dir = Arc::clone(root);
+    for name in components(path) {
+        dir = find_or_create(&dir, name, DiskInodeType::Directory)?;
+    }
+    let input: Box<dyn Read> = match archive {
+        "-" => Box::new(io::stdin().lock()),
+        _ => Box::new(File::open(archive)?),
+    };
+    let summary = import_tar(&dir, &mut TarStream(BufReader::new(input)))?;
+    println!(
+        "{} files, {} directories, {} bytes imported",
+        summary.files, summary.dirs, summary.bytes
+    );
+    if summary.skipped > 0 {
+        eprintln!("efs: skipped {} links or special files", summary.skipped);
+    }
+    Ok(())
+}
+
+fn ls(root: &Arc<Inode>, path: &str, long: bool) -> Result<()> {
+    let inode = lookup(root, path)?;
+    let names = if inode.is_dir()? {
+        inode.ls()?
+    } else {
+        vec![components(path).pop().unwrap_or("/").to_string()]
+    };
+    for name in names {
+        if !long {
+            println!("{}", name);
+            continue;
+        }
+        let child = if inode.is_dir()? {
+            inode.find(&name)?.expect("listed above")
+        } else {
+            Arc::clone(&inode)
+        };
+        let stat = child.stat()?;
+        let kind = match stat.type_ {
+            DiskInodeType::Directory => 'd',
+            DiskInodeType::File => '-',
+        };
+        println!(
+            "{} {:>6} {:>10} {:>6} {}",
+            kind, stat.inode_id, stat.size, stat.blocks, name
+        );
+    }
+    Ok(())
+}
+
+fn cat(inode: &Inode) -> Result<()> {
+    if inode.is_dir()? {
+        return Err("is a directory".into());
+    }
+    copy_out(inode, &mut io::stdout().lock())
+}
+
+fn mkdir(root: &Arc<Inode>, path: &str) -> Result<()> {
+    let (parent, name) = lookup_parent(root, path)?;
+    check_name(name)?;
+    parent
+        .create(name, DiskInodeType::Directory)?
+        .ok_or_else(|| format!("{}: already exists", path))?;
+    Ok(())
+}
+
+fn rm(root: &Arc<Inode>, path: &str, recursive: bool) -> Result<()> {
+    let (parent, name) = lookup_parent(root, path)?;
+    let inode = lookup(root, path)?;
+    if inode.is_dir()? && recursive {
+        for child in inode.ls()? {
+            rm(root, &format!("{}/{}", path, child), true)?;
+        }
+    }
+    drop(inode);
+    if !parent.unlink(name)? {
+        return Err(format!("{}: directory not empty", path).into());
+    }
+    Ok(())
+}
+
+fn mv(root: &Arc<Inode>, src: &str, dst: &str) -> Result<()> {
+    let (src_parent, src_name) = lookup_parent(root, src)?;
+    lookup(root, src)?;
+    // 如果 dst 是一个已经存在的目录, 就移动到它里面
+    let (dst_parent, dst_name) = match lookup(root, dst) {
+        Ok(inode) if inode.is_dir()? => (inode, src_name),
+        _ => lookup_parent(root, dst)?,
+    };
+    check_name(dst_name)?;
+    // 不能把目录移动到它自己或者它的子目录中
+    let src_names = components(src);
+    let mut dst_names = components(dst);
+    dst_names.truncate(src_names.len());
+    if dst_names == src_names {
+        return Err(format!("cannot move {} into itself", src).into());
+    }
+    if !src_parent.rename(src_name, &dst_parent, dst_name)? {
+        return Err(format!("{}: already exists", dst).into());
+    }
+    Ok(())
+}
+
+fn stat(root: &Arc<Inode>, path: &str) -> Result<()> {
+    let inode = lookup(root, path)?;
+    let stat = inode.stat()?;
+    let (block_id, block_offset) = inode.inode_info();
+    println!("  File: {}", path);
+    println!("  Type: {:?}", stat.type_);
+    println!(" Inode: {}", stat.inode_id);
+    println!("  Size: {}", stat.size);
+    println!("Blocks: {}", stat.blocks);
+    println!("  Disk: block {} offset {}", block_id, block_offset);
+    Ok(())
+}
+
+/// 扩展属性: 不给出 name 时列出所有属性; 只给出 name 时打印它的值; 给出 value 时设置它; -d 删除它
+///
+/// 值以 0x 开头时按十六进制解析; 打印时不是 UTF-8 的值也以十六进制显示
+fn xattr(inode: &Inode, args: &[String], delete: bool) -> Result<()> {
+    match (args.first(), args.get(1)) {
+        (Some(name), _) if delete => match inode.remove_xattr(name)? {
+            true => Ok(()),
+            false => Err(format!("{}: no such attribute", name).into()),
+        },
+        (Some(name), Some(value)) => inode
+            .set_xattr(name, &parse_xattr_value(value)?)
+            .map_err(|err| format!("{}: {}", name, err).into()),
+        (Some(name), None) => {
+            let value = inode
+                .get_xattr(name)?
+                .ok_or_else(|| format!("{}: no such attribute", name))?;
+            println!("{}", format_xattr_value(&value));
+            Ok(())
+        }
+        (None, _) => {
+            for name in inode.list_xattr()? {
+                let value = inode.get_xattr(&name)?.expect("listed above");
+                println!("{}={}", name, format_xattr_value(&value));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn parse_xattr_value(value: &str) -> Result<Vec<u8>> {
+    let Some(hex) = value.strip_prefix("0x") else {
+        return Ok(value.as_bytes().to_vec());
+    };
+    if hex.len() % 2 != 0 {
+        return Err(format!("{}: odd number of hex digits", value).into());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+fn format_xattr_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) if !text.starts_with("0x") && !text.chars().any(char::is_control) => {
+            text.to_string()
+        }
+        _ => value.iter().fold(String::from("0x"), |mut hex, byte| {
+            hex.push_str(&format!("{:02x}", byte));
+            hex
+        }),
+    }
+}
+
+fn df(fs: &FileSystem) -> Result<()> {
+    let statfs = fs.statfs();
+    println!("{:>8} {:>10} {:>10} {:>10}", "", "total", "used", "free");
+    println!(
+        "{:>8} {:>10} {:>10} {:>10}",
+        "blocks", statfs.total_blocks, statfs.used_blocks, statfs.free_blocks
+    );
+    println!(
+        "{:>8} {:>10} {:>10} {:>10}",
+        "inodes", statfs.total_inodes, statfs.used_inodes, statfs.free_inodes
+    );
+    println!("block size: {}", statfs.block_size);
+    Ok(())
+}
+
+/// 列出 path 下每一项占用的块数, 最后一行是 path 本身的总计
+fn du(root: &Arc<Inode>, path: &str) -> Result<()> {
+    let inode = lookup(root, path)?;
+    if inode.is_dir()? {
+        for name in inode.ls()? {
+            let usage = inode.find(&name)?.expect("listed above").du()?;
+            println!("{:>8} {:>10} {}", usage.blocks, usage.bytes, name);
+        }
+    }
+    let usage = inode.du()?;
+    println!(
+        "{:>8} {:>10} {} ({} files, {} directories)",
+        usage.blocks, usage.bytes, path, usage.files, usage.dirs
+    );
+    Ok(())
+}
+
+/// 碎片报告: path 下每个文件的数据块分成了几段, 以及整个文件系统空闲空间的碎片情况
+fn frag(fs: &FileSystem, root: &Arc<Inode>, path: &str) -> Result<()> {
+    // (文件数, 分成多于一段的文件数, 总段数)
+    fn walk(inode: &Inode, path: &str, totals: &mut (usize, usize, usize)) -> Result<()> {
+        if inode.is_dir()? {
+            for name in inode.ls()? {
+                let child = inode.find(&name)?.expect("listed above");
+                walk(
+                    &child,
+                    &format!("{}/{}", path.trim_end_matches('/'), name),
+                    totals,
+                )?;
+            }
+            return Ok(());
+        }
+        let extents = inode.extents()?;
+        totals.0 += 1;
+        totals.2 += extents;
+        if extents > 1 {
+            totals.1 += 1;
+            println!("{:>8} {}", extents, path);
+        }
+        Ok(())
+    }
+
+    let mut totals = (0, 0, 0);
+    walk(&*lookup(root, path)?, path, &mut totals)?;
+    let (files, fragmented, extents) = totals;
+    println!(
+        "{} files, {} fragmented, {} extents ({:.2} per file)",
+        files,
+        fragmented,
+        extents,
+        extents as f64 / files.max(1) as f64
+    );
+    let free = fs.free_extents()?;
+    println!(
+        "free space: {} blocks in {} extents, largest {} blocks",
+        fs.statfs().free_blocks,
+        free.count,
+        free.largest
+    );
+    Ok(())
+}
@@ -2,21 +2,27 @@
 //!
 //! 在 fs 布局中存在两类不同的位图, 分别对索引节点和数据块进行管理
 //!
-//! 每个位图都由若干个块组成, 每个块大小为 512 bytes, 即 4096 bits
+//! 每个位图都由若干个块组成, 每个块大小为 block_size bytes (默认 512 bytes, 即 4096 bits)
 //! 每个 bit 都代表一个索引节点/数据块的分配状态,  0 意味着未分配, 而 1 则意味着已经分配出去
 //!
 //! 位图所要做的事情是通过基于 bit 为单位的分配(寻找一个为 0 的 bit 位并设置为 1)
 //! 和回收(将bit位清零)来进行索引节点/数据块的分配和回收
+//!
+//! 除了总是分配编号最小的空闲 bit 的 [`Bitmap::alloc`] 之外,
+//! 还可以从某个 bit 开始向后寻找 (分配提示), 或者一次分配一段连续的 bit ([`Bitmap::alloc_run`]),
+//! 这样同一个文件的数据块在磁盘上尽量连续, 顺序读文件时也就是顺序读磁盘
 
-use std::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
+use core::ops::Range;
 
-use super::{get_block_cache, BlockDevice, BLOCK_BITS};
+use super::{block_bits, get_block_cache, BlockDevice, BlockKind, FsResult};
 
 /// 磁盘块上位图区域的数据以磁盘数据结构 BitmapBlock 的格式进行操作.
-/// BitmapBlock 是一个磁盘数据结构, 它将位图区域中的一个磁盘块解释为长度为 64 的一个 u64 数组,
-/// 每个 u64 打包了一组 64 bits, 于是整个数组包含 64 * 64 = 4096 bits, 且可以以组为单位进行操作
+/// BitmapBlock 是一个磁盘数据结构, 它将位图区域中的一个磁盘块解释为长度为 block_size / 8 的一个 u64 数组,
+/// 每个 u64 打包了一组 64 bits, 对于 512 字节的块, 整个数组包含 64 * 64 = 4096 bits, 且可以以组为单位进行操作
 /// 刚好占用一个磁盘块的大小.
-type BitmapBlock = [u64; 64]; // size = 64 * 64 = 4096 bits = 512 bytes
+/// 最后一个 u64 被保留用来存放校验和, 因此实际可用的 bit 数为 block_bits(block_size).
+type BitmapBlock = [u64]; // size = block_size / 8 * 64 bits = block_size bytes
 
 /// Bitmap 自身是驻留在内存中的,
 /// 但是它能够表示索引节点/数据块区域中的那些磁盘块的分配情况.
@@ -25,13 +31,35 @@ pub struct Bitmap {
     start_block_id: usize,
     /// 位图索引使用的磁盘块数
     blocks_counts: usize,
+    /// 文件系统的块大小
+    block_size: usize,
+    /// 有效的 bit 数; 位图最后一个块中超出的 bit 没有对应的索引节点/数据块, 不会被分配
+    bits: usize,
+}
+
+/// 空闲空间的碎片情况, see [`Bitmap::free_extents`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FreeExtents {
+    /// 连续空闲区间的个数
+    pub count: usize,
+    /// 最长的连续空闲区间的长度
+    pub largest: usize,
 }
 
 impl Bitmap {
-    pub fn new(start_block_id: usize, blocks_counts: usize) -> Self {
+    /// bits 为有效的 bit 数, 不能超过 blocks_counts 个位图块所能容纳的数目
+    pub fn new(
+        start_block_id: usize,
+        blocks_counts: usize,
+        block_size: usize,
+        bits: usize,
+    ) -> Self {
+        assert!(bits <= blocks_counts * block_bits(block_size));
         Self {
             start_block_id,
             blocks_counts,
+            block_size,
+            bits,
         }
     }
 
@@ -45,40 +73,43 @@ impl Bitmap {
     /// 它将会返回分配的 bit 所在的位置, 等同于 索引节点/数据块 的编号.
     ///
     /// 如果所有bit均已经被分配出去了, 则返回 None .
-    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> FsResult<Option<usize>> {
         // 枚举区域中的每个块(编号为 block_id ), 在循环内部我们需要读写这个块, 在块内尝试找到一个空闲的bit并置 1 .
         // 一旦涉及到块的读写, 就需要用到块缓存层提供的接口
         for block_id in 0..self.blocks_counts {
             // 调用 get_block_cache 获取块缓存
             let pos = get_block_cache(
                 // 注意传入的块编号是区域起始块编号 start_block_id 加上区域内的块编号 block_id 得到的块设备上的块编号
-                block_id + self.start_block_id as usize,
+                block_id + self.start_block_id,
+                self.block_size,
+                BlockKind::Bitmap,
                 Arc::clone(block_device),
-            )
+            )?
             // 通过 .lock() 获取块缓存的互斥锁从而可以对块缓存进行访问
             .lock()
-            // 使用 BlockCache::modify 接口.
+            // 使用 BlockCache::modify_slice 接口.
             //
-            // 它传入的偏移量 offset 为 0, 这是因为整个块上只有一个 BitmapBlock , 它的大小恰好为 512 字节, (see BlockCache.cache which is a [u8; BLOCK_SZ])
-            // 因此我们需要从块的开头开始才能访问到完整的 BitmapBlock .
+            // 整个块上只有一个 BitmapBlock , 它的大小恰好为一个块 (see BlockCache.cache which has block_size bytes),
+            // 因此我们需要将整个块解释为一个 u64 数组才能访问到完整的 BitmapBlock .
             //
             // 同时, 传给它的闭包需要显式声明参数类型为 &mut BitmapBlock ,
-            // 不然的话,  BlockCache 的泛型方法 modify/get_mut 无法得知应该用哪个类型来解析块上的数据.
-            // 在声明之后, 编译器才能在这里将两个方法中的泛型 T 实例化为具体类型 BitmapBlock .
+            // 不然的话,  BlockCache 的泛型方法 modify_slice/get_slice_mut 无法得知应该用哪个类型来解析块上的数据.
+            // 在声明之后, 编译器才能在这里将两个方法中的泛型 T 实例化为具体类型 u64 .
             //
-            // 总结一下, 这里 modify 的含义就是:
-            // 从缓冲区偏移量为 0 的位置开始将一段连续的数据(数据的长度随具体类型而定)解析为一个 BitmapBlock 并要对该数据结构进行修改.
+            // 总结一下, 这里 modify_slice 的含义就是:
+            // 将整个缓冲区解析为一个 BitmapBlock 并要对该数据结构进行修改.
             // 在闭包内部, 我们可以使用这个 BitmapBlock 的可变引用 bitmap_block 对它进行访问.
             // read/get_ref 的用法完全相同, 后面将不再赘述.
-            .modify(0, |bitmap_block: &mut BitmapBlock| -> Option<usize> {
+            .modify_slice(|bitmap_block: &mut BitmapBlock| -> Option<usize> {
                 // 返回值赋值给 pos
 
                 // 尝试在 bitmap_block 中找到一个空闲的 bit 并返回其位置.
                 // 如果能够找到的话, bit 组的编号将保存在变量 bits64_pos 中, 而分配的 bit 在组内的位置将保存在变量 inner_pos 中.
                 // bits64_pos: 为 bitmap_block 数组的某元素 (bits64) 的下标 (bits64_pos/bitmap_index), 该元素以二进制解释不是全 1
                 // inner_pos: 范围 [0, 63], 该元素以二进制解释时最左边的(最低位的) 0 的位置
-                if let Some((bits64_pos, inner_pos)) = bitmap_block
-                    // 遍历每 64 bits构成的组(一个 u64 )
+                let groups = block_bits(self.block_size) / 64;
+                if let Some((bits64_pos, inner_pos)) = bitmap_block[..groups]
+                    // 遍历每 64 bits构成的组(一个 u64 ), 跳过存放校验和的最后一组
                     .iter()
                     .enumerate()
                     // 如果它并没有达到 u64::MAX (不是 0x1111..1111, 即该行未分配完),
@@ -87,12 +118,16 @@ impl Bitmap {
                     .map(|(bits64_pos, bits64)| (bits64_pos, bits64.trailing_ones() as usize))
                 // 在此处返回 if let 匹配的bits64_pos, inner_pos = bits64.trailing_ones()
                 {
+                    // 在返回分配的 bit 编号的时候, 它的计算方式是:
+                    // block_id(块号) * block_bits(每块大小: bits) + bits64_pos(行号, 块内组号, 数组index) * 64 + inner_pos(组内编号, 最低位的 0 的位置(已经修改为 1 ))
+                    let bit = block_id * block_bits(self.block_size) + bits64_pos * 64 + inner_pos;
+                    // 超出有效范围的 bit 没有对应的块, 此时说明前面的 bit 都已经分配出去了
+                    if bit >= self.bits {
+                        return None;
+                    }
                     // 或运算 将该位置置为 1
                     bitmap_block[bits64_pos] |= 1 << inner_pos;
-
-                    // 在返回分配的 bit 编号的时候, 它的计算方式是:
-                    // block_id(块号) * BLOCK_BITS(每块大小: bits) + bits64_pos(行号, 块内组号, 数组index) * 64 + inner_pos(组内编号, 最低位的 0 的位置(已经修改为 1 ))
-                    Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
+                    Some(bit)
 
                     // 返回值赋值给变量 pos
 
@@ -114,34 +149,206 @@ impl Bitmap {
             });
             // 一旦在某个块中找到一个空闲的bit并成功分配, 就不再考虑后续的块, 提前返回
             if pos.is_some() {
-                return pos;
+                return Ok(pos);
             }
         }
-        None
+        Ok(None)
     }
 
-    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
-        let (block_id, bits64_pos, inner_pos) = decomposition(bit);
+    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> FsResult<()> {
+        let (block_id, bits64_pos, inner_pos) = decomposition(bit, self.block_size);
         get_block_cache(
-            block_id + self.start_block_id as usize,
+            block_id + self.start_block_id,
+            self.block_size,
+            BlockKind::Bitmap,
             Arc::clone(block_device),
-        )
+        )?
         .lock()
-        .modify(0, |bitmap_block: &mut BitmapBlock| {
+        .modify_slice(|bitmap_block: &mut BitmapBlock| {
             assert!(bitmap_block[bits64_pos] & (1 << inner_pos) != 0);
             bitmap_block[bits64_pos] &= !(1u64 << inner_pos);
         });
+        Ok(())
+    }
+
+    /// 统计已经分配出去的 bit 数量
+    pub fn count_allocated(&self, block_device: &Arc<dyn BlockDevice>) -> FsResult<usize> {
+        let groups = block_bits(self.block_size) / 64;
+        let mut count = 0;
+        for block_id in 0..self.blocks_counts {
+            count += get_block_cache(
+                block_id + self.start_block_id,
+                self.block_size,
+                BlockKind::Bitmap,
+                Arc::clone(block_device),
+            )?
+            .lock()
+            .read_slice(|bitmap_block: &BitmapBlock| {
+                bitmap_block[..groups]
+                    .iter()
+                    .map(|bits64| bits64.count_ones() as usize)
+                    .sum::<usize>()
+            });
+        }
+        Ok(count)
+    }
+
+    /// 从 goal 开始向后 (到达末尾后从头) 寻找并分配一段长度为 len 的连续空闲 bit
+    ///
+    /// 如果找不到这么长的连续空闲区间, 就从 goal 之后的第一个空闲 bit 开始分配尽可能长的一段.
+    /// 返回 (起始 bit, 实际分配的长度), 所有 bit 都已经分配出去时返回 None.
+    ///
+    /// 为了简单起见, 一段连续区间不会跨越两个位图块 (对于 512 字节的块, 一个位图块覆盖约 2MB).
+    pub fn alloc_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        goal: usize,
+        len: usize,
+    ) -> FsResult<Option<(usize, usize)>> {
+        assert!(len > 0);
+        let per_block = block_bits(self.block_size);
+        let goal = if goal < self.bits { goal } else { 0 };
+        let goal_block = goal / per_block;
+        // 第一个找到的空闲 bit 及其之后连续空闲的长度, 找不到长度为 len 的区间时用它
+        let mut fallback: Option<(usize, usize)> = None;
+
+        // 先从 goal 所在的块的 goal 处开始, 依次检查之后的块, 最后回到开头检查 goal 之前的部分
+        let ranges = (goal_block..self.blocks_counts)
+            .map(|block_id| {
+                let lo = if block_id == goal_block {
+                    goal % per_block
+                } else {
+                    0
+                };
+                (block_id, lo, per_block)
+            })
+            .chain((0..=goal_block).map(|block_id| {
+                let hi = if block_id == goal_block {
+                    goal % per_block
+                } else {
+                    per_block
+                };
+                (block_id, 0, hi)
+            }));
+        for (block_id, lo, hi) in ranges {
+            // 最后一个位图块中只有一部分 bit 是有效的
+            let hi = hi.min(self.bits.saturating_sub(block_id * per_block));
+            if lo >= hi {
+                continue;
+            }
+            let groups = self.read_groups(block_device, block_id)?;
+            let is_free = |i: usize| groups[i / 64] & (1 << (i % 64)) == 0;
+            let mut i = lo;
+            while i < hi {
+                // 跳过已经全部分配出去的组
+                if i % 64 == 0 && groups[i / 64] == u64::MAX {
+                    i += 64;
+                    continue;
+                }
+                if !is_free(i) {
+                    i += 1;
+                    continue;
+                }
+                let start = i;
+                while i < hi && i - start < len && is_free(i) {
+                    i += 1;
+                }
+                let run = i - start;
+                if run == len {
+                    return self.mark_run(block_device, block_id, start, run).map(Some);
+                }
+                if fallback.is_none() {
+                    fallback = Some((block_id * per_block + start, run));
+                }
+            }
+        }
+        match fallback {
+            Some((bit, run)) => self
+                .mark_run(block_device, bit / per_block, bit % per_block, run)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// 将位图中第 block_id 个块内从 start 开始的 len 个 bit 置 1, 返回 (起始 bit, 长度)
+    fn mark_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        block_id: usize,
+        start: usize,
+        len: usize,
+    ) -> FsResult<(usize, usize)> {
+        get_block_cache(
+            block_id + self.start_block_id,
+            self.block_size,
+            BlockKind::Bitmap,
+            Arc::clone(block_device),
+        )?
+        .lock()
+        .modify_slice(|bitmap_block: &mut BitmapBlock| {
+            for i in start..start + len {
+                debug_assert!(bitmap_block[i / 64] & (1 << (i % 64)) == 0);
+                bitmap_block[i / 64] |= 1 << (i % 64);
+            }
+        });
+        Ok((block_id * block_bits(self.block_size) + start, len))
+    }
+
+    /// 复制出位图中第 block_id 个块的所有 bit 组 (不含存放校验和的最后一组)
+    fn read_groups(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        block_id: usize,
+    ) -> FsResult<Vec<u64>> {
+        let groups = block_bits(self.block_size) / 64;
+        Ok(get_block_cache(
+            block_id + self.start_block_id,
+            self.block_size,
+            BlockKind::Bitmap,
+            Arc::clone(block_device),
+        )?
+        .lock()
+        .read_slice(|bitmap_block: &BitmapBlock| bitmap_block[..groups].to_vec()))
+    }
+
+    /// 统计空闲空间被分成了多少段, 以及最长的一段有多长
+    pub fn free_extents(&self, block_device: &Arc<dyn BlockDevice>) -> FsResult<FreeExtents> {
+        let per_block = block_bits(self.block_size);
+        let mut extents = FreeExtents::default();
+        // 当前这一段空闲区间的长度, 可以跨越位图块
+        let mut run = 0;
+        for block_id in 0..self.blocks_counts {
+            let groups = self.read_groups(block_device, block_id)?;
+            let hi = per_block.min(self.bits.saturating_sub(block_id * per_block));
+            for i in 0..hi {
+                if groups[i / 64] & (1 << (i % 64)) == 0 {
+                    if run == 0 {
+                        extents.count += 1;
+                    }
+                    run += 1;
+                    extents.largest = extents.largest.max(run);
+                } else {
+                    run = 0;
+                }
+            }
+        }
+        Ok(extents)
+    }
+
+    /// 位图本身所在的块
+    pub fn block_ids(&self) -> Range<usize> {
+        self.start_block_id..self.start_block_id + self.blocks_counts
     }
 
     /// 获取可分配块的最大数量
     pub fn maximum(&self) -> usize {
-        self.blocks_counts * BLOCK_BITS
+        self.bits
     }
 }
 
 /// 将bit编号 bit 分解为区域中的块编号 block_pos , 块内的组编号 bits64_pos 以及组内编号 inner_pos 的三元组
-fn decomposition(mut bit: usize) -> (usize, usize, usize) {
-    let block_id = bit / BLOCK_BITS;
-    bit %= BLOCK_BITS;
+fn decomposition(mut bit: usize, block_size: usize) -> (usize, usize, usize) {
+    let block_id = bit / block_bits(block_size);
+    bit %= block_bits(block_size);
     (block_id, bit / 64, bit % 64)
 }
@@ -21,46 +21,191 @@
 //! 如果是这样, 则在一段连续时间内对于一个块进行的所有操作均是在同一个固定的缓冲区中进行的, 这解决了同步性问题.
 //! 此外, 通过 read/write_block 进行块实际读写的时机完全交给块缓存层的全局管理器处理, 上层子系统无需操心.
 //! 全局管理器会尽可能将更多的块操作合并起来, 并在必要的时机发起真正的块实际读写.
+//!
+//! 被修改的块在以下时机写回: 被替换出缓存时; 调用 [`block_cache_sync`] 等函数显式写回时;
+//! 以及周期性调用 [`block_cache_flush_expired`] 时, 写回已经脏了至少一个周期的块.
+//! 文件系统在每次操作之后是否立即写回, 由挂载时选择的 [`CacheMode`](crate::CacheMode) 决定.
+//!
+//! 顺序读文件时, 上层会通过 [`block_cache_prefetch`] 提前把后面的若干个块读入缓存,
+//! 块号连续的块合并为一次 [`BlockDevice::read_blocks`] 调用.
 
-use std::{
+use alloc::{
     collections::VecDeque,
     // sync::{Arc, Mutex},
     sync::Arc,
+    vec,
+    vec::Vec,
 };
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use lazy_static::*;
+use log::error;
 use spin::Mutex; // https://docs.rs/spin/0.5.2/spin/struct.Mutex.html
 
-use super::{BlockDevice, BLOCK_CACHE_SIZE, BLOCK_SIZE};
+use super::{
+    checksum, BlockDevice, DiskInode, FsError, FsResult, SuperBlock, BLOCK_CACHE_SIZE, SECTOR_SIZE,
+};
+
+/// 块的用途, 决定了块缓存如何校验这个块
+///
+/// 除了普通的数据块之外, 其余的块都是元数据块, 带有 CRC32C 校验和 (see checksum.rs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    /// 文件内容, 不做校验
+    Data,
+    /// 超级块
+    Super,
+    /// 索引节点区域的块, 其中每个 DiskInode 各自带有校验和
+    Inode,
+    /// 位图块
+    Bitmap,
+    /// 索引块 (IndirectBlock)
+    Indirect,
+    /// 目录的数据块
+    Dir,
+    /// 扩展属性块
+    Xattr,
+}
+
+impl BlockKind {
+    /// 块中每条带校验和的记录的大小; 数据块没有校验和, 返回 None
+    fn record_size(self, block_size: usize) -> Option<usize> {
+        match self {
+            BlockKind::Data => None,
+            BlockKind::Super => Some(core::mem::size_of::<SuperBlock>()),
+            BlockKind::Inode => Some(core::mem::size_of::<DiskInode>()),
+            BlockKind::Bitmap | BlockKind::Indirect | BlockKind::Dir | BlockKind::Xattr => {
+                Some(block_size)
+            }
+        }
+    }
+}
 
 /// Cached block inside memory
 pub struct BlockCache {
-    /// cache 是一个 512 字节的数组(恰好为一个块), 表示位于内存中的缓冲区
-    cache: [u8; BLOCK_SIZE],
+    /// cache 是一个 block_size 字节的缓冲区(恰好为一个块), 表示位于内存中的缓冲区
+    ///
+    /// 以 u64 为单位分配, 保证将其解释为 IndirectBlock, BitmapBlock 等磁盘数据结构时是对齐的
+    cache: Vec<u64>,
+    /// 块大小, 由文件系统的超级块决定, 是扇区大小 SECTOR_SIZE 的整数倍
+    block_size: usize,
     /// block_id 记录了这个块缓存来自于磁盘中的块的编号
     block_id: usize,
     /// block_device 是一个底层块设备的引用, 可通过它进行块读写
     block_device: Arc<dyn BlockDevice>,
     /// modified 记录这个块从磁盘载入内存缓存之后, 它有没有被修改过
     modified: bool,
+    /// 块的用途, 以最近一次请求这个块时给出的为准
+    kind: BlockKind,
+    /// 这个块从干净变脏时的写回周期 (FLUSH_TICK), 只在 modified 为 true 时有意义
+    dirtied_at: u64,
 }
 
 impl BlockCache {
-    /// 创建一个 BlockCache: 这将触发一次 read_block 将一个块上的数据从磁盘读到缓冲区 cache
-    pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
-        let mut cache = [0u8; BLOCK_SIZE];
-        block_device.read_block(block_id, &mut cache);
+    /// 创建一个 BlockCache: 这将触发若干次 read_block 将一个块所覆盖的扇区从磁盘读到缓冲区 cache
+    ///
+    /// 如果 kind 是元数据块而校验和不匹配, 返回 FsError::Corrupted; 读扇区失败时返回 FsError::Io
+    pub fn new(
+        block_id: usize,
+        block_size: usize,
+        kind: BlockKind,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> FsResult<Self> {
+        let mut block_cache = Self::empty(block_id, block_size, kind, block_device);
+        let first_sector = block_cache.first_sector();
+        let block_device = Arc::clone(&block_cache.block_device);
+        block_device.read_blocks(first_sector, block_cache.as_bytes_mut())?;
+        if !block_cache.verify() {
+            return Err(FsError::Corrupted(block_id));
+        }
+        Ok(block_cache)
+    }
+
+    /// 以已经从磁盘读出的内容 data 创建一个 BlockCache, 用于预读; 同样会检查校验和
+    fn with_data(
+        block_id: usize,
+        block_size: usize,
+        kind: BlockKind,
+        block_device: Arc<dyn BlockDevice>,
+        data: &[u8],
+    ) -> FsResult<Self> {
+        let mut block_cache = Self::empty(block_id, block_size, kind, block_device);
+        block_cache.as_bytes_mut().copy_from_slice(data);
+        if !block_cache.verify() {
+            return Err(FsError::Corrupted(block_id));
+        }
+        Ok(block_cache)
+    }
+
+    /// 内容全为 0 的缓冲区, 还没有从磁盘读入
+    fn empty(
+        block_id: usize,
+        block_size: usize,
+        kind: BlockKind,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> Self {
+        assert_eq!(block_size % SECTOR_SIZE, 0);
         Self {
-            cache,
+            cache: vec![0u64; block_size / 8],
+            block_size,
             block_id,
             block_device,
             modified: false,
+            kind,
+            dirtied_at: 0,
+        }
+    }
+
+    /// 检查缓冲区中每条记录的校验和
+    fn verify(&self) -> bool {
+        match self.kind.record_size(self.block_size) {
+            Some(record_size) => self
+                .as_bytes()
+                .chunks_exact(record_size)
+                .all(checksum::verify),
+            None => true,
+        }
+    }
+
+    /// 在写回之前重新计算每条记录的校验和
+    fn seal(&mut self) {
+        if let Some(record_size) = self.kind.record_size(self.block_size) {
+            self.as_bytes_mut()
+                .chunks_exact_mut(record_size)
+                .for_each(checksum::seal);
+        }
+    }
+
+    pub fn kind(&self) -> BlockKind {
+        self.kind
+    }
+
+    /// 将缓冲区标记为已修改, 并记下它是在哪个写回周期变脏的
+    fn mark_modified(&mut self) {
+        if !self.modified {
+            self.modified = true;
+            self.dirtied_at = FLUSH_TICK.load(Ordering::Relaxed);
+        }
+    }
+
+    /// 该块在块设备上的第一个扇区的编号
+    fn first_sector(&self) -> usize {
+        self.block_id * (self.block_size / SECTOR_SIZE)
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.cache.as_ptr() as *const u8, self.block_size) }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.cache.as_mut_ptr() as *mut u8, self.block_size)
         }
     }
 
     /// 得到一个 BlockCache 内部的缓冲区中指定偏移量 offset 的字节地址
     fn addr_of_offset(&self, offset: usize) -> usize {
-        &self.cache[offset] as *const u8 as usize
+        self.cache.as_ptr() as usize + offset
     }
 
     /// 获取缓冲区中的位于偏移量 offset 的一个类型为 T 的磁盘上数据结构的不可变引用.
@@ -70,9 +215,9 @@ impl BlockCache {
     where
         T: Sized,
     {
-        let type_size = std::mem::size_of::<T>();
+        let type_size = core::mem::size_of::<T>();
         // 确认 T 被整个包含在磁盘块及其缓冲区之内
-        assert!(offset + type_size <= BLOCK_SIZE);
+        assert!(offset + type_size <= self.block_size);
         let addr = self.addr_of_offset(offset);
         // &* 再借用; 将指针转换为引用
         unsafe { &*(addr as *const T) }
@@ -86,13 +231,27 @@ impl BlockCache {
     where
         T: Sized,
     {
-        let type_size = std::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SIZE);
-        self.modified = true;
+        let type_size = core::mem::size_of::<T>();
+        assert!(offset + type_size <= self.block_size);
+        self.mark_modified();
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
 
+    /// 将整个块解释为一个元素类型为 T 的数组 (例如 IndirectBlock 或 DataBlock),
+    /// 数组的长度由块大小决定, 因此无法用 get_ref 这样的定长类型来表示
+    pub fn get_slice<T>(&self) -> &[T] {
+        let len = self.block_size / core::mem::size_of::<T>();
+        unsafe { core::slice::from_raw_parts(self.cache.as_ptr() as *const T, len) }
+    }
+
+    /// get_slice 的可变版本, 同样会将缓冲区标记为已修改
+    pub fn get_slice_mut<T>(&mut self) -> &mut [T] {
+        let len = self.block_size / core::mem::size_of::<T>();
+        self.mark_modified();
+        unsafe { core::slice::from_raw_parts_mut(self.cache.as_mut_ptr() as *mut T, len) }
+    }
+
     // 思考: 为什么使用闭包来实现对缓冲区的读写操作
     //
     // 将 get_ref/get_mut 进一步封装为更为易用的形式.
@@ -115,17 +274,32 @@ impl BlockCache {
         f(self.get_mut(offset))
     }
 
+    pub fn read_slice<T, V>(&self, f: impl FnOnce(&[T]) -> V) -> V {
+        f(self.get_slice())
+    }
+
+    pub fn modify_slice<T, V>(&mut self, f: impl FnOnce(&mut [T]) -> V) -> V {
+        f(self.get_slice_mut())
+    }
+
     /// If modified, write back to disk when dropped.
     ///
     /// 事实上,  sync 并不是只有在 drop 的时候才会被调用.
     /// 在 Linux 中, 通常有一个后台进程负责定期将内存中缓冲区的内容写回磁盘.
     /// 另外有一个 sys_fsync 系统调用可以让应用主动通知内核将一个文件的修改同步回磁盘.
-    /// 由于我们的实现比较简单,  sync 仅会在 BlockCache 被 drop 时才会被调用.
-    pub fn sync(&mut self) {
+    /// 我们的实现中, 除了 drop 之外, 块缓存被替换出去之前, 文件系统显式写回时, 以及周期性写回时都会调用 sync.
+    ///
+    /// 写回失败时缓冲区仍然保持 modified, 下一次 sync 时会整块重试
+    pub fn sync(&mut self) -> FsResult<()> {
         if self.modified {
-            self.block_device.write_block(self.block_id, &self.cache);
+            self.seal();
+            let first_sector = self.first_sector();
+            for (i, sector) in self.as_bytes().chunks_exact(SECTOR_SIZE).enumerate() {
+                self.block_device.write_block(first_sector + i, sector)?;
+            }
             self.modified = false;
         }
+        Ok(())
     }
 }
 
@@ -135,8 +309,12 @@ impl Drop for BlockCache {
     /// 这个时候 modified 标记将会决定数据是否需要写回磁盘.
     /// 在 BlockCache 被 drop 的时候, 它会首先调用 sync 方法,
     /// 如果自身确实被修改过的话才会将缓冲区的内容写回磁盘.
+    ///
+    /// 被替换出去的块缓存在替换之前已经写回过了, 这里的错误只能记录下来
     fn drop(&mut self) {
-        self.sync();
+        if let Err(err) = self.sync() {
+            error!("failed to write back block {}: {}", self.block_id, err);
+        }
     }
 }
 
@@ -173,11 +351,14 @@ pub struct BlockCacheManager {
     ///
     /// 事实上, 一般情况下我们需要在更上层提供保护措施避免两个线程同时对一个块缓存进行读写,
     /// 因此这里只是比较谨慎的留下一层保险.
-    /// 注意:  VecDeque 中只以 block_id 作为标识的话, 同时读写不同设备的同一个 block 时会有冲突
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    /// 注意:  VecDeque 中只以 block_id 作为标识的话, 同时读写不同设备的同一个 block 时会有冲突,
+    /// 因此以 (设备编号, 块编号) 作为标识, 设备编号见 device_id
+    queue: VecDeque<(CacheKey, Arc<Mutex<BlockCache>>)>,
+    /// 已经替换出去的块缓存数. 预读在不持有管理器锁时读盘, 用它判断期间是否有块被写回并替换出去
+    evictions: u64,
 }
 
-/**
+/*
     // 修改 queue 为Vec
     pub struct BlockCacheManager {
         queue: Vec<(usize, Arc<Mutex<BlockCache>>)>,
@@ -215,10 +396,21 @@ pub struct BlockCacheManager {
     }
 */
 
+/// 以块设备在内存中的地址作为设备编号
+///
+/// 块缓存持有块设备的引用, 只要还有这个设备的块缓存, 它的地址就不会被其他设备复用
+fn device_id(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
+
+/// 块缓存的标识: (设备编号, 块编号)
+type CacheKey = (usize, usize);
+
 impl BlockCacheManager {
     pub fn new() -> Self {
         Self {
             queue: VecDeque::new(),
+            evictions: 0,
         }
     }
 
@@ -227,45 +419,67 @@ impl BlockCacheManager {
     pub fn get_block_cache(
         &mut self,
         block_id: usize,
+        block_size: usize,
+        kind: BlockKind,
         block_device: Arc<dyn BlockDevice>,
-    ) -> Arc<Mutex<BlockCache>> {
+    ) -> FsResult<Arc<Mutex<BlockCache>>> {
         // 遍历整个队列试图找到一个编号相同的块缓存,
         // 如果找到了, 会将块缓存管理器中保存的块缓存的引用复制一份并返回
-        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == block_id) {
-            Arc::clone(&pair.1)
+        let key = (device_id(&block_device), block_id);
+        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == key) {
+            // 块可能被回收后又用作其他用途 (例如数据块变成了索引块),
+            // 缓冲区中的内容就是最新的, 不需要重新校验, 由 get_block_cache 更新它的用途
+            Ok(Arc::clone(&pair.1))
         } else {
             // 如果找不到, 此时必须将块从磁盘读入内存中的缓冲区.
             // 在实际读取之前, 需要判断管理器保存的块缓存数量是否已经达到了上限.
             // 如果达到了上限, 需要执行缓存替换算法, 丢掉某个块缓存并空出一个空位.
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // 这里使用一种类 FIFO 算法:
-                // 每加入一个块缓存时要从队尾加入, 要替换时则从队头弹出.
-                if let Some((idx, _)) = self
-                    .queue
-                    .iter()
-                    .enumerate()
-                    // 但此时队头对应的块缓存可能仍在使用:
-                    // 判断的标志是其强引用计数, 即除了块缓存管理器保留的一份副本之外, 在外面还有若干份副本正在使用.
-                    // 因此, 我们的做法是从队头遍历到队尾找到第一个强引用计数恰好为 1 的块缓存并将其替换出去.
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
-                {
-                    self.queue.drain(idx..=idx); // 从队列中删除该块缓存, range: [idx, idx] == idx
-                } else {
-                    // 那么是否有可能出现队列已满且其中所有的块缓存都正在使用的情形呢?
-                    // 事实上, 只要我们的上限 BLOCK_CACHE_SIZE 设置的足够大, 超过所有应用同时访问的块总数上限, 那么这种情况永远不会发生.
-                    // 但是, 如果我们的上限设置不足, 内核将 panic (基于简单内核设计的思路).
-                    panic!("Run out of BlockCache");
-                }
-            }
+            self.make_room();
             // 创建一个新的块缓存(会触发 read_block 进行块读取)并加入到队尾, 最后返回给请求者.
+            // 校验失败的块不会进入缓存
             let block_cache = Arc::new(Mutex::new(BlockCache::new(
                 block_id,
+                block_size,
+                kind,
                 Arc::clone(&block_device),
-            )));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
+            )?));
+            self.queue.push_back((key, Arc::clone(&block_cache)));
+            Ok(block_cache)
         }
     }
+
+    /// 块缓存数量达到上限时, 替换出一个块缓存, 返回被替换出去的块缓存的标识
+    fn make_room(&mut self) -> Option<CacheKey> {
+        if self.queue.len() >= BLOCK_CACHE_SIZE {
+            // 这里使用一种类 FIFO 算法:
+            // 每加入一个块缓存时要从队尾加入, 要替换时则从队头弹出.
+            if let Some(idx) = self
+                .queue
+                .iter()
+                // 但此时队头对应的块缓存可能仍在使用:
+                // 判断的标志是其强引用计数, 即除了块缓存管理器保留的一份副本之外, 在外面还有若干份副本正在使用.
+                // 因此, 我们的做法是从队头遍历到队尾找到第一个强引用计数恰好为 1 的块缓存并将其替换出去.
+                //
+                // 替换之前先写回, 写回失败的块缓存留在队列中以免丢失修改, 继续寻找下一个.
+                // 只有管理器持有它, 因此在持有管理器锁的同时获取它的锁不会死锁
+                .position(|pair| Arc::strong_count(&pair.1) == 1 && pair.1.lock().sync().is_ok())
+            {
+                let (key, _) = self.queue.remove(idx).unwrap(); // 从队列中删除该块缓存
+                self.evictions += 1;
+                return Some(key);
+            }
+            // 那么是否有可能出现队列已满且其中所有的块缓存都正在使用的情形呢?
+            // 单线程时, 只要我们的上限 BLOCK_CACHE_SIZE 设置的足够大, 超过一次操作同时访问的块总数上限, 那么这种情况永远不会发生.
+            // 但是去掉文件系统的大锁之后, 多个线程可以同时持有若干个块缓存, 无法给出固定的上限.
+            // 因此 BLOCK_CACHE_SIZE 只是一个软上限: 找不到可以替换的块缓存时暂时超出上限,
+            // 之后加入新的块缓存时再逐步替换回来.
+        }
+        None
+    }
+
+    fn contains(&self, key: CacheKey) -> bool {
+        self.queue.iter().any(|pair| pair.0 == key)
+    }
 }
 
 lazy_static! {
@@ -281,19 +495,181 @@ lazy_static! {
 /// 它返回的是一个 Arc<Mutex<BlockCache>>,
 /// 调用者需要通过 .lock() 获取里层互斥锁 Mutex 才能对最里面的 BlockCache 进行操作,
 /// 比如通过 read/modify 访问缓冲区里面的磁盘数据结构.
+///
+/// block_size 为文件系统的块大小, 同一块设备上的所有块都应当使用同一个块大小;
+/// kind 为块的用途, 元数据块在载入时会检查校验和, 不匹配时返回 FsError::Corrupted
+///
+/// 注意不能在持有管理器锁的同时去获取某个块的锁: 另一个线程可能正持有这个块的锁并等待管理器锁.
 pub fn get_block_cache(
     block_id: usize,
+    block_size: usize,
+    kind: BlockKind,
     block_device: Arc<dyn BlockDevice>,
-) -> Arc<Mutex<BlockCache>> {
-    BLOCK_CACHE_MANAGER
+) -> FsResult<Arc<Mutex<BlockCache>>> {
+    let block_cache = BLOCK_CACHE_MANAGER
         .lock() // use spin lock: https://docs.rs/spin/0.5.2/spin/struct.Mutex.html
         // .unwrap() // use std
-        .get_block_cache(block_id, block_device)
+        .get_block_cache(block_id, block_size, kind, block_device)?;
+    // 此时已经释放了管理器锁
+    block_cache.lock().kind = kind;
+    Ok(block_cache)
+}
+
+/// 将编号为 block_id 的块格式化为一个全零的 kind 块, 写回时按 kind 计算校验和
+///
+/// 全零的元数据块不能通过校验, 因此新分配的索引块, 目录块和扩展属性块在第一次按元数据读取之前都要先格式化.
+/// 块原来的内容按数据块读入, 不做校验
+pub fn block_cache_format(
+    block_id: usize,
+    block_size: usize,
+    kind: BlockKind,
+    block_device: &Arc<dyn BlockDevice>,
+) -> FsResult<()> {
+    let block_cache = get_block_cache(
+        block_id,
+        block_size,
+        BlockKind::Data,
+        Arc::clone(block_device),
+    )?;
+    let mut block_cache = block_cache.lock();
+    block_cache.as_bytes_mut().fill(0);
+    block_cache.mark_modified();
+    block_cache.kind = kind;
+    Ok(())
 }
 
-pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, block_cache) in manager.queue.iter() {
-        block_cache.lock().sync();
+/// 预读: 将 block_device 上编号为 block_ids 的块提前读入块缓存
+///
+/// 已经在缓存中的块会被跳过, 剩下的块中编号连续的一段通过一次 read_blocks 读入.
+/// 预读只是一种优化, 读取失败或者校验失败的块直接放弃, 等到真正访问它时再报告错误.
+pub fn block_cache_prefetch(
+    block_ids: &[usize],
+    block_size: usize,
+    kind: BlockKind,
+    block_device: &Arc<dyn BlockDevice>,
+) {
+    let device = device_id(block_device);
+    let (mut missing, mut evictions): (Vec<usize>, u64) = {
+        let manager = BLOCK_CACHE_MANAGER.lock();
+        let missing = block_ids
+            .iter()
+            .copied()
+            .filter(|&block_id| !manager.contains((device, block_id)))
+            .collect();
+        (missing, manager.evictions)
+    };
+    missing.sort_unstable();
+    missing.dedup();
+
+    let sectors = block_size / SECTOR_SIZE;
+    let mut rest = missing.as_slice();
+    while let Some(&first) = rest.first() {
+        // 找出从 first 开始编号连续的一段
+        let len = rest
+            .iter()
+            .enumerate()
+            .take_while(|&(i, &block_id)| block_id == first + i)
+            .count();
+        let mut data = vec![0u8; len * block_size];
+        if block_device.read_blocks(first * sectors, &mut data).is_ok() {
+            for (i, data) in data.chunks_exact(block_size).enumerate() {
+                let Ok(block_cache) = BlockCache::with_data(
+                    first + i,
+                    block_size,
+                    kind,
+                    Arc::clone(block_device),
+                    data,
+                ) else {
+                    continue;
+                };
+                let mut manager = BLOCK_CACHE_MANAGER.lock();
+                // 读盘期间没有持有管理器锁, 其他线程可能已经读入并修改了这个块:
+                // 还在缓存中时以缓存中的为准; 如果期间有块被替换出去, 它可能已经被修改并写回,
+                // 读到的数据也许已经过时, 干脆放弃剩下的预读
+                if manager.evictions != evictions {
+                    return;
+                }
+                if !manager.contains((device, first + i)) {
+                    // 自己替换出去的块如果也在预读的范围内, 它同样可能是被其他线程读入并修改过的
+                    if let Some((victim_device, victim)) = manager.make_room() {
+                        if victim_device == device && missing.binary_search(&victim).is_ok() {
+                            return;
+                        }
+                    }
+                    manager
+                        .queue
+                        .push_back(((device, first + i), Arc::new(Mutex::new(block_cache))));
+                }
+                evictions = manager.evictions;
+            }
+        }
+        rest = &rest[len..];
+    }
+}
+
+/// 周期性写回的时钟, 每调用一次 block_cache_flush_expired 加一
+static FLUSH_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// 将所有被修改过的块缓存写回块设备
+///
+/// 即使某个块写回失败也会继续写回其余的块, 最后返回遇到的第一个错误
+pub fn block_cache_sync_all() -> FsResult<()> {
+    sync_where(|_| true, |_| true)
+}
+
+/// 只写回 block_device 上被修改过的块缓存
+///
+/// 文件系统的操作只应当关心自己所在的设备, 另一个设备写回失败不应该让它也返回错误
+pub fn block_cache_sync(block_device: &Arc<dyn BlockDevice>) -> FsResult<()> {
+    let device = device_id(block_device);
+    sync_where(|key| key.0 == device, |_| true)
+}
+
+/// 只写回 block_device 上编号在 block_ids (已排序) 中的块缓存, 用于 fsync 单个文件
+pub fn block_cache_sync_blocks(
+    block_device: &Arc<dyn BlockDevice>,
+    block_ids: &[usize],
+) -> FsResult<()> {
+    let device = device_id(block_device);
+    sync_where(
+        |key| key.0 == device && block_ids.binary_search(&key.1).is_ok(),
+        |_| true,
+    )
+}
+
+/// 周期性写回: 写回 block_device 上在上一次调用之前就已经变脏的块缓存, 并推进写回时钟
+///
+/// 应当以固定的间隔 (例如由时钟中断) 调用, 这样被修改的块最多在两个周期之后就会写回磁盘,
+/// 而在一个周期内被反复修改的块只会写回一次. 写回时钟是所有设备共享的,
+/// 因此多个文件系统各自调用时, 块会更早地被写回.
+pub fn block_cache_flush_expired(block_device: &Arc<dyn BlockDevice>) -> FsResult<()> {
+    let device = device_id(block_device);
+    let now = FLUSH_TICK.fetch_add(1, Ordering::Relaxed);
+    sync_where(
+        |key| key.0 == device,
+        |block_cache| block_cache.dirtied_at < now,
+    )
+}
+
+/// 写回标识满足 filter, 并且 (加锁之后) 满足 expired 的块缓存
+fn sync_where(
+    filter: impl Fn(&CacheKey) -> bool,
+    expired: impl Fn(&BlockCache) -> bool,
+) -> FsResult<()> {
+    // 先复制出所有块缓存的引用并释放管理器锁, 再逐个加锁写回
+    let block_caches: Vec<_> = BLOCK_CACHE_MANAGER
+        .lock()
+        .queue
+        .iter()
+        .filter(|(key, _)| filter(key))
+        .map(|(_, block_cache)| Arc::clone(block_cache))
+        .collect();
+    let mut ret = Ok(());
+    for block_cache in block_caches {
+        let mut block_cache = block_cache.lock();
+        if expired(&block_cache) {
+            ret = ret.and(block_cache.sync());
+        }
     }
+    ret
 }
@@ -2,20 +2,57 @@
 //! 块缓存层会调用这两个方法, 进行块缓存的管理.
 //! 泛用性: 可以访问实现了 BlockDevice Trait 的块设备驱动程序.
 
-use std::any::Any;
+use core::any::Any;
+use core::fmt::{Display, Formatter, Result};
+
+use super::SECTOR_SIZE;
 
 // 块与扇区
 // 实际上, 块和扇区是两个不同的概念.
 // 扇区 (Sector) 是块设备随机读写的数据单位, 通常每个扇区为 512 字节.
 // 而块是文件系统存储文件时的数据单位, 每个块的大小等同于一个或多个扇区.
+// easy-fs 的块大小在创建时确定 (512B ~ 4K), 而 BlockDevice 始终以 512 字节的扇区为单位读写,
+// 块缓存负责把一个块拆成若干个扇区.
+
+/// 块设备读写失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// 扇区编号超出了设备的容量
+    OutOfRange(usize),
+    /// 读写编号为 block_id 的扇区时发生 I/O 错误
+    Io(usize),
+}
+
+impl Display for BlockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            BlockError::OutOfRange(block_id) => write!(f, "sector {} is out of range", block_id),
+            BlockError::Io(block_id) => write!(f, "I/O error on sector {}", block_id),
+        }
+    }
+}
 
 // 块设备接口层
 // 定义设备驱动需要实现的块读写接口 BlockDevice trait
 
 pub trait BlockDevice: Send + Sync + Any {
+    // 注意这里的 block_id 是扇区编号, buf 的长度为 SECTOR_SIZE
+    // 读写失败时返回 BlockError, 由块缓存层转换为 FsError::Io 交给上层
+
     // read_block 将编号为 block_id 的块从磁盘读入内存中的缓冲区 buf ;
-    fn read_block(&self, block_id: usize, buf: &mut [u8]);
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> core::result::Result<(), BlockError>;
 
     // write_block 将内存中的缓冲区 buf 中的数据写入磁盘编号为 block_id 的块.
-    fn write_block(&self, block_id: usize, buf: &[u8]);
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> core::result::Result<(), BlockError>;
+
+    /// 从编号为 block_id 的扇区开始连续读取若干个扇区, buf 的长度为 SECTOR_SIZE 的整数倍
+    ///
+    /// 块缓存读入一个块以及预读时都会调用它. 默认实现逐个扇区调用 read_block,
+    /// 能够一次传输多个扇区的设备 (例如 virtio-blk 或宿主机上的文件) 应当覆盖这个方法
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> core::result::Result<(), BlockError> {
+        for (i, sector) in buf.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            self.read_block(block_id + i, sector)?;
+        }
+        Ok(())
+    }
 }
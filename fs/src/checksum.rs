@@ -0,0 +1,65 @@
+//! 元数据校验和
+//!
+//! 超级块, DiskInode, 位图块, 索引块, 目录块和扩展属性块都带有 CRC32C 校验和, 以便发现写了一半或者发生了位翻转的块.
+//!
+//! 校验的单位是一条记录 (record): 记录的最后 CHECKSUM_SIZE 个字节保存了前面所有字节的 CRC32C.
+//! 超级块和 DiskInode 各自是一条记录 (校验和是它们的最后一个字段),
+//! 位图块, 索引块, 目录块和扩展属性块则整个块是一条记录 (校验和位于块的最后 4 个字节).
+//!
+//! 块缓存在从磁盘载入块时进行校验, 在写回磁盘 (sync) 时重新计算.
+//! 记录的校验和以 RECORD_SEED 为初值, 全零的记录不能通过校验, 因此被清零或者从未写过的元数据块都会被发现;
+//! 创建文件系统时的元数据区域和新分配的索引块, 目录块, 扩展属性块都需要先格式化 (see block_cache_format).
+
+use super::CHECKSUM_SIZE;
+
+/// CRC32C (Castagnoli) 多项式, 按位反转后的形式
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+
+/// 按字节查表计算 CRC32C, 表在编译期生成
+static CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// 记录校验和的初值 ("EFS!"), 使得全零的记录算出的校验和不为 0
+const RECORD_SEED: u32 = 0x4546_5321;
+
+/// 计算 data 的 CRC32C 校验和
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_seeded(0, data)
+}
+
+/// 以 seed 为初值计算 data 的 CRC32C 校验和, seed 为 0 时就是标准的 CRC32C
+fn crc32c_seeded(seed: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!seed, |crc, &byte| {
+        CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// 检查一条记录的校验和是否与内容相符
+pub(crate) fn verify(record: &[u8]) -> bool {
+    let (body, tail) = record.split_at(record.len() - CHECKSUM_SIZE);
+    let stored = u32::from_le_bytes(tail.try_into().unwrap());
+    crc32c_seeded(RECORD_SEED, body) == stored
+}
+
+/// 重新计算一条记录的校验和
+pub(crate) fn seal(record: &mut [u8]) {
+    let (body, tail) = record.split_at_mut(record.len() - CHECKSUM_SIZE);
+    tail.copy_from_slice(&crc32c_seeded(RECORD_SEED, body).to_le_bytes());
+}
@@ -0,0 +1,53 @@
+//! easy-fs 的错误类型
+//!
+//! 元数据损坏: 块缓存在载入元数据块时发现校验和不匹配,
+//! 此时不能继续使用块中的内容 (例如把错误的块编号交给 get_block_id), 只能将错误返回给上层.
+//!
+//! 空间不足: 没有空闲的数据块或索引节点了.
+//!
+//! 文件过大: 写入或截断的目标大小超过了三级索引能覆盖的范围, 或者 DiskInode::size (u32) 能表示的范围.
+//!
+//! 参数错误: 调用者传入的参数不合法, 例如扩展属性名为空或者过长.
+//!
+//! I/O 错误: 块设备读写扇区失败. 读失败的块不会进入块缓存;
+//! 写回失败的块缓存仍然保留修改, 下一次 sync 时会重试.
+
+use core::fmt::{Display, Formatter, Result};
+
+use super::BlockError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// 编号为 block_id 的块的校验和与其内容不匹配
+    Corrupted(usize),
+    /// 没有空闲的数据块或索引节点
+    NoSpace,
+    /// 文件大小超过了 max_file_size
+    FileTooLarge,
+    /// 调用者传入的参数不合法
+    InvalidArgument,
+    /// 块设备读写失败
+    Io(BlockError),
+}
+
+impl Display for FsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            FsError::Corrupted(block_id) => write!(f, "block {} is corrupted", block_id),
+            FsError::NoSpace => write!(f, "no space left on device"),
+            FsError::FileTooLarge => write!(f, "file too large"),
+            FsError::InvalidArgument => write!(f, "invalid argument"),
+            FsError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> Self {
+        FsError::Io(err)
+    }
+}
+
+impl core::error::Error for FsError {}
+
+pub type FsResult<T> = core::result::Result<T, FsError>;
@@ -0,0 +1,89 @@
+//! 注入故障的块设备
+//!
+//! [`FaultyDevice`] 包装另一个块设备, 对指定的扇区返回 I/O 错误, 其余的读写原样转发.
+//! 用来测试 easy-fs 在磁盘出错时能否把错误正确地返回给上层, 而不是 panic 或者悄悄地损坏数据.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use spin::Mutex;
+
+use super::{BlockDevice, BlockError, SECTOR_SIZE};
+
+/// 在哪些操作上注入故障
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// 读失败
+    Read,
+    /// 写失败
+    Write,
+    /// 读写都失败
+    ReadWrite,
+}
+
+impl Fault {
+    fn on_read(self) -> bool {
+        self != Fault::Write
+    }
+
+    fn on_write(self) -> bool {
+        self != Fault::Read
+    }
+}
+
+pub struct FaultyDevice {
+    inner: Arc<dyn BlockDevice>,
+    /// 出错的扇区及其出错的方式
+    faults: Mutex<BTreeMap<usize, Fault>>,
+}
+
+impl FaultyDevice {
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            inner,
+            faults: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// 让编号为 block_id 的扇区在 fault 指定的操作上失败
+    pub fn fail(&self, block_id: usize, fault: Fault) {
+        self.faults.lock().insert(block_id, fault);
+    }
+
+    /// 让文件系统中编号为 block_id, 大小为 block_size 的块所覆盖的所有扇区失败
+    pub fn fail_block(&self, block_id: usize, block_size: usize, fault: Fault) {
+        let sectors = block_size / SECTOR_SIZE;
+        for sector in block_id * sectors..(block_id + 1) * sectors {
+            self.fail(sector, fault);
+        }
+    }
+
+    /// 恢复编号为 block_id 的扇区
+    pub fn heal(&self, block_id: usize) {
+        self.faults.lock().remove(&block_id);
+    }
+
+    /// 恢复所有扇区
+    pub fn heal_all(&self) {
+        self.faults.lock().clear();
+    }
+
+    fn fault(&self, block_id: usize) -> Option<Fault> {
+        self.faults.lock().get(&block_id).copied()
+    }
+}
+
+impl BlockDevice for FaultyDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        match self.fault(block_id) {
+            Some(fault) if fault.on_read() => Err(BlockError::Io(block_id)),
+            _ => self.inner.read_block(block_id, buf),
+        }
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        match self.fault(block_id) {
+            Some(fault) if fault.on_write() => Err(BlockError::Io(block_id)),
+            _ => self.inner.write_block(block_id, buf),
+        }
+    }
+}
@@ -3,14 +3,57 @@
 //! [`FileSystem`] 知道每个布局区域所在的位置, 磁盘块的分配和回收也需要经过它才能完成, 因此某种意义上讲它可以看成一个磁盘块管理器
 //!
 //! 从这一层开始, 所有的数据结构放在内存上
+//!
+//! # 锁
+//!
+//! FileSystem 本身不再由一把大锁保护, 而是以 Arc<FileSystem> 的形式共享, 各部分各自加锁:
+//!
+//! - 每个索引节点一把读写锁 (在 [`Inode`] 中), 保护该文件/目录的内容, 大小和索引块;
+//! - 索引节点位图和数据块位图各一把锁, 即分配器锁;
+//! - 块缓存中每个块一把锁, 只在访问这个块的期间短暂持有.
+//!
+//! 为了避免死锁, 加锁的顺序总是: 目录的索引节点锁 -> 目录下文件的索引节点锁 -> 分配器锁 -> 块锁.
+//! 持有块锁时可以再获取分配器锁 (为空洞分配块时), 但持有分配器锁时只会访问位图块.
+//! Inode::rename 需要同时持有两个目录的锁, 此时按照索引节点编号从小到大加锁.
+//!
+//! # 索引节点表
+//!
+//! 同一个索引节点在内存中只有一个 Inode: [`FileSystem::get_inode`] 以索引节点编号为键,
+//! 只要还有人持有这个 Inode, 就会返回同一个 Arc<Inode>. Arc 的强引用计数就是它的打开计数,
+//! 被 unlink 的文件在最后一个 Arc<Inode> 被释放 (也就是最后一次关闭) 时才会回收它的块和索引节点.
+//!
+//! # 数据块的分配策略
+//!
+//! 数据块不再总是分配编号最小的空闲块, 而是:
+//!
+//! - 没有提示时从一个轮转的游标 (next-fit) 开始向后寻找, 游标停在上一次分配的块之后,
+//!   这样先后创建的文件依次排列, 刚释放的块也不会立刻被零散地重用;
+//! - 写文件时以文件中前一个数据块的下一个块作为提示, 并且一次为整个写入预留一段连续的块 ([`FileSystem::alloc_data_run`]),
+//!   因此即使多个文件交替写入, 每个文件的数据块也大多是连续的.
+//!
+//! [`FileSystem::free_extents`] 和 [`Inode::extents`] 可以用来观察空闲空间和文件的碎片程度.
+//!
+//! # 写回策略
+//!
+//! 挂载 ([`FileSystem::open_with`]) 时可以选择块缓存的写回策略 [`CacheMode`]:
+//!
+//! - `sync`: 每次修改文件系统的操作结束时都写回这个设备上所有被修改的块, 这是 [`FileSystem::open`] 的默认行为;
+//! - `writeback`: 操作结束时不写回, 被修改的块只在被替换出缓存, 调用 [`FileSystem::sync`] 或 [`Inode::fsync`],
+//!   以及周期性调用 [`FileSystem::flush_expired`] 时写回. 最后一个 Arc<FileSystem> 被释放 (卸载) 时也会写回.
 
-use std::sync::Arc;
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+use log::error;
 use spin::Mutex;
 
 use super::{
-    block_cache_sync_all, get_block_cache, Bitmap, BlockDevice, DiskInode, DiskInodeType, Inode,
-    SuperBlock, BLOCK_SIZE,
+    block_bits, block_cache_flush_expired, block_cache_format, block_cache_sync, checksum,
+    get_block_cache, Bitmap, BlockDevice, BlockKind, DiskInode, DiskInodeType, FreeExtents,
+    FsError, FsResult, Inode, SuperBlock, BLOCK_SIZES, SECTOR_SIZE,
 };
 
 /// 文件系统 (磁盘块管理器)
@@ -22,32 +65,102 @@ pub struct FileSystem {
     /// 让它们也能够直接访问块设备.
     pub block_device: Arc<dyn BlockDevice>,
     /// 索引节点位图
-    /// 一位代表一个索引节点, 一个块中存放4个索引节点
-    pub inode_bitmap: Bitmap,
+    /// 一位代表一个索引节点, 一个块中存放 block_size / 128 个索引节点
+    inode_bitmap: Mutex<Bitmap>,
     /// 数据块位图
     /// 一位代表一个数据块
-    pub data_bitmap: Bitmap,
+    data_bitmap: Mutex<Bitmap>,
     /// 索引区域起始块号
     inode_area_start_block: u32,
     /// 数据区域起始块号
     data_area_start_block: u32,
+    /// 数据区域的块数
+    data_area_blocks: u32,
+    /// 已经分配的索引节点数, 只在持有 inode_bitmap 的锁时修改
+    used_inodes: AtomicUsize,
+    /// 已经分配的数据块数, 只在持有 data_bitmap 的锁时修改
+    used_blocks: AtomicUsize,
+    /// 没有分配提示时从这里 (数据块位图中的 bit 编号) 开始寻找空闲块, 只在持有 data_bitmap 的锁时修改
+    data_cursor: AtomicUsize,
+    /// 块大小, 在创建文件系统时确定并记录在超级块中
+    pub block_size: usize,
+    /// 索引节点表, 以索引节点编号为键; Inode 完全释放之后才会移除它的表项 (see FileSystem::close_inode)
+    inodes: Mutex<BTreeMap<u32, Weak<Inode>>>,
+    /// 块缓存的写回策略, 在挂载时确定
+    cache_mode: CacheMode,
+}
+
+/// 块缓存的写回策略, see [`FileSystem::open_with`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheMode {
+    /// 每次操作结束时写回
+    #[default]
+    Sync,
+    /// 只在替换, 显式 sync/fsync 和周期性写回时写回
+    WriteBack,
+}
+
+impl core::str::FromStr for CacheMode {
+    type Err = ();
+
+    /// 挂载选项的写法: "sync" 或 "writeback"
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "sync" => Ok(CacheMode::Sync),
+            "writeback" => Ok(CacheMode::WriteBack),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 文件系统的空间使用情况, see [`FileSystem::statfs`]
+#[derive(Debug, Clone, Copy)]
+pub struct StatFs {
+    /// 块大小
+    pub block_size: usize,
+    /// 数据区域的总块数
+    pub total_blocks: usize,
+    /// 已经分配的数据块数 (包括索引块和目录块)
+    pub used_blocks: usize,
+    /// 空闲的数据块数
+    pub free_blocks: usize,
+    /// 索引节点总数
+    pub total_inodes: usize,
+    /// 已经分配的索引节点数
+    pub used_inodes: usize,
+    /// 空闲的索引节点数
+    pub free_inodes: usize,
 }
 
-type DataBlock = [u8; BLOCK_SIZE];
+type DataBlock = [u8];
+
+/// DiskInode 的大小
+const INODE_SIZE: usize = core::mem::size_of::<DiskInode>();
 
 impl FileSystem {
     /// 在块设备上创建并初始化一个文件系统
+    ///
+    /// 块设备上的内容会被全部清零, 因此这里访问的元数据块都不会校验失败, 只可能返回 FsError::Io.
+    /// 块大小不受支持, 没有索引节点位图, 或者 total_blocks 放不下各个区域时返回 FsError::InvalidArgument
     pub fn create(
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,        // 磁盘总块数
         inode_bitmap_blocks: u32, // 索引节点位图占用的块数
-    ) -> Arc<Mutex<Self>> {
+        block_size: u32,          // 块大小, 必须是 BLOCK_SIZES 中的一个
+    ) -> FsResult<Arc<Self>> {
+        if !BLOCK_SIZES.contains(&(block_size as usize)) || inode_bitmap_blocks == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let bs = block_size as usize;
+
         // 根据传入的参数计算每个区域各应该包含多少块
 
         let inode_bitmap = Bitmap::new(
             // note: inode 位图的起始块号是 1 (0 是超级块)
             1,
             inode_bitmap_blocks as usize,
+            bs,
+            inode_bitmap_blocks as usize * block_bits(bs),
         );
 
         // 根据 inode 位图的大小计算 inode 区域至少需要多少个块,
@@ -61,7 +174,7 @@ impl FileSystem {
         // inode 区域大小
         let inode_area_blocks =
             // 向上取整
-            ((inode_num * std::mem::size_of::<DiskInode>() + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32;
+            (inode_num * core::mem::size_of::<DiskInode>()).div_ceil(bs) as u32;
 
         // 索引节点使用总的块数 等于 索引节点位图占用的块数 加上 索引节点区域占用的块数
         let inode_total_blocks = inode_area_blocks + inode_bitmap_blocks;
@@ -70,11 +183,15 @@ impl FileSystem {
 
         // 总的数据块数 等于 磁盘总块数 减去 索引节点总的块数
         // Q: 为什么再减去 1 呢?(减去的 1 是超级块, block_id = 0)
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
+        // 至少要剩下一个数据块位图块和一个数据块
+        let data_total_blocks = total_blocks
+            .checked_sub(1 + inode_total_blocks)
+            .filter(|&blocks| blocks >= 2)
+            .ok_or(FsError::InvalidArgument)?;
 
         // 数据块位图区域大小
         //
-        // Q: 为什么要除以 4097 呢? 为什么不是除以 4096 呢?
+        // Q: 为什么要除以 4097 呢? 为什么不是除以 4096 呢? (以 512B 的块为例, 一个位图块有 4096 个 bit)
         //
         // 我们希望位图覆盖后面的数据块的前提下数据块尽量多.
         // 但要求数据块位图中的每个 bit 仍然能够对应到一个数据块,
@@ -86,8 +203,9 @@ impl FileSystem {
         // 得到 x >= data_total_blocks / 4097.
         // 数据块尽量多也就要求位图块数尽量少, 于是取 x 的最小整数解也就是 data_total_blocks / 4097 上取整, 也就是代码中的表达式.
         // 因此数据块位图区域最合理的大小是剩余的块数除以 4097 再上取整.
+        // 一般地, 块大小为 block_size 时除数为 block_size * 8 + 1.
         //
-        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
+        let data_bitmap_blocks = data_total_blocks.div_ceil(block_bits(bs) as u32 + 1);
 
         // 数据块区域大小
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
@@ -97,66 +215,86 @@ impl FileSystem {
             // inode_bitmap_blocks + inode_area_blocks = inode_total_blocks; + 1 is the super block
             (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
             data_bitmap_blocks as usize,
+            bs,
+            data_area_blocks as usize,
         );
 
         // 初始化文件系统
-        let mut fs = Self {
+        // 新创建的文件系统使用默认的写回策略, 需要其他策略时重新 open_with 即可
+        let cache_mode = CacheMode::Sync;
+        let fs = Self {
             block_device: Arc::clone(&block_device),
-            inode_bitmap,
-            data_bitmap,
+            inode_bitmap: Mutex::new(inode_bitmap),
+            data_bitmap: Mutex::new(data_bitmap),
             // Q: 为什么不是从 0 开始计算的: 0 这个块存放了其他信息(超级块)
             // 在 inode_area 之前存放了 inode_bitmap, 故 inode_area 的起始块号为 inode_bitmap_blocks + 1
             inode_area_start_block: 1 + inode_bitmap_blocks,
             // 在 data_area 之前存放了 inode_bitmap, inode_area, data_bitmap, 故 data_area 的起始块号为 inode_bitmap_blocks + inode_area_blocks + 2
             data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            data_area_blocks,
+            used_inodes: AtomicUsize::new(0),
+            used_blocks: AtomicUsize::new(0),
+            data_cursor: AtomicUsize::new(0),
+            block_size: bs,
+            inodes: Mutex::new(BTreeMap::new()),
+            cache_mode,
         };
 
-        // 既然是创建文件系统, 第一次使用, 需要将块设备的前 total_blocks 个块清零
+        // 既然是创建文件系统, 第一次使用, 需要将块设备的前 total_blocks 个块清零.
+        // 元数据区域的块按各自的用途格式化, 写回时会带上校验和
+        let inode_area_start = 1 + inode_bitmap_blocks;
+        let data_bitmap_start = 1 + inode_total_blocks;
+        let data_area_start = data_bitmap_start + data_bitmap_blocks;
         for i in 0..total_blocks {
-            get_block_cache(i as usize, Arc::clone(&block_device))
-                .lock()
-                .modify(0, |data_block: &mut DataBlock| {
-                    // 以块为单位, 将块中的所有字节都设置为 0
-                    for byte in data_block.iter_mut() {
-                        *byte = 0;
-                    }
-                });
+            let kind = match i {
+                0 => BlockKind::Super,
+                _ if i < inode_area_start => BlockKind::Bitmap,
+                _ if i < data_bitmap_start => BlockKind::Inode,
+                _ if i < data_area_start => BlockKind::Bitmap,
+                _ => BlockKind::Data,
+            };
+            block_cache_format(i as usize, bs, kind, &block_device)?;
         }
 
         // 初始化超级块
         // 将位于块设备编号为 0 块上的超级块进行初始化, 只需传入之前计算得到的每个区域的块数就行
-        get_block_cache(0, Arc::clone(&block_device)).lock().modify(
-            0,
-            |super_block: &mut SuperBlock| {
+        get_block_cache(0, bs, BlockKind::Super, Arc::clone(&block_device))?
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
                 super_block.initialize(
                     total_blocks,
                     inode_bitmap_blocks,
                     inode_area_blocks,
                     data_bitmap_blocks,
                     data_area_blocks,
+                    block_size,
                 );
-            },
-        );
+            });
 
         // 为根目录 "/" 创建一个 inode
         // 首先需要调用 alloc_inode 在 inode 位图中分配一个 inode ,
         // 由于这是第一次分配, 它的编号固定是 0 .
-        assert_eq!(fs.alloc_inode(), 0);
+        assert_eq!(fs.alloc_inode()?, 0);
 
         // 将分配到的 inode 初始化为 fs 中的根目录,
         // 故需要调用 get_disk_inode_pos 来根据 inode 编号获取该 inode 所在的块的编号以及块内偏移,
         // 之后就可以将它们传给 get_block_cache 和 modify 了
         let (root_inode_block_id, root_inode_offset) = fs.get_disk_inode_pos(0);
 
-        get_block_cache(root_inode_block_id as usize, Arc::clone(&block_device))
-            .lock()
-            .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-                disk_inode.initialize(DiskInodeType::Directory);
-            });
+        get_block_cache(
+            root_inode_block_id as usize,
+            bs,
+            BlockKind::Inode,
+            Arc::clone(&block_device),
+        )?
+        .lock()
+        .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
+            disk_inode.initialize(DiskInodeType::Directory);
+        });
 
-        block_cache_sync_all();
+        block_cache_sync(&block_device)?;
 
-        Arc::new(Mutex::new(fs))
+        Ok(Arc::new(fs))
     }
 
     /// 通过 inode_id
@@ -164,10 +302,10 @@ impl FileSystem {
     //
     // Q: 那么删除是不是可以解决
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
-        let inode_size = std::mem::size_of::<DiskInode>();
+        let inode_size = INODE_SIZE;
         // 每块有多少 inode
-        // inodes_per_block = BLOCK_SIZE / inode_size = 512 / 128 = 4,  表示每个块中有 4 个 inode
-        let inodes_pre_block = (BLOCK_SIZE / inode_size) as u32;
+        // inodes_per_block = block_size / inode_size, 对于 512B 的块为 512 / 128 = 4, 表示每个块中有 4 个 inode
+        let inodes_pre_block = (self.block_size / inode_size) as u32;
         let block_id = self.inode_area_start_block + inode_id / inodes_pre_block;
         (
             block_id,
@@ -189,76 +327,268 @@ impl FileSystem {
     /// 以 bit 组(每组 64 bits)为单位进行遍历,
     /// 找到一个尚未被全部分配出去的组,
     /// 最后在里面分配一个 bit.
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    ///
+    /// 所有索引节点都已经分配出去时返回 FsError::NoSpace
+    pub fn alloc_inode(&self) -> FsResult<u32> {
+        let inode_bitmap = self.inode_bitmap.lock();
+        if self.used_inodes.load(Ordering::Relaxed) >= inode_bitmap.maximum() {
+            return Err(FsError::NoSpace);
+        }
+        let bit = inode_bitmap
+            .alloc(&self.block_device)?
+            .ok_or(FsError::NoSpace)?;
+        self.used_inodes.fetch_add(1, Ordering::Relaxed);
+        Ok(bit as u32)
     }
 
-    /// 分配数据块
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    /// 分配数据块, 从游标处开始寻找
+    pub fn alloc_data(&self) -> FsResult<u32> {
+        self.alloc_data_run(None, 1).map(|(block_id, _)| block_id)
+    }
+
+    /// 分配数据块, 尽量分配块号为 goal 或者紧随其后的块
+    pub fn alloc_data_near(&self, goal: u32) -> FsResult<u32> {
+        self.alloc_data_run(Some(goal), 1)
+            .map(|(block_id, _)| block_id)
+    }
+
+    /// 分配一段连续的数据块, 返回 (起始块号, 块数)
+    ///
+    /// 从 goal (没有提示或提示不在数据区域时从游标) 开始寻找长度为 len 的连续空闲块;
+    /// 找不到时分配第一个空闲块开始尽可能长的一段, 因此返回的块数可能少于 len, 但至少为 1.
+    /// 分配之后游标移动到这段块之后.
+    pub fn alloc_data_run(&self, goal: Option<u32>, len: usize) -> FsResult<(u32, usize)> {
+        let data_bitmap = self.data_bitmap.lock();
+        if self.used_blocks.load(Ordering::Relaxed) >= self.data_area_blocks as usize {
+            return Err(FsError::NoSpace);
+        }
+        let goal = goal
+            .and_then(|goal| goal.checked_sub(self.data_area_start_block))
+            .filter(|&bit| bit < self.data_area_blocks)
+            .map_or_else(
+                || self.data_cursor.load(Ordering::Relaxed),
+                |bit| bit as usize,
+            );
+        let (bit, len) = data_bitmap
+            .alloc_run(&self.block_device, goal, len)?
+            .ok_or(FsError::NoSpace)?;
+        self.used_blocks.fetch_add(len, Ordering::Relaxed);
+        self.data_cursor.store(bit + len, Ordering::Relaxed);
+        Ok((bit as u32 + self.data_area_start_block, len))
     }
 
     /// 回收数据块
-    pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                data_block.iter_mut().for_each(|p| {
-                    *p = 0;
-                })
-            });
-        self.data_bitmap.dealloc(
+    ///
+    /// 回收的块可能是数据块, 目录块或索引块, 清零之后按数据块写回, 以后再用作元数据块时会重新格式化 (see DiskInode::get_or_alloc_block_id)
+    pub fn dealloc_data(&self, block_id: u32) -> FsResult<()> {
+        get_block_cache(
+            block_id as usize,
+            self.block_size,
+            BlockKind::Data,
+            Arc::clone(&self.block_device),
+        )?
+        .lock()
+        .modify_slice(|data_block: &mut DataBlock| {
+            data_block.iter_mut().for_each(|p| {
+                *p = 0;
+            })
+        });
+        let data_bitmap = self.data_bitmap.lock();
+        data_bitmap.dealloc(
             &self.block_device,
             (block_id - self.data_area_start_block) as usize,
-        )
+        )?;
+        self.used_blocks.fetch_sub(1, Ordering::Relaxed);
+        Ok(())
     }
 
-    // maybe
-    #[allow(unused)]
-    pub fn dealloc_inode(&mut self, inode_id: u32) {
-        // 由于一个块中可以存放 4 个索引节点, 因此相较于删除数据节点,
-        // inode_id 对应的数据大小为 DirEntry 的大小, 也就是 128 字节
-        // 而 block_id 对应的数据大小为 DataBlock 的大小, 也就是 512 字节
-        // 删除索引节点没那么容易 (可能需要修改数据结构)
-        // 不可以直接这样对块内的数据进行清零
-        // get_block_cache(inode_id as usize, Arc::clone(&self.block_device)) // 参数不应该是 inode_id
-        //     .lock()
-        //     .modify(0, |data_block: &mut DataBlock| {
-        //         data_block.iter_mut().for_each(|p| {
-        //             *p = 0;
-        //         })
-        //     });
-        self.inode_bitmap.dealloc(
-            &self.block_device,
-            (inode_id - self.inode_area_start_block) as usize,
-        )
+    /// 回收索引节点
+    ///
+    /// 由于一个块中可以存放多个索引节点, 不能像回收数据块那样将整个块清零,
+    /// 只能清零这个索引节点所在的 128 字节, 写回时会重新计算它的校验和.
+    /// 调用者需要先回收它的数据块, 索引块和扩展属性块.
+    pub fn dealloc_inode(&self, inode_id: u32) -> FsResult<()> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(
+            block_id as usize,
+            self.block_size,
+            BlockKind::Inode,
+            Arc::clone(&self.block_device),
+        )?
+        .lock()
+        .modify(block_offset, |disk_inode: &mut [u8; INODE_SIZE]| {
+            disk_inode.fill(0)
+        });
+        // 索引节点位图中的 bit 编号就是索引节点编号
+        let inode_bitmap = self.inode_bitmap.lock();
+        inode_bitmap.dealloc(&self.block_device, inode_id as usize)?;
+        self.used_inodes.fetch_sub(1, Ordering::Relaxed);
+        Ok(())
     }
 
     // 通过 open 方法可以从一个已写入了 fs 镜像的块设备上打开 fs
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
-        // 读超级块: 超级块的索引 id 为 0
-        get_block_cache(0, Arc::clone(&block_device))
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> FsResult<Arc<Self>> {
+        Self::open_with(block_device, CacheMode::Sync)
+    }
+
+    /// 与 open 相同, 但使用 cache_mode 作为块缓存的写回策略
+    pub fn open_with(
+        block_device: Arc<dyn BlockDevice>,
+        cache_mode: CacheMode,
+    ) -> FsResult<Arc<Self>> {
+        // 读超级块: 超级块位于 0 号块的开头.
+        // 打开之前还不知道块大小, 因此直接读出第 0 个扇区, 而不经过块缓存,
+        // 以免块缓存中留下一个大小不对的 0 号块
+        let mut sector = [0u8; SECTOR_SIZE];
+        block_device.read_block(0, &mut sector)?;
+        if !checksum::verify(&sector[..core::mem::size_of::<SuperBlock>()]) {
+            return Err(FsError::Corrupted(0));
+        }
+        let super_block =
+            unsafe { core::ptr::read_unaligned(sector.as_ptr() as *const SuperBlock) };
+        // 魔数不对 (不是 easy-fs, 或者是旧版本的镜像) 或者块大小不受支持, 同样无法继续解读
+        if !super_block.is_valid() {
+            return Err(FsError::Corrupted(0));
+        }
+
+        let block_size = super_block.block_size as usize;
+        let inode_total_blocks = super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
+
+        let inode_bitmap = Bitmap::new(
+            1,
+            super_block.inode_bitmap_blocks as usize,
+            block_size,
+            super_block.inode_bitmap_blocks as usize * block_bits(block_size),
+        );
+        let data_bitmap = Bitmap::new(
+            (1 + inode_total_blocks) as usize,
+            super_block.data_bitmap_blocks as usize,
+            block_size,
+            super_block.data_area_blocks as usize,
+        );
+        // 只在打开时扫描一次位图, 之后的分配和回收都会维护这两个计数
+        let used_inodes = inode_bitmap.count_allocated(&block_device)?;
+        let used_blocks = data_bitmap.count_allocated(&block_device)?;
+
+        let fs = Self {
+            block_device,
+            inode_bitmap: Mutex::new(inode_bitmap),
+            data_bitmap: Mutex::new(data_bitmap),
+            inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
+            // FIX: BUG for dealloc_data
+            data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
+            data_area_blocks: super_block.data_area_blocks,
+            used_inodes: AtomicUsize::new(used_inodes),
+            used_blocks: AtomicUsize::new(used_blocks),
+            data_cursor: AtomicUsize::new(0),
+            block_size,
+            inodes: Mutex::new(BTreeMap::new()),
+            cache_mode,
+        };
+
+        Ok(Arc::new(fs))
+    }
+
+    /// 统计文件系统的空间使用情况
+    ///
+    /// 已经分配的数据块和索引节点数由 alloc/dealloc 维护, 不需要扫描位图
+    pub fn statfs(&self) -> StatFs {
+        let total_inodes = self.inode_bitmap.lock().maximum();
+        let used_inodes = self.used_inodes.load(Ordering::Relaxed);
+        let total_blocks = self.data_area_blocks as usize;
+        let used_blocks = self.used_blocks.load(Ordering::Relaxed);
+        StatFs {
+            block_size: self.block_size,
+            total_blocks,
+            used_blocks,
+            free_blocks: total_blocks - used_blocks,
+            total_inodes,
+            used_inodes,
+            free_inodes: total_inodes - used_inodes,
+        }
+    }
+
+    /// 块缓存的写回策略
+    pub fn cache_mode(&self) -> CacheMode {
+        self.cache_mode
+    }
+
+    /// 一次修改文件系统的操作结束时调用: sync 模式下写回这个设备上所有被修改的块
+    pub(crate) fn write_through(&self) -> FsResult<()> {
+        match self.cache_mode {
+            CacheMode::Sync => block_cache_sync(&self.block_device),
+            CacheMode::WriteBack => Ok(()),
+        }
+    }
+
+    /// 写回这个文件系统所有被修改的块
+    pub fn sync(&self) -> FsResult<()> {
+        block_cache_sync(&self.block_device)
+    }
+
+    /// 周期性写回, 写回已经脏了至少一个周期的块, see [`block_cache_flush_expired`]
+    ///
+    /// 应当由内核以固定的间隔调用; sync 模式下所有的块在操作结束时就已经写回了, 调用它也没有坏处
+    pub fn flush_expired(&self) -> FsResult<()> {
+        block_cache_flush_expired(&self.block_device)
+    }
+
+    /// 索引节点位图和数据块位图所在的块
+    pub(crate) fn bitmap_block_ids(&self) -> impl Iterator<Item = usize> {
+        let inode_bitmap = self.inode_bitmap.lock().block_ids();
+        inode_bitmap.chain(self.data_bitmap.lock().block_ids())
+    }
+
+    /// 统计数据区域中空闲空间的碎片情况, 需要扫描整个数据块位图
+    pub fn free_extents(&self) -> FsResult<FreeExtents> {
+        self.data_bitmap.lock().free_extents(&self.block_device)
+    }
+
+    /// 获取编号为 inode_id 的索引节点
+    ///
+    /// 如果这个索引节点已经有人打开, 返回同一个 Arc<Inode>, 否则新建一个并记录在索引节点表中.
+    /// 旧的 Inode 正在释放 (例如正在回收一个已经被删除的文件) 时, 等它释放完再新建
+    pub fn get_inode(self: &Arc<Self>, inode_id: u32) -> Arc<Inode> {
+        loop {
+            let mut inodes = self.inodes.lock();
+            match inodes.get(&inode_id) {
+                Some(inode) => {
+                    if let Some(inode) = inode.upgrade() {
+                        return inode;
+                    }
+                }
+                None => {
+                    let inode = Arc::new(Inode::new(inode_id, Arc::clone(self)));
+                    inodes.insert(inode_id, Arc::downgrade(&inode));
+                    return inode;
+                }
+            }
+            drop(inodes);
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Inode 释放时调用: 从索引节点表中移除它的表项, dealloc 为 true 时同时回收索引节点
+    ///
+    /// 回收时在索引节点表的锁内清除位图中的 bit, 因此这个编号被 create 重新分配之后,
+    /// get_inode 一定不会再看到旧的 Inode 的表项
+    pub(crate) fn close_inode(&self, inode_id: u32, dealloc: bool) -> FsResult<()> {
+        let mut inodes = self.inodes.lock();
+        inodes.remove(&inode_id);
+        if dealloc {
+            self.dealloc_inode(inode_id)?;
+            drop(inodes);
+            self.write_through()?;
+        }
+        Ok(())
+    }
+
+    /// 编号为 inode_id 的索引节点当前被打开的次数, 即它的 Arc<Inode> 的数量
+    pub fn open_count(&self, inode_id: u32) -> usize {
+        self.inodes
             .lock()
-            .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading EFS!");
-
-                let inode_total_blocks =
-                    super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-
-                let fs = Self {
-                    block_device,
-                    inode_bitmap: Bitmap::new(1, super_block.inode_bitmap_blocks as usize),
-                    data_bitmap: Bitmap::new(
-                        (1 + inode_total_blocks) as usize,
-                        super_block.data_bitmap_blocks as usize,
-                    ),
-                    inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                    // FIX: BUG for dealloc_data
-                    data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
-                };
-
-                Arc::new(Mutex::new(fs))
-            })
+            .get(&inode_id)
+            .map_or(0, |inode| inode.strong_count())
     }
 
     // 文件系统的使用者在通过 FileSystem::open 从装载了 fs 镜像的块设备上打开 efs 之后,
@@ -270,21 +600,18 @@ impl FileSystem {
     // 事实上 FileSystem 提供了另一个名为 root_inode 的方法来获取根目录的 Inode
 
     /// 获取文件系统的根inode
-    pub fn root_inode(fs: &Arc<Mutex<Self>>) -> Inode {
-        // acquire fs lock temporarily
-        let block_device = Arc::clone(&fs.lock().block_device);
-        // release fs lock (lock used in stack 在函数中使用, 释放 drop 掉)
-
-        // acquire fs lock temporarily
-        // 对于 root_inode 的初始化, 是在调用 Inode::new 时将传入的 inode_id 设置为 0 ,
+    pub fn root_inode(fs: &Arc<Self>) -> Arc<Inode> {
+        // 对于 root_inode 的初始化, 是在调用 get_inode 时将传入的 inode_id 设置为 0 ,
         // 因为根目录对应于文件系统中第一个分配的 inode , 因此它的 inode_id 总会是 0 .
-        let (block_id, block_offset) = fs.lock().get_disk_inode_pos(0);
-        // release fs lock
-
-        // 不会在调用 Inode::new 过程中尝试获取整个 FileSystem 的锁来查询 inode 在块设备中的位置,
-        // 而是在调用它之前预先查询并作为参数传过去
-        Inode::new(block_id, block_offset, Arc::clone(fs), block_device)
+        fs.get_inode(0)
     }
+}
 
-    // TODO: dealloc_inode
+impl Drop for FileSystem {
+    /// 卸载: 最后一个 Arc<FileSystem> 被释放时 (此时已经没有打开的 Inode), 写回所有被修改的块
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            error!("failed to sync file system on unmount: {}", err);
+        }
+    }
 }
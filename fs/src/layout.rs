@@ -6,6 +6,7 @@
 //!
 //! - 最开始的区域的长度为一个块, 其内容是超级块 ([`SuperBlock`])
 //!   超级块内以 魔数 的形式提供了文件系统合法性检查功能, 同时还可以定位其他连续区域的位置
+//!   (超级块, 索引节点, 位图块, 索引块和目录块还带有 CRC32C 校验和, see checksum.rs)
 //!
 //! - 第二个区域是一个索引节点位图, 长度为若干个块
 //!   它记录了后面的索引节点区域中有哪些索引节点已经被分配出去使用了, 而哪些还尚未被分配出去
@@ -19,14 +20,14 @@
 //! - 最后的区域则是数据块区域
 //!   其中的每一个已经分配出去的块保存了文件或目录中的具体数据内容.
 
-use std::{
-    fmt::{Debug, Formatter, Result},
-    sync::Arc,
-};
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt::{Debug, Formatter, Result};
 
 use super::{
-    get_block_cache, BlockDevice, BLOCK_SIZE, DIRENT_SIZE, EAZY_FS_MAGIC, INDIRECT1_BOUND,
-    INODE_DIRECT_COUNT, INODE_INDIRECT1_COUNT, INODE_INDIRECT2_COUNT, NAME_LENGTH_LIMIT,
+    block_cache_format, dirents_per_block, get_block_cache, indirect1_bound, indirect2_bound,
+    indirect3_bound, inode_indirect1_count, inode_indirect2_count, BlockDevice, BlockKind,
+    FsResult, BLOCK_SIZES, DIRECT_BOUND, DIRENT_SIZE, EAZY_FS_MAGIC, INODE_DIRECT_COUNT,
+    NAME_LENGTH_LIMIT,
 };
 
 #[repr(C)]
@@ -37,6 +38,10 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    /// 文件系统的块大小, 以字节为单位, 是 BLOCK_SIZES 中的一个
+    pub block_size: u32,
+    /// 以上字段的 CRC32C, 由块缓存在写回时计算
+    checksum: u32,
 }
 
 impl Debug for SuperBlock {
@@ -48,6 +53,8 @@ impl Debug for SuperBlock {
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("block_size", &self.block_size)
+            .field("checksum", &self.checksum)
             .finish()
     }
 }
@@ -64,6 +71,7 @@ impl SuperBlock {
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
         data_area_blocks: u32,
+        block_size: u32,
     ) {
         *self = Self {
             magic: EAZY_FS_MAGIC,
@@ -72,33 +80,39 @@ impl SuperBlock {
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            block_size,
+            checksum: 0,
         };
     }
 
-    /// is_valid 可以通过魔数判断超级块所在的文件系统是否合法
+    /// is_valid 可以通过魔数判断超级块所在的文件系统是否合法, 同时检查记录的块大小是否受支持
+    ///
+    /// 校验和由块缓存 (或 FileSystem::open) 在读入超级块时检查
     pub fn is_valid(&self) -> bool {
-        self.magic == EAZY_FS_MAGIC
+        self.magic == EAZY_FS_MAGIC && BLOCK_SIZES.contains(&(self.block_size as usize))
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum DiskInodeType {
     File,
     Directory,
 }
 
 /// 索引块 IndirectBlock 实质上是一个 u32 数组, 每个都指向一个下一级索引块或者数据块
-type IndirectBlock = [u32; BLOCK_SIZE / 4]; // size = 512B / 4B(u32) = 128
+///
+/// 最后一个 u32 是整个块的校验和, 因此只有前 inode_indirect1_count(block_size) 个表项可用
+type IndirectBlock = [u32]; // len = block_size / 4B(u32), 512B 的块为 128 (127 个表项)
 
 // 作为一个文件而言, 它的内容在文件系统看来没有任何既定的格式, 都只是
 // 一个 (u8) 字节序列, 因此每个保存内容的数据块都只是一个字节数组
-type DataBlock = [u8; BLOCK_SIZE]; // size = 512B
+type DataBlock = [u8]; // len = block_size
 
 /// 每个 文件/目录 在磁盘上均以一个 DiskInode 的形式存储
 ///
-/// 由于字节对齐, DiskInode 大小为 (1 + 28 + 1 + 1) * 4 + 4(字节对齐) = 128 B
+/// 由于字节对齐, DiskInode 大小为 (2 + 24 + 3 + 1) * 4 + 4(type_, 字节对齐) + 4(checksum) = 128 B
 ///
-/// 为了充分利用空间, 将 DiskInode 的大小设置为 128 字节, 每个块正好能够容纳 4 个 DiskInode
+/// 为了充分利用空间, 将 DiskInode 的大小设置为 128 字节, 每个 512 字节的块正好能够容纳 4 个 DiskInode
 //
 // 注意: 在后续需要支持更多类型的元数据的时候, 可以适当缩减直接索引 direct 的块
 // 数, 并将节约出来的空间用来存放其他元数据, 仍可保证 DiskInode 的总大小为 128 字节
@@ -106,6 +120,7 @@ type DataBlock = [u8; BLOCK_SIZE]; // size = 512B
 // Q: 删除文件 / 文件夹时如何删除索引节点块中的索引节点?
 // 由于一个块中可以存放 4 个索引节点, 因此相较于删除数据节点, 删除索引节点没那么容易 (可能需要修改数据结构)
 #[repr(C)]
+#[derive(Clone)]
 pub struct DiskInode {
     /// 文件/目录内容的字节数
     pub size: u32,
@@ -122,7 +137,8 @@ pub struct DiskInode {
     /// 当文件比较大的时候, 不仅直接索引的 direct 数组装满, 还需要用到一级间接索引 indirect1
     /// , 它指向一个一级索引块, 这个块也位于磁盘布局的数据块区域中
     /// . 这个一级索引块中的每个 u32 都用来指向数据块区域中一个保存该文件内容的数据块
-    /// . 因此, 最多能够索引 INODE_INDIRECT1_COUNT =  512B / 4B(u32) = 128 数据块, 对应 512B * 128 = 64KB 的内容
+    /// . 因此, 最多能够索引 block_size / 4B(u32) 个数据块, 对于 512B 的块为 128 个, 对应 512B * 128 = 64KB 的内容
+    /// (以下容量均以 512B 的块为例, 4K 的块每级索引可以容纳 1024 个表项)
     pub indirect1: u32,
     /// 二级间接索引块(号)
     ///
@@ -131,8 +147,19 @@ pub struct DiskInode {
     /// 一个不同的一级索引块, 这些一级索引块也位于数据块区域中
     /// . 因此, 通过二级间接索引最多能够索引 128 * 64KB = 8MB 的内容
     pub indirect2: u32,
+    /// 三级间接索引块(号)
+    ///
+    /// 直接索引加上一二级索引只能支持约 8MB 的文件, 对于磁盘镜像和日志来说太小了
+    /// . 三级索引块中的每个 u32 指向一个二级索引块, 因此最多能够索引 128 * 8MB = 1GB 的内容
+    pub indirect3: u32,
+    /// 扩展属性块(号), 0 表示没有扩展属性, see xattr.rs
+    ///
+    /// 所有扩展属性放在这一个块中
+    pub xattr: u32,
     /// 索引节点的类型 DiskInodeType, 目前仅支持文件 File 和目录 Directory 两种类型
     pub type_: DiskInodeType,
+    /// 以上字段的 CRC32C, 由块缓存在写回时计算
+    checksum: u32,
 }
 
 impl DiskInode {
@@ -142,7 +169,10 @@ impl DiskInode {
         self.direct.iter_mut().for_each(|x| *x = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        self.indirect3 = 0;
+        self.xattr = 0;
         self.type_ = type_;
+        self.checksum = 0;
     }
 
     pub fn is_dir(&self) -> bool {
@@ -153,61 +183,281 @@ impl DiskInode {
         self.type_ == DiskInodeType::File
     }
 
+    /// 自身数据块的用途: 目录的数据块需要校验, 文件的数据块不需要
+    pub(crate) fn content_kind(&self) -> BlockKind {
+        if self.is_dir() {
+            BlockKind::Dir
+        } else {
+            BlockKind::Data
+        }
+    }
+
+    // 目录块的最后一个目录项位置存放校验和, 因此目录项在目录内容中并不是紧密排列的:
+    // 每个块只放 dirents_per_block 个目录项. 目录的 size 为最后一个目录项之后的偏移.
+
+    /// 第 index 个目录项在目录内容中的字节偏移
+    pub fn dirent_offset(index: usize, block_size: usize) -> usize {
+        let per_block = dirents_per_block(block_size);
+        index / per_block * block_size + index % per_block * DIRENT_SIZE
+    }
+
+    /// 目录中目录项的数量
+    pub fn dirent_count(&self, block_size: usize) -> usize {
+        let size = self.size as usize;
+        size / block_size * dirents_per_block(block_size) + size % block_size / DIRENT_SIZE
+    }
+
+    /// 将文件内部第 inner_id 个数据块定位到某一级间接索引上,
+    /// 返回 (索引级数, 在该级索引所覆盖范围内的相对编号); 直接索引的级数为 0
+    fn locate(inner_id: usize, block_size: usize) -> (usize, usize) {
+        if inner_id < DIRECT_BOUND {
+            (0, inner_id)
+        } else if inner_id < indirect1_bound(block_size) {
+            (1, inner_id - DIRECT_BOUND)
+        } else if inner_id < indirect2_bound(block_size) {
+            (2, inner_id - indirect1_bound(block_size))
+        } else {
+            assert!(
+                inner_id < indirect3_bound(block_size),
+                "block {} out of range",
+                inner_id
+            );
+            (3, inner_id - indirect2_bound(block_size))
+        }
+    }
+
+    /// level 级索引块下每个表项所覆盖的数据块数目
+    fn entry_capacity(level: usize, block_size: usize) -> usize {
+        inode_indirect1_count(block_size).pow(level as u32 - 1)
+    }
+
     /// 通过索引查到它自身用于保存文件内容的第 block_id 个数据块的块编号, 这样后续才能对这个数据块进行访问
-    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+    ///
+    /// 块编号 0 是超级块, 不可能是数据块或索引块, 因此用它来表示空洞 (hole):
+    /// 只要路径上任意一级索引为 0, 就返回 0, 调用者应当将其视为全零的数据块
+    pub fn get_block_id(
+        &self,
+        inner_id: u32,
+        block_size: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<u32> {
         // 块索引
-        let inner_id = inner_id as usize;
-
-        if inner_id < INODE_DIRECT_COUNT {
+        let (level, mut last) = Self::locate(inner_id as usize, block_size);
+        let mut block_id = match level {
             // 直接索引
-            self.direct[inner_id]
-        } else if inner_id < INDIRECT1_BOUND {
-            // 一级索引
-            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-                .lock()
-                // 解析为 IndirectBlock 指向一个下一级索引块或者数据块
-                .read(0, |indirect_block: &IndirectBlock| {
-                    indirect_block[inner_id - INODE_DIRECT_COUNT]
-                })
-        } else {
-            // 二级索引
-            let last = inner_id - INDIRECT1_BOUND;
-            // 对于二级索引的情况, 需要先查二级索引块找到挂在它下面的一级 子 索引块
-            let indirect1 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
-                .lock()
-                .read(0, |indirect2: &IndirectBlock| {
-                    indirect2[last / INODE_INDIRECT1_COUNT]
-                });
-            // 再通过一级 子 索引块找到数据块
-            get_block_cache(indirect1 as usize, Arc::clone(block_device))
-                .lock()
-                .read(0, |indirect1: &IndirectBlock| {
-                    indirect1[last % INODE_INDIRECT1_COUNT]
-                })
+            0 => return Ok(self.direct[last]),
+            1 => self.indirect1,
+            2 => self.indirect2,
+            _ => self.indirect3,
+        };
+        // 从最高一级索引块开始逐级向下查找, 直到找到数据块
+        for level in (1..=level).rev() {
+            if block_id == 0 {
+                return Ok(0);
+            }
+            let capacity = Self::entry_capacity(level, block_size);
+            block_id = get_block_cache(
+                block_id as usize,
+                block_size,
+                BlockKind::Indirect,
+                Arc::clone(block_device),
+            )?
+            .lock()
+            // 解析为 IndirectBlock 指向一个下一级索引块或者数据块
+            .read_slice(|indirect_block: &IndirectBlock| indirect_block[last / capacity]);
+            last %= capacity;
+        }
+        Ok(block_id)
+    }
+
+    /// 与 get_block_id 相同, 但会为路径上的空洞 (索引块或数据块) 分配新块.
+    ///
+    /// 新块由上层的磁盘块管理器通过 alloc 分配; 磁盘块管理器保证新分配的块内容全为 0,
+    /// 因此新的索引块中的所有表项天然都是空洞. 新的索引块和目录块在这里格式化, 以便之后能通过校验.
+    pub fn get_or_alloc_block_id(
+        &mut self,
+        inner_id: u32,
+        block_size: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        alloc: &mut impl FnMut() -> FsResult<u32>,
+    ) -> FsResult<u32> {
+        let (level, mut last) = Self::locate(inner_id as usize, block_size);
+        let content_kind = self.content_kind();
+        // 最后一级是数据块 (或目录块), 其余都是索引块; 普通数据块不做校验, 不需要格式化
+        let mut alloc = |level: usize| -> FsResult<u32> {
+            let kind = match level {
+                0 => content_kind,
+                _ => BlockKind::Indirect,
+            };
+            let block_id = alloc()?;
+            if kind != BlockKind::Data {
+                block_cache_format(block_id as usize, block_size, kind, block_device)?;
+            }
+            Ok(block_id)
+        };
+        let root = match level {
+            0 => &mut self.direct[last],
+            1 => &mut self.indirect1,
+            2 => &mut self.indirect2,
+            _ => &mut self.indirect3,
+        };
+        if *root == 0 {
+            *root = alloc(level)?;
+        }
+        let mut block_id = *root;
+        for level in (1..=level).rev() {
+            let capacity = Self::entry_capacity(level, block_size);
+            block_id = get_block_cache(
+                block_id as usize,
+                block_size,
+                BlockKind::Indirect,
+                Arc::clone(block_device),
+            )?
+            .lock()
+            .modify_slice(|indirect_block: &mut IndirectBlock| -> FsResult<u32> {
+                let entry = &mut indirect_block[last / capacity];
+                if *entry == 0 {
+                    *entry = alloc(level - 1)?;
+                }
+                Ok(*entry)
+            })?;
+            last %= capacity;
+        }
+        Ok(block_id)
+    }
+
+    /// 统计实际分配的数据块, 索引块和扩展属性块数目, 空洞不计入
+    pub fn allocated_blocks(
+        &self,
+        block_size: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<u32> {
+        Ok(self.block_ids(block_size, block_device)?.len() as u32)
+    }
+
+    /// 列出实际分配的所有数据块, 索引块和扩展属性块的块编号, 空洞不计入
+    pub fn block_ids(
+        &self,
+        block_size: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<Vec<u32>> {
+        let mut block_ids: Vec<u32> = self
+            .direct
+            .iter()
+            .copied()
+            .filter(|&block_id| block_id != 0)
+            .collect();
+        for (level, indirect) in [
+            (1, self.indirect1),
+            (2, self.indirect2),
+            (3, self.indirect3),
+        ] {
+            Self::collect_indirect(indirect, level, block_size, block_device, &mut block_ids)?;
+        }
+        if self.xattr != 0 {
+            block_ids.push(self.xattr);
+        }
+        Ok(block_ids)
+    }
+
+    /// 统计文件内部第 start..end 个数据块中还是空洞的块数, 也就是写入这些块时需要分配的数据块数 (不含索引块)
+    pub fn holes(
+        &self,
+        start: u32,
+        end: u32,
+        block_size: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<u32> {
+        let mut holes = 0;
+        for inner_id in start..end {
+            if self.get_block_id(inner_id, block_size, block_device)? == 0 {
+                holes += 1;
+            }
+        }
+        Ok(holes)
+    }
+
+    /// 统计文件的数据块在磁盘上被分成了多少段连续的区间 (extent), 空洞不计入
+    ///
+    /// 顺序写入的文件通常只有一段; 数据块中间夹着索引块时也会被分开
+    pub fn extents(&self, block_size: usize, block_device: &Arc<dyn BlockDevice>) -> FsResult<u32> {
+        let mut extents = 0;
+        let mut last = 0;
+        for inner_id in 0..self.data_blocks(block_size) {
+            let block_id = self.get_block_id(inner_id, block_size, block_device)?;
+            if block_id == 0 {
+                continue;
+            }
+            if last == 0 || block_id != last + 1 {
+                extents += 1;
+            }
+            last = block_id;
+        }
+        Ok(extents)
+    }
+
+    /// 收集 level 级索引块 indirect 本身及其下所有的数据块和索引块
+    fn collect_indirect(
+        indirect: u32,
+        level: usize,
+        block_size: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        block_ids: &mut Vec<u32>,
+    ) -> FsResult<()> {
+        if indirect == 0 {
+            return Ok(());
         }
+        let count = inode_indirect1_count(block_size);
+        let entries: Vec<u32> = get_block_cache(
+            indirect as usize,
+            block_size,
+            BlockKind::Indirect,
+            Arc::clone(block_device),
+        )?
+        .lock()
+        .read_slice(|indirect_block: &IndirectBlock| {
+            indirect_block[..count]
+                .iter()
+                .copied()
+                .filter(|&block_id| block_id != 0)
+                .collect()
+        });
+        // 不持有块锁递归, 以免同时锁住太多块
+        block_ids.push(indirect);
+        for entry in entries {
+            if level == 1 {
+                block_ids.push(entry);
+            } else {
+                Self::collect_indirect(entry, level - 1, block_size, block_device, block_ids)?;
+            }
+        }
+        Ok(())
     }
 
     // 在对文件/目录初始化之后, 它的 size 均为 0, 此时并不会索引到
     // 任何数据块, 它需要通过 increase_size 方法逐步扩充容量.
-    // 在扩充的时候, 自然需要一些新的数据块来作为索引块或是保存内容的数据块.
-    // 故需要先编写一些辅助方法来确定在容量扩充的时候额外需要多少块.
+    // 由于支持了空洞, 扩充容量时并不会分配数据块, 数据块和索引块会在第一次写入时才分配.
 
     /// 计算为了容纳自身 size 字节的内容需要多少个数据块
-    pub fn data_blocks(&self) -> u32 {
-        Self::_data_blocks(self.alloc_size)
+    pub fn data_blocks(&self, block_size: usize) -> u32 {
+        Self::_data_blocks(self.alloc_size, block_size)
     }
 
-    fn _data_blocks(size: u32) -> u32 {
-        // 用 size 除以每个块的大小 BLOCK_SZ 并向上取整
-        (size + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32
+    fn _data_blocks(size: u32, block_size: usize) -> u32 {
+        // 用 size 除以每个块的大小 block_size 并向上取整
+        size.div_ceil(block_size as u32)
     }
 
-    pub fn total_blocks(size: u32) -> u32 {
+    /// 一个没有空洞的 size 字节的文件所占用的数据块和索引块总数
+    pub fn total_blocks(size: u32, block_size: usize) -> u32 {
         // total_blocks 不仅包含数据块, 还需要统计索引块
+        let indirect1_count = inode_indirect1_count(block_size);
+        let indirect1_bound = indirect1_bound(block_size);
+        let indirect2_bound = indirect2_bound(block_size);
 
         // 调用 data_blocks 得到需要多少数据块
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
+        let data_blocks = Self::_data_blocks(size, block_size) as usize;
+        let mut total = data_blocks;
 
         // 根据数据块数目所处的区间统计索引块
 
@@ -216,204 +466,180 @@ impl DiskInode {
             total += 1;
         }
 
-        if data_blocks > INDIRECT1_BOUND {
+        if data_blocks > indirect1_bound {
             // 二级级索引
             total += 1;
 
             // 二级索引的一级子索引
-            total +=
-                (data_blocks - INDIRECT1_BOUND - 1 + INODE_INDIRECT1_COUNT) / INODE_INDIRECT1_COUNT;
+            total += (data_blocks.min(indirect2_bound) - indirect1_bound).div_ceil(indirect1_count);
+        }
+
+        if data_blocks > indirect2_bound {
+            // 三级索引
+            total += 1;
+
+            let rest = data_blocks - indirect2_bound;
+            // 三级索引的二级子索引
+            total += rest.div_ceil(inode_indirect2_count(block_size));
+            // 三级索引的一级孙索引
+            total += rest.div_ceil(indirect1_count);
         }
 
         total as u32
     }
 
-    /// 计算将一个 DiskInode 的 size 扩容到 new_size 需要额外多少个数据和索引块
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-        assert!(new_size >= self.alloc_size);
-        // 调用两次 total_blocks 作差
-        Self::total_blocks(new_size) - Self::total_blocks(self.alloc_size)
+    /// 将文件大小扩充到 new_size, 新增的部分是一个空洞, 读出来全为 0
+    ///
+    /// alloc_size 记录索引所覆盖的范围, 超出 alloc_size 的索引表项一定为 0;
+    /// 对于目录而言, 删除目录项只会减小 size, 因此 alloc_size 可能大于 size.
+    /// 调用者需要保证 new_size 不超过 max_file_size (Inode::write 和 Inode::truncate 会检查)
+    pub fn increase_size(&mut self, new_size: u32, block_size: usize) {
+        assert!(
+            Self::_data_blocks(new_size, block_size) as usize <= indirect3_bound(block_size),
+            "file size {} exceeds the indirect3 limit",
+            new_size
+        );
+        if new_size > self.size {
+            self.size = new_size;
+        }
+        self.alloc_size = self.alloc_size.max(new_size);
     }
 
-    /// 通过 increase_size 方法逐步扩充容量
-    /// 在对文件/目录初始化之后, 它的 size 均为 0, 此时并不会索引到任何数据块.
-    /// 在扩充的时候, 需要一些新的数据块来作为索引块或是保存内容的数据块.
-    pub fn increase_size(
+    /// 将文件截断或扩充到 new_size, 返回需要由磁盘块管理器回收的数据块和索引块
+    ///
+    /// 缩小时, 第 data_blocks(new_size) 个及之后的数据块, 以及因此变空的索引块都会被回收,
+    /// 最后一个数据块中超出 new_size 的部分会被清零, 以保证之后再扩充时读到的是 0
+    pub fn truncate(
         &mut self,
         new_size: u32,
-        // 保存了本次容量扩充所需块编号的向量, 这些块都是由上层的磁盘块管理器负责分配的
-        new_blocks: Vec<u32>,
+        block_size: usize,
         block_device: &Arc<dyn BlockDevice>,
-    ) {
-        let mut current_blocks = self.data_blocks(); // 当前文件大小所需的数据块数目
-        self.size = new_size;
-        self.alloc_size = new_size;
-        // Q: 为什么不用 total_block 方法
-        // A: 注意参数 new_blocks 是由上层的磁盘块管理器负责分配的 (包括了索引需要使用的 block), 这里计算的 total_block 只与数据大小相关
-        let mut total_blocks = self.data_blocks(); // 扩容后的总块数
-        let mut new_blocks = new_blocks.into_iter();
-
-        // 填充直接索引
-        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            // note: index current_blocks 相当于第 current_blocks+1 块
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
-            current_blocks += 1;
+    ) -> FsResult<Vec<u32>> {
+        if new_size >= self.size && new_size >= self.alloc_size {
+            self.increase_size(new_size, block_size);
+            return Ok(Vec::new());
         }
 
-        // 分配一级索引
-        if total_blocks > INODE_DIRECT_COUNT as u32 {
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
-                // 直接索引已经填满, 需要分配一级索引
-                self.indirect1 = new_blocks.next().unwrap();
+        // 清零最后一个数据块的尾部
+        let tail = new_size as usize % block_size;
+        if tail != 0 {
+            let block_id =
+                self.get_block_id(new_size / block_size as u32, block_size, block_device)?;
+            if block_id != 0 {
+                get_block_cache(
+                    block_id as usize,
+                    block_size,
+                    self.content_kind(),
+                    Arc::clone(block_device),
+                )?
+                .lock()
+                .modify_slice(|data_block: &mut DataBlock| data_block[tail..].fill(0));
             }
-            current_blocks -= INODE_DIRECT_COUNT as u32;
-            total_blocks -= INODE_DIRECT_COUNT as u32;
-        } else {
-            return;
         }
 
-        // 填充一级索引
-        get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-            .lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                    indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                    current_blocks += 1;
-                }
-            });
+        // 保存所有需要回收的块编号
+        let mut v: Vec<u32> = Vec::new();
+        let keep = Self::_data_blocks(new_size, block_size) as usize;
+        self.size = new_size;
+        self.alloc_size = new_size;
 
-        // 分配二级索引
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
-                // 一级索引已经填满, 需要分配二级索引
-                self.indirect2 = new_blocks.next().unwrap();
+        // 回收直接索引
+        for block_id in self.direct.iter_mut().skip(keep) {
+            if *block_id != 0 {
+                v.push(*block_id);
+                *block_id = 0;
             }
-            current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            total_blocks -= INODE_INDIRECT1_COUNT as u32;
-        } else {
-            return;
         }
+        // 依次回收一级, 二级, 三级索引
+        Self::truncate_indirect(
+            &mut self.indirect1,
+            1,
+            keep.saturating_sub(DIRECT_BOUND),
+            block_size,
+            &mut v,
+            block_device,
+        )?;
+        Self::truncate_indirect(
+            &mut self.indirect2,
+            2,
+            keep.saturating_sub(indirect1_bound(block_size)),
+            block_size,
+            &mut v,
+            block_device,
+        )?;
+        Self::truncate_indirect(
+            &mut self.indirect3,
+            3,
+            keep.saturating_sub(indirect2_bound(block_size)),
+            block_size,
+            &mut v,
+            block_device,
+        )?;
+        Ok(v)
+    }
 
-        // 填充二级索引
-        // from (a0, b0) -> (a1, b1)
-        // a0 当前二级索引的索引号
-        let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
-        // b0 当前二级索引的一级子索引的索引号
-        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
-        // a1 目标二级索引的索引号
-        let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
-        // b1 目标二级索引的一级子索引的索引号
-        let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
-
-        // 分配二级索引的一级子索引
-        get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
-            .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
-                while (a0 < a1) || (a0 == a1 && b0 < b1) {
-                    if b0 == 0 {
-                        indirect2[a0] = new_blocks.next().unwrap();
-                    }
-
-                    // 填充二级索引的一级子索引
-                    get_block_cache(indirect2[a0] as usize, Arc::clone(block_device))
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            indirect1[b0] = new_blocks.next().unwrap();
-                        });
-
-                    // 移动到下一个一级子索引
-                    b0 += 1;
-                    if b0 == INODE_INDIRECT1_COUNT {
-                        b0 = 0;
-                        a0 += 1;
+    /// 回收 level 级索引块 *indirect 下除前 keep 个数据块之外的所有数据块和索引块.
+    /// 如果 keep 为 0, indirect 本身也会被回收并置为 0.
+    fn truncate_indirect(
+        indirect: &mut u32,
+        level: usize,
+        keep: usize,
+        block_size: usize,
+        v: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<()> {
+        let capacity = Self::entry_capacity(level, block_size);
+        let count = inode_indirect1_count(block_size);
+        if *indirect == 0 || keep >= capacity * count {
+            return Ok(());
+        }
+        get_block_cache(
+            *indirect as usize,
+            block_size,
+            BlockKind::Indirect,
+            Arc::clone(block_device),
+        )?
+        .lock()
+        .modify_slice(|indirect_block: &mut IndirectBlock| -> FsResult<()> {
+            // 最后一个 u32 是校验和, 不是表项
+            for (i, entry) in indirect_block[..count].iter_mut().enumerate() {
+                // 该表项所覆盖的数据块中需要保留的数目
+                let entry_keep = keep.saturating_sub(i * capacity).min(capacity);
+                if level == 1 {
+                    if entry_keep == 0 && *entry != 0 {
+                        v.push(*entry);
+                        *entry = 0;
                     }
+                } else {
+                    Self::truncate_indirect(
+                        entry,
+                        level - 1,
+                        entry_keep,
+                        block_size,
+                        v,
+                        block_device,
+                    )?;
                 }
-            });
+            }
+            Ok(())
+        })?;
+        if keep == 0 {
+            v.push(*indirect);
+            *indirect = 0;
+        }
+        Ok(())
     }
 
     /// 清空文件的内容并回收所有数据和索引块
     ///
-    /// 将大小清除为零并返回应释放的块, 再将块内容清零;
+    /// 将大小清除为零并返回应释放的块;
     /// 最后将回收的所有块的编号保存在一个向量中返回给磁盘块管理器
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
-        // 保存所有需要回收的块编号
-        let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
-        self.size = 0;
-        self.alloc_size = 0;
-        // 当前已经清空的块数目 分别对应直接索引, 一级索引, 二级索引
-        let mut current_blocks = 0usize;
-
-        // 回收直接索引
-        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]); // 保存需要回收的块编号
-            self.direct[current_blocks] = 0; // 清空直接索引
-            current_blocks += 1;
-        }
-
-        // 回收一级索引块
-        if data_blocks > INODE_DIRECT_COUNT {
-            v.push(self.indirect1);
-            data_blocks -= INODE_DIRECT_COUNT;
-            current_blocks = 0;
-        } else {
-            return v;
-        }
-        get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-            .lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                    v.push(indirect1[current_blocks]);
-                    // indirect1[current_blocks] = 0; // 磁盘内容不需要清空
-                    current_blocks += 1;
-                }
-            });
-        self.indirect1 = 0; // 清空一级索引
-
-        // 回收二级索引块
-        if data_blocks > INODE_INDIRECT1_COUNT {
-            v.push(self.indirect2);
-            data_blocks -= INODE_INDIRECT1_COUNT;
-        } else {
-            return v;
-        }
-        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
-        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
-        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
-        get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
-            .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
-                for i in 0..a1 {
-                    get_block_cache(indirect2[i] as usize, Arc::clone(block_device))
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            // 回收二级索引块的一级子索引
-                            for j in 0..INODE_INDIRECT1_COUNT {
-                                v.push(indirect1[j]);
-                                // indirect1[j] = 0; // 磁盘内容不需要清空
-                            }
-                        });
-                    // 回收二级索引
-                    v.push(indirect2[i]);
-                    // indirect2[i] = 0; // 磁盘内容不需要清空
-                }
-
-                // 对于最后一个一级子索引块
-                if b1 > 0 {
-                    get_block_cache(indirect2[a1] as usize, Arc::clone(block_device))
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            for j in 0..b1 {
-                                v.push(indirect1[j]);
-                                // indirect1[j] = 0; // 磁盘内容不需要清空
-                            }
-                        });
-                    v.push(indirect2[a1]);
-                    // indirect2[a1] = 0; // 磁盘内容不需要清空
-                }
-            });
-        self.indirect2 = 0; // 清空二级索引
-        v
+    pub fn clear_size(
+        &mut self,
+        block_size: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<Vec<u32>> {
+        self.truncate(0, block_size, block_device)
     }
 
     // 通过 DiskInode 来读写它索引的那些数据块中的数据
@@ -425,8 +651,9 @@ impl DiskInode {
         &self,
         offset: usize,
         buf: &mut [u8],
+        block_size: usize,
         block_device: &Arc<dyn BlockDevice>,
-    ) -> usize {
+    ) -> FsResult<usize> {
         // 从 offset 开始读取内容
         let mut start = offset;
         // 取最小值
@@ -434,10 +661,10 @@ impl DiskInode {
         // use size rather than alloc_size
         let end = (offset + buf.len()).min(self.size as usize);
         if start >= end {
-            return 0;
+            return Ok(0);
         }
         // 目前是文件内部第多少个数据块
-        let mut start_block = start / BLOCK_SIZE as usize;
+        let mut start_block = start / block_size;
         // 读取的字节数
         let mut read_size = 0usize;
 
@@ -447,10 +674,10 @@ impl DiskInode {
         loop {
             // 计算当前块的终止位置 (终止字节编号)
             // Q: 为什么要 +1 呢
-            // A: start_block * BLOCK_SIZE != 当前块的终止位置的字节编号 (第 0 块的终止字节编号为 1 * BLOCK_SIZE)
+            // A: start_block * block_size != 当前块的终止位置的字节编号 (第 0 块的终止字节编号为 1 * block_size)
             // Q: 为什么不在最后 -1 呢
             // A: 读取的部分为 [strat, end), 每次读取的字节数为 end_current_block - start
-            let mut end_current_block = (start / BLOCK_SIZE + 1) * BLOCK_SIZE;
+            let mut end_current_block = (start / block_size + 1) * block_size;
             end_current_block = end_current_block.min(end);
 
             // 读取当前块的内容
@@ -459,18 +686,27 @@ impl DiskInode {
             let block_read_size = end_current_block - start;
             // dst 作为缓冲区 buf 的一个切片, 可用于修改 buf 中的内容
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                // start_block 维护着目前是文件内部第多少个数据块,
-                // 需要首先调用 get_block_id 从索引中查到这个数据块在块设备中的块编号,
-                // 随后才能传入 get_block_cache 中将正确的数据块缓存到内存中进行访问
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_blocks: &DataBlock| {
-                let src = &data_blocks[start % BLOCK_SIZE..start % BLOCK_SIZE + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            // start_block 维护着目前是文件内部第多少个数据块,
+            // 需要首先调用 get_block_id 从索引中查到这个数据块在块设备中的块编号,
+            // 随后才能传入 get_block_cache 中将正确的数据块缓存到内存中进行访问
+            let block_id = self.get_block_id(start_block as u32, block_size, block_device)?;
+            if block_id == 0 {
+                // 空洞读出来全为 0
+                dst.fill(0);
+            } else {
+                get_block_cache(
+                    block_id as usize,
+                    block_size,
+                    self.content_kind(),
+                    Arc::clone(block_device),
+                )?
+                .lock()
+                .read_slice(|data_blocks: &DataBlock| {
+                    let src =
+                        &data_blocks[start % block_size..start % block_size + block_read_size];
+                    dst.copy_from_slice(src);
+                });
+            }
 
             read_size += block_read_size;
 
@@ -481,46 +717,56 @@ impl DiskInode {
             start_block += 1;
             start = end_current_block;
         }
-        read_size
+        Ok(read_size)
     }
 
     /// 将数据写入当前磁盘 inode
     /// 只要 Inode 管理的数据块的大小足够, 传入的整个缓冲区的数据都必定会被写入到文件中.
     /// 注意, 当从 offset 开始的区间超出了文件范围的时候, 需要调用者在调用 write_at 之前提前调用 increase_size.
+    ///
+    /// 写入到空洞上时, 会通过 alloc 向磁盘块管理器申请新的数据块 (以及缺失的索引块)
     pub fn write_at(
         &mut self,
         offset: usize,
         buf: &[u8],
+        block_size: usize,
         block_device: &Arc<dyn BlockDevice>,
-    ) -> usize {
+        alloc: &mut impl FnMut() -> FsResult<u32>,
+    ) -> FsResult<usize> {
         // 从 offset 开始读取内容
         let mut start = offset;
         // 取最小值
         // use alloc_size rather than size
         let end = (offset + buf.len()).min(self.alloc_size as usize);
-        assert!(start <= end);
+        if start >= end {
+            // 不能为空的写入分配数据块
+            return Ok(0);
+        }
         // 目前是文件内部第多少个数据块
-        let mut start_block = start / BLOCK_SIZE as usize;
+        let mut start_block = start / block_size;
         let mut write_size = 0usize;
 
         loop {
             // 计算当前块的终止位置
-            let mut end_current_block = (start / BLOCK_SIZE + 1) * BLOCK_SIZE;
+            let mut end_current_block = (start / block_size + 1) * block_size;
             end_current_block = end_current_block.min(end);
             let block_write_size = end_current_block - start;
 
             get_block_cache(
                 // start_block 维护着目前是文件内部第多少个数据块,
-                // 需要首先调用 get_block_id 从索引中查到这个数据块在块设备中的块编号,
+                // 需要首先从索引中查到 (必要时分配) 这个数据块在块设备中的块编号,
                 // 随后才能传入 get_block_cache 中将正确的数据块缓存到内存中进行访问
-                self.get_block_id(start_block as u32, block_device) as usize,
+                self.get_or_alloc_block_id(start_block as u32, block_size, block_device, alloc)?
+                    as usize,
+                block_size,
+                self.content_kind(),
                 Arc::clone(block_device),
-            )
+            )?
             .lock()
-            .modify(0, |data_blocks: &mut DataBlock| {
+            .modify_slice(|data_blocks: &mut DataBlock| {
                 let src = &buf[write_size..write_size + block_write_size];
                 let dst =
-                    &mut data_blocks[start % BLOCK_SIZE..start % BLOCK_SIZE + block_write_size];
+                    &mut data_blocks[start % block_size..start % block_size + block_write_size];
                 dst.copy_from_slice(src);
             });
 
@@ -548,7 +794,7 @@ impl DiskInode {
         //
         // 另外, 在 write 之前会调用 increase_size 不必担心 size 不对
         // self.size = end as u32; // 更新文件大小
-        write_size
+        Ok(write_size)
     }
 }
 
@@ -561,7 +807,7 @@ impl DiskInode {
 #[repr(C)]
 /// 目录项
 ///
-/// 它自身占据空间 32 字节, 每个数据块可以存储 16 个目录项
+/// 它自身占据空间 32 字节, 每个 512 字节的目录块可以存储 15 个目录项 (最后一个位置留给校验和)
 pub struct DirEntry {
     /// 目录项 Dirent 最大允许保存长度为 27 的文件/目录名 (数组 name 中最末的一个字节留给 '\0')
     name: [u8; NAME_LENGTH_LIMIT + 1], // 28B
@@ -593,20 +839,20 @@ impl DirEntry {
     /// 序列化目录项
     pub fn as_bytes(&self) -> &[u8] {
         unsafe {
-            std::slice::from_raw_parts(self as *const Self as usize as *const u8, DIRENT_SIZE)
+            core::slice::from_raw_parts(self as *const Self as usize as *const u8, DIRENT_SIZE)
         }
     }
 
     /// 序列化目录项
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         unsafe {
-            std::slice::from_raw_parts_mut(self as *mut Self as usize as *mut u8, DIRENT_SIZE)
+            core::slice::from_raw_parts_mut(self as *mut Self as usize as *mut u8, DIRENT_SIZE)
         }
     }
 
     pub fn name(&self) -> &str {
         let len = (0usize..).find(|&i| self.name[i] == 0).unwrap(); // 找到第一个 0
-        std::str::from_utf8(&self.name[..len]).unwrap()
+        core::str::from_utf8(&self.name[..len]).unwrap()
     }
 
     pub fn chname(&mut self, name: &str) {
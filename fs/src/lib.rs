@@ -3,41 +3,111 @@
 mod bitmap;
 mod block_cache;
 mod block_dev;
+mod checksum;
+mod error;
+#[cfg(any(test, feature = "testing"))]
+mod faulty;
 mod fs;
 mod layout;
+#[cfg(feature = "ram-disk")]
+mod ram_disk;
+mod tar;
 mod vfs;
+mod xattr;
 
+extern crate alloc;
 extern crate log;
 
-/// Use a block size of 512 bytes
-pub const BLOCK_SIZE: usize = 512;
+/// BlockDevice 以 512 字节的扇区为单位进行读写
+pub const SECTOR_SIZE: usize = 512;
+/// Use a block size of 512 bytes by default
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+/// 文件系统的块大小在创建时指定, 可以是 512, 1K, 2K 或 4K, 并记录在超级块中
+pub const BLOCK_SIZES: [usize; 4] = [512, 1024, 2048, 4096];
 /// 为了避免在块缓存上浪费过多内存, 内存中同时只能驻留有限个磁盘块的缓冲区
 pub const BLOCK_CACHE_SIZE: usize = 16;
+/// 顺序读时最多预读的数据块数, 不能超过块缓存的容量, 否则预读的块会互相替换出去
+pub const READ_AHEAD_MAX: usize = BLOCK_CACHE_SIZE / 2;
 /// Magic number for sanity check
-pub const EAZY_FS_MAGIC: u32 = 0x3b800001;
+///
+/// DiskInode 的布局改变时 (例如增加了 xattr 字段) 递增, 以免旧的镜像被错误地解读
+pub const EAZY_FS_MAGIC: u32 = 0x3b800002;
 /// The max number of direct inodes
-pub const INODE_DIRECT_COUNT: usize = 27; // note: 可根据元数据情况修改
+///
+/// DiskInode 为 128 字节, 除去 size, alloc_size, 三个间接索引, xattr, type_ 和 checksum 之后剩下 24 个 u32
+pub const INODE_DIRECT_COUNT: usize = 24;
 /// The max length of inode name
 pub const NAME_LENGTH_LIMIT: usize = 27;
-/// The max number of indirect1 inodes
-pub const INODE_INDIRECT1_COUNT: usize = BLOCK_SIZE / 4;
-/// The max number of indirect2 inodes
-pub const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
 /// The upper bound of direct inode index
 pub const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
+
+/// 元数据校验和 (CRC32C) 的大小
+pub const CHECKSUM_SIZE: usize = 4;
+/// 扩展属性名的最大长度
+pub const XATTR_NAME_MAX: usize = 255;
+
+/// The max number of indirect1 inodes
+///
+/// 索引块的最后一个 u32 用来存放校验和
+pub const fn inode_indirect1_count(block_size: usize) -> usize {
+    block_size / 4 - 1
+}
+/// The max number of indirect2 inodes
+pub const fn inode_indirect2_count(block_size: usize) -> usize {
+    inode_indirect1_count(block_size) * inode_indirect1_count(block_size)
+}
+/// The max number of indirect3 inodes
+pub const fn inode_indirect3_count(block_size: usize) -> usize {
+    inode_indirect2_count(block_size) * inode_indirect1_count(block_size)
+}
 /// The upper bound of indirect1 inode index
-pub const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
+pub const fn indirect1_bound(block_size: usize) -> usize {
+    DIRECT_BOUND + inode_indirect1_count(block_size)
+}
 /// The upper bound of indirect2 inode index
-#[allow(unused)]
-pub const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
-/// 块的 bit 数量
-pub const BLOCK_BITS: usize = BLOCK_SIZE * 8;
+pub const fn indirect2_bound(block_size: usize) -> usize {
+    indirect1_bound(block_size) + inode_indirect2_count(block_size)
+}
+/// The upper bound of indirect3 inode index
+pub const fn indirect3_bound(block_size: usize) -> usize {
+    indirect2_bound(block_size) + inode_indirect3_count(block_size)
+}
+/// 文件的最大字节数: 受三级索引的容量和 DiskInode::size (u32) 两者限制
+pub const fn max_file_size(block_size: usize) -> usize {
+    let limit = indirect3_bound(block_size).saturating_mul(block_size);
+    if limit < u32::MAX as usize {
+        limit
+    } else {
+        u32::MAX as usize
+    }
+}
+/// 位图块中可用的 bit 数量
+///
+/// 位图块的最后一个 u64 被保留, 其中的后 4 个字节用来存放校验和
+pub const fn block_bits(block_size: usize) -> usize {
+    (block_size - 8) * 8
+}
+
 /// 目录项的大小
 pub const DIRENT_SIZE: usize = 32;
+/// 每个目录块能容纳的目录项数量, 最后一个目录项的位置留给校验和
+pub const fn dirents_per_block(block_size: usize) -> usize {
+    block_size / DIRENT_SIZE - 1
+}
 
-pub use bitmap::Bitmap;
-pub use block_cache::{block_cache_sync_all, get_block_cache};
-pub use block_dev::BlockDevice;
-pub use fs::FileSystem;
+pub use bitmap::{Bitmap, FreeExtents};
+pub use block_cache::{
+    block_cache_flush_expired, block_cache_format, block_cache_prefetch, block_cache_sync,
+    block_cache_sync_all, block_cache_sync_blocks, get_block_cache, BlockKind,
+};
+pub use block_dev::{BlockDevice, BlockError};
+pub use checksum::crc32c;
+pub use error::{FsError, FsResult};
+#[cfg(any(test, feature = "testing"))]
+pub use faulty::{Fault, FaultyDevice};
+pub use fs::{CacheMode, FileSystem, StatFs};
 pub use layout::*;
-pub use vfs::Inode;
+#[cfg(feature = "ram-disk")]
+pub use ram_disk::RamDisk;
+pub use tar::{export_tar, import_tar, ImportSummary, TarError, TarSink, TarSource};
+pub use vfs::{DiskUsage, Inode, InodeStat};
@@ -0,0 +1,70 @@
+//! 内存中的块设备
+//!
+//! [`RamDisk`] 用一段内存模拟磁盘, 不需要宿主机上的文件或真实的驱动,
+//! 可以用来在宿主机上测试 easy-fs, 也可以在内核中作为一个临时的文件系统使用.
+
+use alloc::{vec, vec::Vec};
+
+use spin::Mutex;
+
+use super::{BlockDevice, BlockError, SECTOR_SIZE};
+
+pub struct RamDisk {
+    /// 磁盘的全部内容, 长度为 SECTOR_SIZE 的整数倍
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// 创建一个有 sectors 个扇区, 内容全为 0 的磁盘
+    pub fn new(sectors: usize) -> Self {
+        Self {
+            data: Mutex::new(vec![0; sectors * SECTOR_SIZE]),
+        }
+    }
+
+    /// 磁盘的扇区数
+    pub fn sectors(&self) -> usize {
+        self.data.lock().len() / SECTOR_SIZE
+    }
+
+    /// 复制一份磁盘的全部内容, 例如用来保存成镜像文件
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.lock().clone()
+    }
+}
+
+impl From<Vec<u8>> for RamDisk {
+    /// 以 data 为内容创建磁盘, 不足一个扇区的部分补 0
+    fn from(mut data: Vec<u8>) -> Self {
+        data.resize(data.len().next_multiple_of(SECTOR_SIZE), 0);
+        Self {
+            data: Mutex::new(data),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        let data = self.data.lock();
+        let start = block_id * SECTOR_SIZE;
+        let sector = data
+            .get(start..start + buf.len())
+            .ok_or(BlockError::OutOfRange(block_id))?;
+        buf.copy_from_slice(sector);
+        Ok(())
+    }
+
+    /// read_block 本身就可以一次复制多个扇区
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        let mut data = self.data.lock();
+        let start = block_id * SECTOR_SIZE;
+        data.get_mut(start..start + buf.len())
+            .ok_or(BlockError::OutOfRange(block_id))?
+            .copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,555 @@
+//! tar 归档 (ustar 格式) 的导出和导入
+//!
+//! [`export_tar`] 将以某个 Inode 为根的子树写成 tar 归档, [`import_tar`] 将 tar 归档中的目录和文件恢复到某个目录下.
+//! 两者都是流式的: 文件内容分段读写, 不会把整个文件或者整个归档放进内存,
+//! 因此既可以被宿主机上的 efs 工具使用, 也可以被内核中的 tar 命令使用.
+//! 归档从哪里来, 写到哪里去, 由调用者实现 [`TarSource`] 和 [`TarSink`] 决定.
+//!
+//! easy-fs 没有权限, 时间和所有者: 导出时目录的权限为 0755, 文件为 0644, 时间和所有者均为 0, 导入时忽略这些字段.
+//! 扩展属性保存在 POSIX.1-2001 (pax) 扩展头的 `SCHILY.xattr.<name>` 记录中, 这也是 GNU tar 和 bsdtar 的写法;
+//! 放不进 ustar 头部的长路径同样通过 pax 扩展头的 `path` 记录保存, 导入时也接受 GNU tar 的长文件名 ('L').
+//! easy-fs 不支持链接, 导入时符号链接, 硬链接和设备文件等会被跳过并计数.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::fmt::{Display, Formatter};
+
+use super::{
+    xattr::{self, Xattr},
+    DiskInodeType, FsError, Inode, NAME_LENGTH_LIMIT,
+};
+
+/// tar 归档的记录单位, 头部和文件内容都按 512 字节对齐
+const TAR_BLOCK: usize = 512;
+/// 每次读写文件内容的字节数
+const CHUNK_SIZE: usize = 8 * TAR_BLOCK;
+/// pax 扩展头和 GNU 长文件名的大小上限, 超过时认为归档已经损坏
+const EXTENDED_HEADER_MAX: u64 = 64 * 1024;
+
+/// 归档数据的去向
+pub trait TarSink {
+    /// 写入全部的 data
+    fn write_all(&mut self, data: &[u8]) -> Result<(), TarError>;
+}
+
+/// 归档数据的来源
+pub trait TarSource {
+    /// 读取至多 buf.len() 个字节, 返回读到的字节数; 返回 0 表示归档已经结束
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, TarError>;
+}
+
+impl TarSink for Vec<u8> {
+    fn write_all(&mut self, data: &[u8]) -> Result<(), TarError> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+impl TarSource for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, TarError> {
+        let len = buf.len().min(self.len());
+        let (head, rest) = self.split_at(len);
+        buf[..len].copy_from_slice(head);
+        *self = rest;
+        Ok(len)
+    }
+}
+
+/// 导出或导入 tar 归档时的错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TarError {
+    /// 文件系统操作失败
+    Fs(FsError),
+    /// 读写归档的来源或去向失败
+    Io,
+    /// 归档格式错误: 头部校验和不匹配, 数字字段无法解析, 或者归档在中途结束
+    Malformed,
+    /// 无法导入或导出的路径: 含有 "..", 某一级的名字太长, 或者与已有的文件/目录类型冲突
+    BadPath(String),
+}
+
+impl Display for TarError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TarError::Fs(err) => write!(f, "{}", err),
+            TarError::Io => write!(f, "failed to read or write the archive"),
+            TarError::Malformed => write!(f, "malformed tar archive"),
+            TarError::BadPath(path) => write!(f, "{}: bad path", path),
+        }
+    }
+}
+
+impl From<FsError> for TarError {
+    fn from(err: FsError) -> Self {
+        TarError::Fs(err)
+    }
+}
+
+impl core::error::Error for TarError {}
+
+/// 导入的结果统计, see [`import_tar`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// 导入的文件数
+    pub files: usize,
+    /// 新建的目录数, 包括为了放置文件而自动创建的上级目录
+    pub dirs: usize,
+    /// 导入的文件内容的字节数
+    pub bytes: usize,
+    /// 跳过的条目数 (链接, 设备文件等)
+    pub skipped: usize,
+}
+
+// ustar 头部各字段的位置
+const NAME: core::ops::Range<usize> = 0..100;
+const MODE: core::ops::Range<usize> = 100..108;
+const UID: core::ops::Range<usize> = 108..116;
+const GID: core::ops::Range<usize> = 116..124;
+const SIZE: core::ops::Range<usize> = 124..136;
+const MTIME: core::ops::Range<usize> = 136..148;
+const CHKSUM: core::ops::Range<usize> = 148..156;
+const TYPEFLAG: usize = 156;
+const MAGIC: core::ops::Range<usize> = 257..263;
+const VERSION: core::ops::Range<usize> = 263..265;
+const PREFIX: core::ops::Range<usize> = 345..500;
+
+/// 将以 inode 为根的子树写成 tar 归档
+///
+/// inode 本身在归档中的路径为 path; path 为空时只导出目录 inode 下面的内容, 相当于 `tar -C dir .`.
+/// 导出期间其他线程对这棵树的修改不一定会体现在归档中, 但归档本身总是完整的.
+pub fn export_tar(inode: &Inode, path: &str, sink: &mut impl TarSink) -> Result<(), TarError> {
+    let path = path.trim_matches('/');
+    if path.is_empty() && !inode.is_dir()? {
+        return Err(TarError::BadPath(path.to_string()));
+    }
+    export_tree(inode, path, sink)?;
+    // 归档以两个全零的块结束
+    sink.write_all(&[0; 2 * TAR_BLOCK])
+}
+
+fn export_tree(inode: &Inode, path: &str, sink: &mut impl TarSink) -> Result<(), TarError> {
+    if inode.is_dir()? {
+        if !path.is_empty() {
+            write_header(sink, inode, &format!("{}/", path), b'5', 0)?;
+        }
+        for name in inode.ls()? {
+            // 列出目录之后这一项可能已经被删除了
+            let Some(child) = inode.find(&name)? else {
+                continue;
+            };
+            let child_path = match path {
+                "" => name,
+                _ => format!("{}/{}", path, name),
+            };
+            export_tree(&child, &child_path, sink)?;
+        }
+        return Ok(());
+    }
+
+    let size = inode.size()?;
+    write_header(sink, inode, path, b'0', size as u64)?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let want = CHUNK_SIZE.min(size - offset);
+        let mut len = inode.read(offset, &mut buf[..want])?;
+        if len == 0 {
+            // 文件在导出期间被截断了, 用 0 补齐头部中记录的大小
+            buf[..want].fill(0);
+            len = want;
+        }
+        sink.write_all(&buf[..len])?;
+        offset += len;
+    }
+    write_padding(sink, size as u64)
+}
+
+/// 写出一个条目的头部; 需要时在它之前写出一个 pax 扩展头, 保存长路径和扩展属性
+fn write_header(
+    sink: &mut impl TarSink,
+    inode: &Inode,
+    path: &str,
+    typeflag: u8,
+    size: u64,
+) -> Result<(), TarError> {
+    let split = split_path(path);
+    let mut pax = Vec::new();
+    if split.is_none() {
+        pax.extend(pax_record(b"path", path.as_bytes()));
+    }
+    for name in inode.list_xattr()? {
+        if let Some(value) = inode.get_xattr(&name)? {
+            pax.extend(pax_record(
+                format!("SCHILY.xattr.{}", name).as_bytes(),
+                &value,
+            ));
+        }
+    }
+    if !pax.is_empty() {
+        sink.write_all(&ustar_header(
+            b"././@PaxHeader",
+            b"",
+            b'x',
+            0o644,
+            pax.len() as u64,
+        ))?;
+        sink.write_all(&pax)?;
+        write_padding(sink, pax.len() as u64)?;
+    }
+    // 路径太长时 ustar 头部中只保留前 100 个字节, 完整的路径在 pax 扩展头中
+    let (prefix, name) = split.unwrap_or(("", &path[..floor_char_boundary(path, NAME.len())]));
+    let mode = if typeflag == b'5' { 0o755 } else { 0o644 };
+    sink.write_all(&ustar_header(
+        name.as_bytes(),
+        prefix.as_bytes(),
+        typeflag,
+        mode,
+        size,
+    ))
+}
+
+/// 将路径分为 ustar 头部的 prefix 和 name 两部分, 放不下时返回 None
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= NAME.len() {
+        return Some(("", path));
+    }
+    // 目录的路径以 '/' 结尾, 不能在最后这个 '/' 处分开
+    path.trim_end_matches('/')
+        .match_indices('/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= PREFIX.len() && name.len() <= NAME.len())
+}
+
+/// 不超过 len 的最大的字符边界
+fn floor_char_boundary(s: &str, len: usize) -> usize {
+    (0..=len.min(s.len()))
+        .rev()
+        .find(|&i| s.is_char_boundary(i))
+        .unwrap_or(0)
+}
+
+fn ustar_header(name: &[u8], prefix: &[u8], typeflag: u8, mode: u32, size: u64) -> [u8; TAR_BLOCK] {
+    let mut header = [0u8; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name);
+    write_octal(&mut header[MODE], mode as u64);
+    write_octal(&mut header[UID], 0);
+    write_octal(&mut header[GID], 0);
+    write_octal(&mut header[SIZE], size);
+    write_octal(&mut header[MTIME], 0);
+    header[TYPEFLAG] = typeflag;
+    header[MAGIC].copy_from_slice(b"ustar\0");
+    header[VERSION].copy_from_slice(b"00");
+    header[PREFIX.start..PREFIX.start + prefix.len()].copy_from_slice(prefix);
+    // 校验和按惯例写成 6 位八进制数, 后跟 NUL 和空格
+    let checksum = header_checksum(&header);
+    write_octal(&mut header[CHKSUM.start..CHKSUM.end - 1], checksum as u64);
+    header[CHKSUM.end - 1] = b' ';
+    header
+}
+
+/// 头部所有字节之和, 其中校验和字段按 8 个空格计算
+fn header_checksum(header: &[u8; TAR_BLOCK]) -> u32 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| if CHKSUM.contains(&i) { b' ' } else { byte } as u32)
+        .sum()
+}
+
+/// 以 NUL 结尾的八进制数, 占满整个字段
+fn write_octal(field: &mut [u8], mut value: u64) {
+    let digits = field.len() - 1;
+    for byte in field[..digits].iter_mut().rev() {
+        *byte = b'0' + (value & 7) as u8;
+        value >>= 3;
+    }
+    field[digits] = 0;
+}
+
+/// pax 扩展头中的一条记录: "<长度> <key>=<value>\n", 长度包括它自己的十进制位数
+fn pax_record(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let base = key.len() + value.len() + 3;
+    let mut len = base;
+    while base + decimal_digits(len) != len {
+        len = base + decimal_digits(len);
+    }
+    let mut record = format!("{} ", len).into_bytes();
+    record.extend_from_slice(key);
+    record.push(b'=');
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+fn decimal_digits(n: usize) -> usize {
+    n.checked_ilog10().unwrap_or(0) as usize + 1
+}
+
+fn padding(size: u64) -> usize {
+    (TAR_BLOCK - (size % TAR_BLOCK as u64) as usize) % TAR_BLOCK
+}
+
+fn write_padding(sink: &mut impl TarSink, size: u64) -> Result<(), TarError> {
+    sink.write_all(&[0; TAR_BLOCK][..padding(size)])
+}
+
+/// 将 tar 归档中的目录和文件导入到目录 dir 下
+///
+/// 已经存在的文件会被覆盖, 已经存在的目录会被合并; 路径中缺少的上级目录会被自动创建.
+/// 出错时已经导入的部分会保留下来.
+pub fn import_tar(
+    dir: &Arc<Inode>,
+    source: &mut impl TarSource,
+) -> Result<ImportSummary, TarError> {
+    let mut summary = ImportSummary::default();
+    // 由 pax 扩展头或者 GNU 长文件名给出的, 作用于下一个条目的路径和扩展属性
+    let mut long_path: Option<String> = None;
+    let mut xattrs: Vec<Xattr> = Vec::new();
+    let mut header = [0u8; TAR_BLOCK];
+    loop {
+        // 归档可以省略结束标记, 在两个条目之间结束
+        if !read_header(source, &mut header)? || header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        if parse_number(&header[CHKSUM])? != header_checksum(&header) as u64 {
+            return Err(TarError::Malformed);
+        }
+        let size = parse_number(&header[SIZE])?;
+        match header[TYPEFLAG] {
+            b'x' => {
+                parse_pax(&read_extended(source, size)?, &mut long_path, &mut xattrs)?;
+                continue;
+            }
+            b'L' => {
+                let name = read_extended(source, size)?;
+                let name = name.split(|&byte| byte == 0).next().unwrap_or_default();
+                long_path = Some(utf8_path(name)?);
+                continue;
+            }
+            _ => {}
+        }
+        let path = match long_path.take() {
+            Some(path) => path,
+            None => ustar_path(&header)?,
+        };
+        let entry_xattrs = core::mem::take(&mut xattrs);
+        let inode = match header[TYPEFLAG] {
+            b'0' | b'\0' | b'7' => {
+                let (parent, name) = match components(&path)?.split_last() {
+                    Some((&name, parents)) => (open_dir(dir, parents, &path, &mut summary)?, name),
+                    None => return Err(TarError::BadPath(path)),
+                };
+                let file = match parent.create(name, DiskInodeType::File)? {
+                    Some(file) => file,
+                    None => match parent.find(name)? {
+                        Some(file) if !file.is_dir()? => {
+                            file.clear()?;
+                            file
+                        }
+                        _ => return Err(TarError::BadPath(path)),
+                    },
+                };
+                copy_in(source, &file, size)?;
+                summary.files += 1;
+                summary.bytes += size as usize;
+                file
+            }
+            b'5' => {
+                skip(source, size)?;
+                open_dir(dir, &components(&path)?, &path, &mut summary)?
+            }
+            _ => {
+                skip(source, size)?;
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        for (name, value) in entry_xattrs {
+            // 名字不合法的属性无法保存, 直接忽略
+            if xattr::valid_name(&name) {
+                inode.set_xattr(&name, &value)?;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// 将文件内容从归档复制到 file 中, 并跳过之后的填充
+fn copy_in(source: &mut impl TarSource, file: &Inode, size: u64) -> Result<(), TarError> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while (offset as u64) < size {
+        let len = CHUNK_SIZE.min((size - offset as u64) as usize);
+        read_exact(source, &mut buf[..len])?;
+        file.write(offset, &buf[..len])?;
+        offset += len;
+    }
+    read_exact(source, &mut buf[..padding(size)])
+}
+
+/// 依次打开 (不存在时创建) dir 下路径为 names 的目录
+fn open_dir(
+    dir: &Arc<Inode>,
+    names: &[&str],
+    path: &str,
+    summary: &mut ImportSummary,
+) -> Result<Arc<Inode>, TarError> {
+    let mut dir = Arc::clone(dir);
+    for &name in names {
+        dir = match dir.create(name, DiskInodeType::Directory)? {
+            Some(child) => {
+                summary.dirs += 1;
+                child
+            }
+            None => match dir.find(name)? {
+                Some(child) if child.is_dir()? => child,
+                _ => return Err(TarError::BadPath(path.to_string())),
+            },
+        };
+    }
+    Ok(dir)
+}
+
+/// 将归档中的路径分为各级名字, 忽略开头的 "/" 和其中的 "."; 不允许 ".." 和过长的名字
+fn components(path: &str) -> Result<Vec<&str>, TarError> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .map(|name| match name {
+            ".." => Err(TarError::BadPath(path.to_string())),
+            _ if name.len() > NAME_LENGTH_LIMIT || name.contains('\0') => {
+                Err(TarError::BadPath(path.to_string()))
+            }
+            _ => Ok(name),
+        })
+        .collect()
+}
+
+/// ustar 头部中的路径: prefix 非空时为 prefix/name
+fn ustar_path(header: &[u8; TAR_BLOCK]) -> Result<String, TarError> {
+    let name = c_str(&header[NAME]);
+    let prefix = match &header[MAGIC] {
+        magic if magic.starts_with(b"ustar") => c_str(&header[PREFIX]),
+        _ => &[],
+    };
+    let mut path = Vec::with_capacity(prefix.len() + 1 + name.len());
+    if !prefix.is_empty() {
+        path.extend_from_slice(prefix);
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    utf8_path(&path)
+}
+
+fn utf8_path(path: &[u8]) -> Result<String, TarError> {
+    String::from_utf8(path.to_vec())
+        .map_err(|_| TarError::BadPath(String::from_utf8_lossy(path).into_owned()))
+}
+
+/// 字段中第一个 NUL 之前的部分
+fn c_str(field: &[u8]) -> &[u8] {
+    field.split(|&byte| byte == 0).next().unwrap_or_default()
+}
+
+/// 解析数字字段: 通常是以空格或 NUL 结尾的八进制数; 最高位为 1 时是 GNU 的 base-256 编码
+fn parse_number(field: &[u8]) -> Result<u64, TarError> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold((field[0] & 0x7f) as u64, |value, &byte| {
+                value << 8 | byte as u64
+            }));
+    }
+    field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != b' ' && byte != 0)
+        .try_fold(0u64, |value, &byte| match byte {
+            b'0'..=b'7' => Ok(value << 3 | (byte - b'0') as u64),
+            _ => Err(TarError::Malformed),
+        })
+}
+
+/// 解析 pax 扩展头中的记录, 只关心 path 和 SCHILY.xattr.*
+fn parse_pax(
+    mut data: &[u8],
+    path: &mut Option<String>,
+    xattrs: &mut Vec<Xattr>,
+) -> Result<(), TarError> {
+    while !data.is_empty() {
+        let space = data
+            .iter()
+            .position(|&byte| byte == b' ')
+            .ok_or(TarError::Malformed)?;
+        let len = core::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| len > space + 1 && len <= data.len() && data[len - 1] == b'\n')
+            .ok_or(TarError::Malformed)?;
+        let record = &data[space + 1..len - 1];
+        let eq = record
+            .iter()
+            .position(|&byte| byte == b'=')
+            .ok_or(TarError::Malformed)?;
+        let (key, value) = (&record[..eq], &record[eq + 1..]);
+        if key == b"path" {
+            *path = Some(utf8_path(value)?);
+        } else if let Some(name) = key.strip_prefix(b"SCHILY.xattr.") {
+            if let Ok(name) = core::str::from_utf8(name) {
+                xattrs.push((String::from(name), value.to_vec()));
+            }
+        }
+        data = &data[len..];
+    }
+    Ok(())
+}
+
+/// 读出 pax 扩展头或者 GNU 长文件名的内容, 以及之后的填充
+fn read_extended(source: &mut impl TarSource, size: u64) -> Result<Vec<u8>, TarError> {
+    if size > EXTENDED_HEADER_MAX {
+        return Err(TarError::Malformed);
+    }
+    let mut data = vec![0u8; size as usize + padding(size)];
+    read_exact(source, &mut data)?;
+    data.truncate(size as usize);
+    Ok(data)
+}
+
+/// 读取一个头部; 归档正好在这里结束时返回 false
+fn read_header(
+    source: &mut impl TarSource,
+    header: &mut [u8; TAR_BLOCK],
+) -> Result<bool, TarError> {
+    let len = source.read(header)?;
+    if len == 0 {
+        return Ok(false);
+    }
+    read_exact(source, &mut header[len..])?;
+    Ok(true)
+}
+
+fn read_exact(source: &mut impl TarSource, mut buf: &mut [u8]) -> Result<(), TarError> {
+    while !buf.is_empty() {
+        match source.read(buf)? {
+            0 => return Err(TarError::Malformed),
+            len => buf = &mut buf[len..],
+        }
+    }
+    Ok(())
+}
+
+/// 跳过 size 字节的内容及其填充
+fn skip(source: &mut impl TarSource, size: u64) -> Result<(), TarError> {
+    let mut rest = size + padding(size) as u64;
+    let mut buf = [0u8; TAR_BLOCK];
+    while rest > 0 {
+        let len = rest.min(TAR_BLOCK as u64) as usize;
+        read_exact(source, &mut buf[..len])?;
+        rest -= len as u64;
+    }
+    Ok(())
+}
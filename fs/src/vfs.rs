@@ -3,59 +3,220 @@
 //! 为此需要设计索引节点 [`Inode`] 暴露给文件系统的使用者, 让他们能够直接对文件和目录进行操作.
 //!
 //!  DiskInode 放在磁盘块中比较固定的位置, 而 Inode 是放在内存中的记录文件索引节点信息的数据结构
+//!
+//! 所有访问磁盘的操作都返回 FsResult: 元数据块校验失败时返回 FsError::Corrupted
+//!
+//! Inode 只能通过 [`FileSystem::get_inode`] 获得, 同一个文件被打开多次时共享同一个 Arc<Inode>.
+//! 被 [`Inode::unlink`] 删除的文件在最后一个 Arc<Inode> 被释放时才回收它的块和索引节点.
+//!
+//! 每个 Inode 记录最近一次读到的位置, 检测到顺序读时会预读后面的若干个数据块 (见 [`Inode::read`]),
+//! 由于同一个文件只有一个 Inode, 多个打开者交替读取同一个文件时会互相干扰, 此时只是少了预读而已.
 
-use std::sync::Arc;
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::fs::{DirEntry, DIRENT_SIZE};
+use crate::{
+    xattr::{self, Xattr},
+    DirEntry, DIRENT_SIZE,
+};
 
-use ::log::error;
+use ::log::{error, info, warn};
 
 use super::{
-    block_cache_sync_all, fs::FileSystem, get_block_cache, BlockDevice, DiskInode, DiskInodeType,
+    block_cache_format, block_cache_prefetch, block_cache_sync_blocks, fs::FileSystem,
+    get_block_cache, max_file_size, BlockDevice, BlockKind, DiskInode, DiskInodeType, FsError,
+    FsResult, READ_AHEAD_MAX,
 };
 
-use spin::{Mutex, MutexGuard};
+use spin::{Mutex, RwLock};
+
+/// 索引节点的元数据, see [`Inode::stat`]
+#[derive(Debug, Clone, Copy)]
+pub struct InodeStat {
+    /// 索引节点编号
+    pub inode_id: u32,
+    /// 文件还是目录
+    pub type_: DiskInodeType,
+    /// 文件/目录内容的字节数
+    pub size: usize,
+    /// 实际占用的数据块, 索引块和扩展属性块数目, 空洞不计入
+    pub blocks: usize,
+    /// 同时打开这个索引节点的 Inode 数目 (包括调用者自己)
+    pub open_count: usize,
+}
+
+/// 一棵目录树的空间使用情况, see [`Inode::du`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// 文件数
+    pub files: usize,
+    /// 目录数, 包括作为起点的目录本身
+    pub dirs: usize,
+    /// 所有文件内容的字节数之和
+    pub bytes: usize,
+    /// 实际占用的数据块, 索引块和扩展属性块数目, 包括目录自身的目录块
+    pub blocks: usize,
+}
+
+/// 顺序读检测: 一次读取从上一次读到的最后一个块 (或它的下一个块) 开始, 就认为是顺序读
+///
+/// 每次顺序读都将预读窗口加倍, 直到 READ_AHEAD_MAX; 随机读时窗口清零.
+/// 预读的块剩下不到半个窗口时, 再预读到当前位置之后一个窗口的位置
+#[derive(Default)]
+struct ReadAhead {
+    /// 上一次读到的最后一个块 (文件内部的块编号)
+    last: Option<u32>,
+    /// 预读窗口的块数, 0 表示没有检测到顺序读
+    window: u32,
+    /// 已经预读到 (不含) 这个块, 避免每次都重新预读同样的块
+    ahead: u32,
+}
+
+impl ReadAhead {
+    /// 记录一次读取了第 first..=last 个块, 返回接下来需要预读的块
+    fn access(&mut self, first: u32, last: u32) -> Range<u32> {
+        // 从文件开头读也看作顺序读的开始
+        let sequential = match self.last {
+            Some(prev) => first == prev || first == prev + 1,
+            None => first == 0,
+        };
+        self.last = Some(last);
+        if !sequential {
+            self.window = 0;
+            self.ahead = 0;
+            return 0..0;
+        }
+        self.window = (self.window * 2).clamp(2, READ_AHEAD_MAX as u32);
+        // 已经预读的块还剩一半以上时不再预读, 这样每次预读的都是连续的一批块, 而不是每次一个
+        if self.ahead > last + 1 + self.window / 2 {
+            return 0..0;
+        }
+        let start = self.ahead.max(last + 1);
+        let end = last + 1 + self.window;
+        self.ahead = end;
+        start..end
+    }
+}
 
 pub struct Inode {
+    /// 索引节点编号
+    inode_id: u32,
     /// 位于哪个盘块(Inode位于的磁盘块)
     block_id: usize,
     /// 盘块上的偏移
     block_offset: usize,
-    fs: Arc<Mutex<FileSystem>>,
+    /// 文件系统的块大小
+    block_size: usize,
+    /// 索引节点锁, 同一个索引节点在内存中只有一个 Inode, 因此也只有一把锁
+    ///
+    /// 读操作持有读锁, 修改文件内容, 大小或目录项的操作持有写锁
+    lock: RwLock<()>,
+    /// 是否已经从目录中删除; 如果是, 在最后一次关闭 (Drop) 时回收
+    unlinked: AtomicBool,
+    /// 顺序读检测和预读窗口
+    read_ahead: Mutex<ReadAhead>,
+    fs: Arc<FileSystem>,
     block_device: Arc<dyn BlockDevice>,
 }
 
 impl Inode {
-    pub fn new(
-        block_id: u32,
-        block_offset: usize,
-        fs: Arc<Mutex<FileSystem>>,
-        block_device: Arc<dyn BlockDevice>,
-    ) -> Self {
+    /// 只应由 FileSystem::get_inode 调用, 否则同一个索引节点会有多把锁
+    pub(crate) fn new(inode_id: u32, fs: Arc<FileSystem>) -> Self {
+        // 现在 FileSystem 没有大锁了, 可以直接向它查询 inode 在块设备中的位置
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
         Self {
+            inode_id,
             block_id: block_id as usize,
             block_offset,
+            block_size: fs.block_size,
+            lock: RwLock::new(()),
+            unlinked: AtomicBool::new(false),
+            read_ahead: Mutex::new(ReadAhead::default()),
+            block_device: Arc::clone(&fs.block_device),
             fs,
-            block_device,
         }
     }
 
+    pub fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+
     // 仿照 BlockCache::read/modify ,
     // 我们可以设计两个方法来简化对于 Inode 对应的磁盘上的 DiskInode 的访问流程,
     // 而不是每次都需要 get_block_cache.lock.read/modify
 
     /// 在磁盘 inode 上调用一个函数来读取它
-    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
-        get_block_cache(self.block_id, Arc::clone(&self.block_device))
-            .lock()
-            .read(self.block_offset, f)
+    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> FsResult<V> {
+        Ok(get_block_cache(
+            self.block_id,
+            self.block_size,
+            BlockKind::Inode,
+            Arc::clone(&self.block_device),
+        )?
+        .lock()
+        .read(self.block_offset, f))
     }
 
     /// 在磁盘 inode 上调用一个函数来修改它
-    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
-        get_block_cache(self.block_id, Arc::clone(&self.block_device))
-            .lock()
-            .modify(self.block_offset, f)
+    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> FsResult<V> {
+        Ok(get_block_cache(
+            self.block_id,
+            self.block_size,
+            BlockKind::Inode,
+            Arc::clone(&self.block_device),
+        )?
+        .lock()
+        .modify(self.block_offset, f))
+    }
+
+    /// 将磁盘 inode 复制一份出来交给 f 读取或修改, 最后再写回去.
+    ///
+    /// 一个索引节点区域的块上有多个 DiskInode, 如果在整个读写过程中都持有这个块的锁,
+    /// 同一个块上的其他文件也会被阻塞. 调用者持有本索引节点的锁, 因此复制出来的 DiskInode 不会过时.
+    /// 即使 f 返回错误, 也会写回已经做出的修改 (例如已经分配的块), 以免泄漏.
+    fn update_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> FsResult<V>) -> FsResult<V> {
+        let mut disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        let ret = f(&mut disk_inode);
+        self.modify_disk_inode(|d| *d = disk_inode)?;
+        ret
+    }
+
+    /// 读出目录 disk_inode 中的第 index 个目录项
+    ///
+    /// 读到的不是完整的目录项说明目录的大小已经损坏, 返回 FsError::Corrupted (索引节点所在的块)
+    fn read_dir_entry(&self, disk_inode: &DiskInode, index: usize) -> FsResult<DirEntry> {
+        let mut dir_entry = DirEntry::create_empty();
+        let read = disk_inode.read_at(
+            DiskInode::dirent_offset(index, self.block_size),
+            dir_entry.as_bytes_mut(),
+            self.block_size,
+            &self.block_device,
+        )?;
+        if read != DIRENT_SIZE {
+            return Err(FsError::Corrupted(self.block_id));
+        }
+        Ok(dir_entry)
+    }
+
+    /// 将目录项写到目录 disk_inode 的第 index 个位置, 调用者需要保证目录的大小足够
+    fn write_dir_entry(
+        &self,
+        disk_inode: &mut DiskInode,
+        index: usize,
+        dir_entry: &DirEntry,
+    ) -> FsResult<()> {
+        let written = disk_inode.write_at(
+            DiskInode::dirent_offset(index, self.block_size),
+            dir_entry.as_bytes(),
+            self.block_size,
+            &self.block_device,
+            &mut || self.fs.alloc_data(),
+        )?;
+        if written != DIRENT_SIZE {
+            return Err(FsError::Corrupted(self.block_id));
+        }
+        Ok(())
     }
 
     // 文件索引
@@ -68,339 +229,718 @@ impl Inode {
     // FEAT: 现在支持目录了
 
     /// 根据名称查找磁盘 inode 下的 inode
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
+    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> FsResult<Option<u32>> {
+        Ok(self
+            .dir_entry_pos(name, disk_inode)?
+            .map(|(_, dir_entry)| dir_entry.inode_id()))
+    }
+
+    /// 在目录中查找名为 name 的目录项, 返回它的位置和内容
+    fn dir_entry_pos(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+    ) -> FsResult<Option<(usize, DirEntry)>> {
         assert!(disk_inode.is_dir()); // 一定是目录
-        let file_count = (disk_inode.size as usize) / DIRENT_SIZE;
-        let mut dir_entry = DirEntry::create_empty();
+        let file_count = disk_inode.dirent_count(self.block_size);
         for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(
-                    DIRENT_SIZE * i,
-                    dir_entry.as_bytes_mut(),
-                    &self.block_device,
-                ),
-                DIRENT_SIZE,
-            ); // 读取目录项
+            // 读取目录项
+            let dir_entry = self.read_dir_entry(disk_inode, i)?;
 
             // 将目录内容中的所有目录项都读到内存进行逐个比对
             // 如果能够找到, 则 find 方法会根据查到 inode 编号, 对应生成一个 Inode 用于后续对文件的访问
             if dir_entry.name() == name {
-                return Some(dir_entry.inode_id() as u32);
+                return Ok(Some((i, dir_entry)));
             }
         }
-        None
+        Ok(None)
     }
 
-    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
-        let fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            // 通过偏移 获取一个 disk_inode; 通过 get_ref(offset) 获取
-            // 它首先调用 find_inode_id 方法
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
-        })
+    pub fn find(&self, name: &str) -> FsResult<Option<Arc<Inode>>> {
+        let _guard = self.lock.read();
+        let disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        // 通过偏移 获取一个 disk_inode; 通过 get_ref(offset) 获取
+        // 它首先调用 find_inode_id 方法
+        Ok(self
+            .find_inode_id(name, &disk_inode)?
+            .map(|inode_id| self.fs.get_inode(inode_id)))
     }
 
-    pub fn is_dir(&self) -> bool {
-        let _fs = self.fs.lock();
+    pub fn is_dir(&self) -> FsResult<bool> {
         self.read_disk_inode(|disk_inode| disk_inode.is_dir())
     }
 
-    pub fn size(&self) -> usize {
-        let _fs = self.fs.lock();
+    pub fn size(&self) -> FsResult<usize> {
+        let _guard = self.lock.read();
         self.read_disk_inode(|disk_inode| disk_inode.size as usize)
     }
 
+    /// 获取索引节点的元数据
+    pub fn stat(&self) -> FsResult<InodeStat> {
+        let _guard = self.lock.read();
+        let disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        Ok(InodeStat {
+            inode_id: self.inode_id,
+            type_: disk_inode.type_,
+            size: disk_inode.size as usize,
+            blocks: disk_inode.allocated_blocks(self.block_size, &self.block_device)? as usize,
+            open_count: self.fs.open_count(self.inode_id),
+        })
+    }
+
+    /// 统计文件的数据块在磁盘上被分成了多少段连续的区间, 见 [`DiskInode::extents`]
+    pub fn extents(&self) -> FsResult<usize> {
+        let _guard = self.lock.read();
+        let disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        Ok(disk_inode.extents(self.block_size, &self.block_device)? as usize)
+    }
+
+    /// 递归地统计以本索引节点为根的目录树的空间使用情况, 对于文件只统计它自己
+    ///
+    /// 每次只持有一个索引节点的锁, 因此统计期间发生的修改不一定被计入
+    pub fn du(&self) -> FsResult<DiskUsage> {
+        let stat = self.stat()?;
+        let mut usage = DiskUsage {
+            blocks: stat.blocks,
+            ..DiskUsage::default()
+        };
+        if stat.type_ == DiskInodeType::File {
+            usage.files = 1;
+            usage.bytes = stat.size;
+            return Ok(usage);
+        }
+        usage.dirs = 1;
+        let children: Vec<u32> = {
+            let _guard = self.lock.read();
+            let disk_inode = self.read_disk_inode(DiskInode::clone)?;
+            (0..disk_inode.dirent_count(self.block_size))
+                .map(|i| Ok(self.read_dir_entry(&disk_inode, i)?.inode_id()))
+                .collect::<FsResult<_>>()?
+        };
+        for inode_id in children {
+            let child = self.fs.get_inode(inode_id).du()?;
+            usage.files += child.files;
+            usage.dirs += child.dirs;
+            usage.bytes += child.bytes;
+            usage.blocks += child.blocks;
+        }
+        Ok(usage)
+    }
+
     pub fn inode_info(&self) -> (usize, usize) {
-        let _fs = self.fs.lock();
         (self.block_id, self.block_offset)
     }
 
     // 包括 find 在内, 所有暴露给文件系统的使用者的文件系统操作(还包括接下来将要介绍的几种),
-    // 全程均需持有 EasyFileSystem 的互斥锁
-    // (相对而言, 文件系统内部的操作, 如之前的 Inode::new 或是上面的 find_inode_id ,
-    // 都是假定在已持有 efs 锁的情况下才被调用的, 因此它们不应尝试获取锁).
-    // 这能够保证在多核情况下, 同时最多只能有一个核在进行文件系统相关操作.
+    // 全程均需持有所操作的索引节点的锁 (只读的操作持有读锁, 其余的持有写锁)
+    // (相对而言, 文件系统内部的操作, 如之前的 find_inode_id 或是 read_dir_entry ,
+    // 都是假定在已持有索引节点锁的情况下才被调用的, 因此它们不应尝试获取锁, 锁是不可重入的).
+    // 这能够保证在多核情况下, 不同的文件可以被同时访问, 而同一个文件的修改是互斥的.
 
     // 文件列举
     // ls 方法可以收集目录下的所有文件的文件名并以向量的形式返回,
-    pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SIZE;
-            let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dir_entry = DirEntry::create_empty();
-                assert_eq!(
-                    disk_inode.read_at(
-                        DIRENT_SIZE * i,
-                        dir_entry.as_bytes_mut(),
-                        &self.block_device,
-                    ),
-                    DIRENT_SIZE,
-                );
-                v.push(String::from(dir_entry.name()));
-            }
-            v
-        })
+    pub fn ls(&self) -> FsResult<Vec<String>> {
+        let _guard = self.lock.read();
+        let disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        let file_count = disk_inode.dirent_count(self.block_size);
+        let mut v: Vec<String> = Vec::new();
+        for i in 0..file_count {
+            let dir_entry = self.read_dir_entry(&disk_inode, i)?;
+            v.push(String::from(dir_entry.name()));
+        }
+        Ok(v)
     }
 
     // 文件创建
     // create 方法可以在目录下创建一个文件
     // 返回 文件的 Inode
-    pub fn create(&self, name: &str, kind: DiskInodeType) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
-        if self
-            .modify_disk_inode(|disk_inode| {
-                assert!(disk_inode.is_dir());
-                self.find_inode_id(name, disk_inode)
-            })
-            .is_some()
-        // 如果已经存在, 则返回 None
-        {
-            println!("file {} already exists", name);
-            return None;
+    //
+    // 本目录已经被删除 (只是还有人打开着它) 时也返回 None, 否则新文件在本目录被回收之后就再也找不到了
+    pub fn create(&self, name: &str, kind: DiskInodeType) -> FsResult<Option<Arc<Inode>>> {
+        // 持有目录的写锁, 同一目录下并发的 create 和删除会被串行化;
+        // unlink 本目录时也会持有它的写锁, 因此这里看到的 unlinked 不会在创建的过程中改变
+        let _guard = self.lock.write();
+        if self.unlinked.load(Ordering::Acquire) {
+            warn!("create {} in an unlinked directory", name);
+            return Ok(None);
         }
+        self.update_disk_inode(|disk_inode| {
+            assert!(disk_inode.is_dir());
+            // 如果已经存在, 则返回 None
+            if self.find_inode_id(name, disk_inode)?.is_some() {
+                warn!("file {} already exists", name);
+                return Ok(None);
+            }
 
-        // 为新文件分配一个 inode 编号
-        let new_inode_id = fs.alloc_inode();
-        let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
-
-        get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
+            // 为新文件分配一个 inode 编号
+            let new_inode_id = self.fs.alloc_inode()?;
+            let (new_inode_block_id, new_inode_block_offset) =
+                self.fs.get_disk_inode_pos(new_inode_id);
+
+            get_block_cache(
+                new_inode_block_id as usize,
+                self.block_size,
+                BlockKind::Inode,
+                Arc::clone(&self.block_device),
+            )?
             .lock()
             .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                if kind == DiskInodeType::File {
-                    new_inode.initialize(DiskInodeType::File);
-                } else {
-                    new_inode.initialize(DiskInodeType::Directory);
-                }
+                new_inode.initialize(kind);
             });
 
-        // 将待创建文件的目录项插入到目录的内容中, 使得之后可以索引到
-        self.modify_disk_inode(|disk_inode| {
+            // 将待创建文件的目录项插入到目录的内容中, 使得之后可以索引到
             // 在目录中添加一个目录项
-            let file_count = (disk_inode.size as usize) / DIRENT_SIZE;
-            let new_size = (file_count + 1) * DIRENT_SIZE;
+            let file_count = disk_inode.dirent_count(self.block_size);
+            let new_size = DiskInode::dirent_offset(file_count + 1, self.block_size);
             // 增加目录的大小
-            self.increase_size(new_size as u32, disk_inode, &mut fs);
-            // 在目录的最后添加一个目录项
-            let dir_entry = DirEntry::new(name, new_inode_id as u32);
-            disk_inode.write_at(
-                // 在此处开始写一个目录项,  大小为 DIRENT_SIZE,  最后root_inode的大小为 new_size
-                file_count * DIRENT_SIZE,
-                dir_entry.as_bytes(),
-                &self.block_device,
-            );
-        });
-
-        // Q: 这与上面的 new_inode_block_id, new_inode_block_offset 有什么区别?
-        // let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-
-        block_cache_sync_all();
-
-        Some(Arc::new(Self::new(
-            new_inode_block_id,
-            new_inode_block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
-    }
-
-    fn increase_size(
-        &self,
-        new_size: u32,
-        disk_inode: &mut DiskInode,
-        fs: &mut MutexGuard<FileSystem>,
-    ) {
-        if new_size < disk_inode.alloc_size {
-            // fix: bug
-            // 某种操作后(可能为 删除文件夹下一个有数据的文件)无法创建文件
-            disk_inode.size = new_size;
-            return;
-        }
-
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
-        }
-        disk_inode.increase_size(new_size, v, &self.block_device);
+            disk_inode.increase_size(new_size as u32, self.block_size);
+            // 在目录的最后添加一个目录项,  最后root_inode的大小为 new_size
+            let dir_entry = DirEntry::new(name, new_inode_id);
+            self.write_dir_entry(disk_inode, file_count, &dir_entry)?;
+
+            Ok(Some(new_inode_id))
+        })?
+        .map(|new_inode_id| {
+            self.fs.write_through()?;
+            Ok(self.fs.get_inode(new_inode_id))
+        })
+        .transpose()
     }
 
     // 文件删除
     // 在以某些标志位打开文件(例如带有 CREATE 标志打开一个已经存在的文件)的时候, 需要首先将文件清空.
     // 在索引到文件的 Inode 之后, 可以调用 clear 方法
     // 将该文件占据的索引块和数据块回收
-    pub fn clear(&self) {
-        let mut fs = self.fs.lock();
-        self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.alloc_size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
+    pub fn clear(&self) -> FsResult<()> {
+        let _guard = self.lock.write();
+        self.update_disk_inode(|disk_inode| {
+            // 文件可能含有空洞, 回收的块数不一定等于 total_blocks(size)
+            let data_blocks_dealloc = disk_inode.clear_size(self.block_size, &self.block_device)?;
 
             for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
+                self.fs.dealloc_data(data_block)?;
             }
-        });
+            Ok(())
+        })?;
 
-        block_cache_sync_all();
+        self.fs.write_through()?;
+        Ok(())
     }
 
-    /// 删除目录项
+    /// 从目录 disk_inode 中删除名为 file_name 的目录项, 返回被删除的目录项
     //
     // 类似删除顺序表的某个元素
     // 这个方法感觉不是很好 时间复杂度O(n) 空间复杂度O(n)
-    pub fn rm_dir_entry(&self, file_name: &str, parent_inode: Arc<Inode>) {
-        let _fs = self.fs.lock();
-
+    fn remove_dir_entry(
+        &self,
+        file_name: &str,
+        disk_inode: &mut DiskInode,
+    ) -> FsResult<Option<DirEntry>> {
         // 找到dir_entry_pos
-        let pos = parent_inode.dir_entry_pos(file_name); // 提前找到位置, 防止拿不到锁
-        if pos.is_none() {
-            println!("rm_dir_entry: file not found");
-            return;
+        let (pos, removed) = match self.dir_entry_pos(file_name, disk_inode)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let file_count = disk_inode.dirent_count(self.block_size);
+        let new_size = DiskInode::dirent_offset(file_count - 1, self.block_size);
+
+        // 从pos开始, 将后面的dir_entry往前移动
+        let mut dir_entry_list: Vec<DirEntry> = Vec::new();
+
+        // 为什么不合并: 读写冲突
+        // fix:
+        for i in (pos + 1)..file_count {
+            dir_entry_list.push(self.read_dir_entry(disk_inode, i)?);
+        }
+
+        for i in pos..(file_count - 1) {
+            let dir_entry = dir_entry_list.remove(0);
+            self.write_dir_entry(disk_inode, i, &dir_entry)?;
         }
-        let pos = pos.unwrap();
-        parent_inode.modify_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SIZE;
-            let new_size = (file_count - 1) * DIRENT_SIZE;
-
-            // 从pos开始, 将后面的dir_entry往前移动
-            let mut dir_entry_list: Vec<DirEntry> = Vec::new();
-
-            // 为什么不合并: 读写冲突
-            // fix:
-            for i in (pos + 1)..file_count {
-                let mut dir_entry = DirEntry::create_empty();
-                assert_eq!(
-                    disk_inode.read_at(
-                        i * DIRENT_SIZE,
-                        dir_entry.as_bytes_mut(),
-                        &self.block_device,
-                    ),
-                    DIRENT_SIZE,
-                );
-                dir_entry_list.push(dir_entry);
+
+        // 将最后一个dir_entry清空
+        let dir_entry = DirEntry::create_empty();
+        self.write_dir_entry(disk_inode, file_count - 1, &dir_entry)?;
+
+        // 修改size (ps: 可以去看看 layout::write 处提到的 bug-fix)
+        disk_inode.size = new_size as u32;
+        Ok(Some(removed))
+    }
+
+    /// 删除目录项
+    ///
+    /// 只删除目录项, 不回收文件本身; 要删除文件请使用 unlink
+    pub fn rm_dir_entry(&self, file_name: &str, parent_inode: Arc<Inode>) -> FsResult<()> {
+        let _guard = parent_inode.lock.write();
+
+        parent_inode.update_disk_inode(|disk_inode| {
+            if parent_inode
+                .remove_dir_entry(file_name, disk_inode)?
+                .is_none()
+            {
+                warn!("rm_dir_entry: file not found");
             }
+            Ok(())
+        })?;
+
+        self.fs.write_through()?;
+        Ok(())
+    }
 
-            for i in pos..(file_count - 1) {
-                let dir_entry = dir_entry_list.remove(0);
-                assert_eq!(
-                    disk_inode.write_at(i * DIRENT_SIZE, dir_entry.as_bytes(), &self.block_device),
-                    DIRENT_SIZE,
-                );
+    /// 从本目录中删除文件 name
+    ///
+    /// 目录项会立即被删除, 但文件的块和索引节点要等到它的最后一个 Arc<Inode> 被释放时才回收,
+    /// 因此已经打开这个文件的人仍然可以继续读写它.
+    /// 文件不存在或者是非空目录时返回 false.
+    pub fn unlink(&self, name: &str) -> FsResult<bool> {
+        // 先锁目录再锁文件: 持有文件的写锁, 检查目录是否为空之后不会有人再在其中 create
+        let _guard = self.lock.write();
+        let mut disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        let inode = match self.find_inode_id(name, &disk_inode)? {
+            Some(inode_id) => self.fs.get_inode(inode_id),
+            None => {
+                warn!("unlink: file {} not found", name);
+                return Ok(false);
             }
+        };
+        let child_guard = inode.lock.write();
+        let not_empty = inode
+            .read_disk_inode(|child| child.is_dir() && child.dirent_count(self.block_size) > 0)?;
+        if not_empty {
+            warn!("unlink: directory {} is not empty", name);
+            return Ok(false);
+        }
 
-            // 将最后一个dir_entry清空
-            let dir_entry = DirEntry::create_empty();
-            disk_inode.write_at(
-                (file_count - 1) * DIRENT_SIZE,
-                dir_entry.as_bytes(),
-                &self.block_device,
-            );
+        self.remove_dir_entry(name, &mut disk_inode)?;
+        self.modify_disk_inode(|d| *d = disk_inode)?;
+        self.fs.write_through()?;
 
-            // 修改size (ps: 可以去看看 layout::write 处提到的 bug-fix)
-            disk_inode.size = new_size as u32;
-        });
+        // 如果没有其他人打开这个文件, 释放 inode 时就会在这里回收它
+        inode.unlinked.store(true, Ordering::Release);
+        drop(child_guard);
+        drop(inode);
+        Ok(true)
+    }
 
-        block_cache_sync_all();
-    }
-
-    fn dir_entry_pos(&self, file_name: &str) -> Option<usize> {
-        self.read_disk_inode(|disk_inode| -> Option<usize> {
-            let file_count = (disk_inode.size as usize) / DIRENT_SIZE;
-            for i in 0..file_count {
-                let mut dir_entry = DirEntry::create_empty();
-                assert_eq!(
-                    disk_inode.read_at(
-                        i * DIRENT_SIZE,
-                        dir_entry.as_bytes_mut(),
-                        &self.block_device
-                    ),
-                    DIRENT_SIZE
-                );
-                if dir_entry.name() == file_name {
-                    return Some(i);
+    /// 将本目录中的 old_name 移动到目录 new_parent 中, 并改名为 new_name
+    ///
+    /// new_parent 可以就是本目录. old_name 不存在, new_name 已经存在或者 new_parent 已经被删除时返回 false.
+    /// 调用者需要保证不会把一个目录移动到它自己的子目录中.
+    pub fn rename(&self, old_name: &str, new_parent: &Inode, new_name: &str) -> FsResult<bool> {
+        if core::ptr::eq(self, new_parent) {
+            let _guard = self.lock.write();
+            let renamed = self.update_disk_inode(|disk_inode| {
+                if self.find_inode_id(new_name, disk_inode)?.is_some() {
+                    return Ok(false);
                 }
+                match self.dir_entry_pos(old_name, disk_inode)? {
+                    Some((i, mut dir_entry)) => {
+                        dir_entry.chname(new_name);
+                        self.write_dir_entry(disk_inode, i, &dir_entry)?;
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            })?;
+            self.fs.write_through()?;
+            return Ok(renamed);
+        }
+
+        // 两个目录可能是父子关系, 而 unlink 按照先父后子的顺序加锁, 因此这里不能阻塞地等待第二把锁:
+        // 按照索引节点编号从小到大加锁, 拿不到第二把锁时放开第一把锁重试
+        let (first, second) = if self.inode_id < new_parent.inode_id {
+            (self, new_parent)
+        } else {
+            (new_parent, self)
+        };
+        let (_first, _second) = loop {
+            let first_guard = first.lock.write();
+            if let Some(second_guard) = second.lock.try_write() {
+                break (first_guard, second_guard);
             }
-            None
-        })
+            drop(first_guard);
+            core::hint::spin_loop();
+        };
+        // 和 create 一样, 不能把文件移动到已经被删除的目录中
+        if new_parent.unlinked.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+        let mut old_dir = self.read_disk_inode(DiskInode::clone)?;
+        let mut new_dir = new_parent.read_disk_inode(DiskInode::clone)?;
+        let inode_id = match self.find_inode_id(old_name, &old_dir)? {
+            Some(inode_id) => inode_id,
+            None => return Ok(false),
+        };
+        if new_parent.find_inode_id(new_name, &new_dir)?.is_some() {
+            return Ok(false);
+        }
+
+        // 先在新目录中添加目录项, 再从旧目录中删除, 中途出错时文件最多多出一个名字而不会丢失
+        let file_count = new_dir.dirent_count(self.block_size);
+        new_dir.increase_size(
+            DiskInode::dirent_offset(file_count + 1, self.block_size) as u32,
+            self.block_size,
+        );
+        let ret = new_parent.write_dir_entry(
+            &mut new_dir,
+            file_count,
+            &DirEntry::new(new_name, inode_id),
+        );
+        new_parent.modify_disk_inode(|d| *d = new_dir)?;
+        ret?;
+        let ret = self.remove_dir_entry(old_name, &mut old_dir);
+        self.modify_disk_inode(|d| *d = old_dir)?;
+        ret?;
+
+        self.fs.write_through()?;
+        Ok(true)
+    }
+
+    /// 回收已经被删除的文件的数据块, 索引块和扩展属性块; 索引节点由 FileSystem::close_inode 回收
+    fn release(&self) -> FsResult<()> {
+        let mut disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        for data_block in disk_inode.clear_size(self.block_size, &self.block_device)? {
+            self.fs.dealloc_data(data_block)?;
+        }
+        if disk_inode.xattr != 0 {
+            self.fs.dealloc_data(disk_inode.xattr)?;
+        }
+        Ok(())
     }
 
     // 文件读写
     //从目录索引到一个文件之后, 可以对它进行读写.
     // 注意: 和 DiskInode 一样, 这里的读写作用在字节序列的一段区间上
 
-    pub fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
+    /// 从 offset 开始读取文件内容到 buf, 返回读取的字节数
+    ///
+    /// 一次读取多个块时分段进行, 每段开始前先把这一段的块一次读入块缓存;
+    /// 检测到顺序读时, 读完之后再预读后面的若干个块
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> FsResult<usize> {
+        let _guard = self.lock.read();
+        let disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        let bs = self.block_size;
+        let end = (offset + buf.len()).min(disk_inode.size as usize);
+        if offset >= end {
+            return Ok(0);
+        }
+        let ahead = self
+            .read_ahead
+            .lock()
+            .access((offset / bs) as u32, ((end - 1) / bs) as u32);
+
+        let mut read_size = 0;
+        while offset + read_size < end {
+            let start = offset + read_size;
+            let segment_end = ((start / bs + READ_AHEAD_MAX) * bs).min(end);
+            let blocks = (start / bs) as u32..segment_end.div_ceil(bs) as u32;
+            if blocks.len() > 1 {
+                self.prefetch(&disk_inode, blocks);
+            }
+            read_size += disk_inode.read_at(
+                start,
+                &mut buf[read_size..segment_end - offset],
+                bs,
+                &self.block_device,
+            )?;
+        }
+        self.prefetch(&disk_inode, ahead);
+        Ok(read_size)
+    }
+
+    /// 预读文件内部第 blocks 个数据块, 超出文件大小的部分和空洞会被跳过
+    ///
+    /// 查找块号时会顺带读入路径上的索引块. 预读只是一种优化, 索引块读取失败时直接放弃, 真正读取时再报告错误
+    fn prefetch(&self, disk_inode: &DiskInode, blocks: Range<u32>) {
+        let blocks = blocks.start
+            ..blocks
+                .end
+                .min(disk_inode.size.div_ceil(self.block_size as u32));
+        let block_ids: FsResult<Vec<usize>> = blocks
+            .map(|inner_id| disk_inode.get_block_id(inner_id, self.block_size, &self.block_device))
+            .filter(|block_id| !matches!(block_id, Ok(0)))
+            .map(|block_id| block_id.map(|block_id| block_id as usize))
+            .collect();
+        if let Ok(block_ids) = block_ids {
+            block_cache_prefetch(
+                &block_ids,
+                self.block_size,
+                disk_inode.content_kind(),
+                &self.block_device,
+            );
+        }
     }
 
-    pub fn chname(&self, old_name: &str, new_name: &str) {
-        let _fs = self.fs.lock();
+    pub fn chname(&self, old_name: &str, new_name: &str) -> FsResult<()> {
+        let _guard = self.lock.write();
 
-        self.modify_disk_inode(|curr_inode| {
+        self.update_disk_inode(|curr_inode| {
             // find file by name
-            let file_count = (curr_inode.alloc_size as usize) / DIRENT_SIZE;
-            let mut dir_entry = DirEntry::create_empty();
-
             // BUG(disk_inode.size): 之后的文件无法读取 -> write change size
-
-            for i in 0..file_count {
-                curr_inode.read_at(
-                    i * DIRENT_SIZE,
-                    dir_entry.as_bytes_mut(),
-                    &self.block_device,
-                );
-                if dir_entry.name() == old_name {
-                    dir_entry.chname(new_name);
-                    curr_inode.write_at(i * DIRENT_SIZE, dir_entry.as_bytes(), &self.block_device);
-                    break;
-                }
+            if let Some((i, mut dir_entry)) = self.dir_entry_pos(old_name, curr_inode)? {
+                dir_entry.chname(new_name);
+                self.write_dir_entry(curr_inode, i, &dir_entry)?;
             }
-        });
+            Ok(())
+        })?;
         // fix: 此时退出文件 cache 未同步, 再次打开时不会被修改(事实上可以在 main.rs 的 exit 中同步))
-        block_cache_sync_all();
+        self.fs.write_through()?;
+        Ok(())
     }
 
-    pub fn dist_inode_info(&self) {
-        let _fs = self.fs.lock();
+    pub fn dist_inode_info(&self) -> FsResult<()> {
+        let _guard = self.lock.read();
         self.read_disk_inode(|disk_inode| {
-            println!("🐳 alloc_size: {} B.", disk_inode.alloc_size);
-            println!("🐳 size: {} B.", disk_inode.size);
-            println!("🐳 type: {:?}.", disk_inode.type_);
-            println!("🐳 direct blocks: {:?}.", disk_inode.direct);
-            println!("🐳 indirect1 block: {}.", disk_inode.indirect1);
-            println!("🐳 indirect2 block: {}.", disk_inode.indirect2);
-        });
+            info!("🐳 alloc_size: {} B.", disk_inode.alloc_size);
+            info!("🐳 size: {} B.", disk_inode.size);
+            info!("🐳 type: {:?}.", disk_inode.type_);
+            info!("🐳 direct blocks: {:?}.", disk_inode.direct);
+            info!("🐳 indirect1 block: {}.", disk_inode.indirect1);
+            info!("🐳 indirect2 block: {}.", disk_inode.indirect2);
+            info!("🐳 indirect3 block: {}.", disk_inode.indirect3);
+        })
     }
 
-    pub fn write(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| -> usize {
+    /// 写入之后的文件大小超过 max_file_size 时返回 FsError::FileTooLarge, 文件不变.
+    /// 写入失败时文件恢复到原来的大小, 原来的大小之外新分配的块都会被回收
+    pub fn write(&self, offset: usize, buf: &[u8]) -> FsResult<usize> {
+        let new_size = offset
+            .checked_add(buf.len())
+            .filter(|&size| size <= max_file_size(self.block_size))
+            .ok_or(FsError::FileTooLarge)?;
+        let _guard = self.lock.write();
+        let size = self.update_disk_inode(|disk_inode| -> FsResult<usize> {
             if !disk_inode.is_file() {
                 error!("write to a non-file inode");
-                return 0;
+                return Ok(0);
             }
 
-            // 如果写入的数据超过了文件的大小, 则需要增加文件的大小
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            // 写入数据
-            let write_size = disk_inode.write_at(offset, buf, &self.block_device);
+            // 如果写入的数据超过了文件的大小, 则需要增加文件的大小;
+            // 写入范围之前未写过的部分保持为空洞, 不会分配数据块
+            let old_size = disk_inode.size;
+            disk_inode.increase_size(new_size as u32, self.block_size);
+            if buf.is_empty() {
+                return Ok(0);
+            }
 
-            // 修改size (ps: 可以去看看 layout::write 处提到的bug-fix)
-            disk_inode.size = (offset + write_size) as u32;
+            let written = self.write_blocks(disk_inode, offset, buf);
+            if written.is_err() && disk_inode.size > old_size {
+                for block_id in
+                    disk_inode.truncate(old_size, self.block_size, &self.block_device)?
+                {
+                    self.fs.dealloc_data(block_id)?;
+                }
+            }
+            written
+        })?;
+        self.fs.write_through()?;
+        Ok(size)
+    }
 
-            write_size
+    /// 将 buf 写入 disk_inode 的 offset 处, 调用者需要先把文件大小扩充到足以容纳写入的范围
+    fn write_blocks(
+        &self,
+        disk_inode: &mut DiskInode,
+        offset: usize,
+        buf: &[u8],
+    ) -> FsResult<usize> {
+        // 写入范围内的空洞需要分配数据块. 为了让文件的数据块尽量连续,
+        // 一次性为它们预留一段连续的块, 并且紧接在文件中前一个数据块之后
+        let bs = self.block_size;
+        let start_block = (offset / bs) as u32;
+        let end_block = (offset + buf.len()).div_ceil(bs) as u32;
+        let mut remaining =
+            disk_inode.holes(start_block, end_block, bs, &self.block_device)? as usize;
+        let mut goal = match start_block {
+            0 => None,
+            _ => match disk_inode.get_block_id(start_block - 1, bs, &self.block_device)? {
+                0 => None,
+                block_id => Some(block_id + 1),
+            },
+        };
+        // 预留的块 [next, end); 索引块也从中分配, 不够时再在最后一个块之后申请
+        let (mut next, mut end) = (0, 0);
+        let written = disk_inode.write_at(offset, buf, bs, &self.block_device, &mut || {
+            if next == end {
+                let (start, len) = self.fs.alloc_data_run(goal, remaining.max(1))?;
+                (next, end) = (start, start + len as u32);
+            }
+            next += 1;
+            remaining = remaining.saturating_sub(1);
+            goal = Some(next);
+            Ok(next - 1)
         });
-        block_cache_sync_all();
-        size
+        // 写入失败时可能还有没用完的预留块
+        for block_id in next..end {
+            self.fs.dealloc_data(block_id)?;
+        }
+        written
+    }
+
+    /// 将文件截断或扩充到 new_len 字节
+    ///
+    /// 缩小时回收末尾的数据块和索引块; 扩充时新增的部分是一个空洞, 直到写入时才会分配数据块.
+    /// new_len 超过 max_file_size 时返回 FsError::FileTooLarge
+    pub fn truncate(&self, new_len: usize) -> FsResult<()> {
+        if new_len > max_file_size(self.block_size) {
+            return Err(FsError::FileTooLarge);
+        }
+        let _guard = self.lock.write();
+        self.update_disk_inode(|disk_inode| {
+            if !disk_inode.is_file() {
+                error!("truncate a non-file inode");
+                return Ok(());
+            }
+            for data_block in
+                disk_inode.truncate(new_len as u32, self.block_size, &self.block_device)?
+            {
+                self.fs.dealloc_data(data_block)?;
+            }
+            Ok(())
+        })?;
+        self.fs.write_through()?;
+        Ok(())
+    }
+
+    // 扩展属性
+    // 所有属性都放在 DiskInode::xattr 指向的一个块中, 每次修改都读出整个块, 修改之后再整个写回
+
+    /// 读出 disk_inode 的所有扩展属性
+    fn read_xattrs(&self, disk_inode: &DiskInode) -> FsResult<Vec<Xattr>> {
+        if disk_inode.xattr == 0 {
+            return Ok(Vec::new());
+        }
+        let block_id = disk_inode.xattr as usize;
+        get_block_cache(
+            block_id,
+            self.block_size,
+            BlockKind::Xattr,
+            Arc::clone(&self.block_device),
+        )?
+        .lock()
+        .read_slice(|data: &[u8]| xattr::decode(block_id, data))
+    }
+
+    /// 将 xattrs 写入 disk_inode 的扩展属性块: 还没有这个块时分配一个, 没有属性了则回收它
+    fn write_xattrs(&self, disk_inode: &mut DiskInode, xattrs: &[Xattr]) -> FsResult<()> {
+        if xattrs.is_empty() {
+            if disk_inode.xattr != 0 {
+                self.fs.dealloc_data(disk_inode.xattr)?;
+                disk_inode.xattr = 0;
+            }
+            return Ok(());
+        }
+        let data = xattr::encode(xattrs, self.block_size).ok_or(FsError::NoSpace)?;
+        if disk_inode.xattr == 0 {
+            disk_inode.xattr = self.fs.alloc_data()?;
+            block_cache_format(
+                disk_inode.xattr as usize,
+                self.block_size,
+                BlockKind::Xattr,
+                &self.block_device,
+            )?;
+        }
+        get_block_cache(
+            disk_inode.xattr as usize,
+            self.block_size,
+            BlockKind::Xattr,
+            Arc::clone(&self.block_device),
+        )?
+        .lock()
+        .modify_slice(|block: &mut [u8]| block.copy_from_slice(&data));
+        Ok(())
+    }
+
+    /// 获取名为 name 的扩展属性的值, 不存在时返回 None
+    pub fn get_xattr(&self, name: &str) -> FsResult<Option<Vec<u8>>> {
+        let _guard = self.lock.read();
+        let disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        Ok(self
+            .read_xattrs(&disk_inode)?
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value))
+    }
+
+    /// 按设置的先后顺序列出所有扩展属性的名字
+    pub fn list_xattr(&self) -> FsResult<Vec<String>> {
+        let _guard = self.lock.read();
+        let disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        Ok(self
+            .read_xattrs(&disk_inode)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    /// 设置名为 name 的扩展属性, 已经存在时替换它的值
+    ///
+    /// name 必须非空且不超过 XATTR_NAME_MAX 字节, 否则返回 FsError::InvalidArgument;
+    /// 所有属性加起来放不进一个块时返回 FsError::NoSpace, 此时原有的属性不变
+    pub fn set_xattr(&self, name: &str, value: &[u8]) -> FsResult<()> {
+        if !xattr::valid_name(name) {
+            return Err(FsError::InvalidArgument);
+        }
+        let _guard = self.lock.write();
+        self.update_disk_inode(|disk_inode| {
+            let mut xattrs = self.read_xattrs(disk_inode)?;
+            match xattrs.iter_mut().find(|(key, _)| key == name) {
+                Some((_, old)) => *old = value.to_vec(),
+                None => xattrs.push((String::from(name), value.to_vec())),
+            }
+            self.write_xattrs(disk_inode, &xattrs)
+        })?;
+        self.fs.write_through()
+    }
+
+    /// 删除名为 name 的扩展属性, 不存在时返回 false
+    pub fn remove_xattr(&self, name: &str) -> FsResult<bool> {
+        let _guard = self.lock.write();
+        let removed = self.update_disk_inode(|disk_inode| {
+            let mut xattrs = self.read_xattrs(disk_inode)?;
+            let len = xattrs.len();
+            xattrs.retain(|(key, _)| key != name);
+            if xattrs.len() == len {
+                return Ok(false);
+            }
+            self.write_xattrs(disk_inode, &xattrs)?;
+            Ok(true)
+        })?;
+        self.fs.write_through()?;
+        Ok(removed)
+    }
+
+    /// 只写回这个文件/目录的块: 索引节点所在的块, 数据块, 索引块和扩展属性块, 以及两个位图的块
+    ///
+    /// 位图块很少, 一起写回是为了不在崩溃之后留下已经被文件使用, 但在位图中仍然空闲的块
+    pub fn fsync(&self) -> FsResult<()> {
+        let _guard = self.lock.read();
+        let disk_inode = self.read_disk_inode(DiskInode::clone)?;
+        let mut block_ids: Vec<usize> = disk_inode
+            .block_ids(self.block_size, &self.block_device)?
+            .into_iter()
+            .map(|block_id| block_id as usize)
+            .chain(self.fs.bitmap_block_ids())
+            .collect();
+        block_ids.push(self.block_id);
+        block_ids.sort_unstable();
+        block_cache_sync_blocks(&self.block_device, &block_ids)
+    }
+}
+
+impl Drop for Inode {
+    /// 最后一次关闭时, 如果文件已经被删除, 回收它占用的空间; 最后才从索引节点表中移除并回收索引节点.
+    /// 块没能全部回收时不回收索引节点, 宁可泄漏也不让这个编号被重新分配
+    fn drop(&mut self) {
+        let mut dealloc = false;
+        if self.unlinked.load(Ordering::Acquire) {
+            match self.release() {
+                Ok(()) => dealloc = true,
+                Err(err) => error!("failed to release inode {}: {}", self.inode_id, err),
+            }
+        }
+        if let Err(err) = self.fs.close_inode(self.inode_id, dealloc) {
+            error!("failed to release inode {}: {}", self.inode_id, err);
+        }
     }
 }
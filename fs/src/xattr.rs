@@ -0,0 +1,79 @@
+//! 扩展属性 (extended attributes)
+//!
+//! 扩展属性是附加在文件/目录上的一组键值对, 例如构建 id, 校验和, 或者将来权限模型使用的标签.
+//! 一个索引节点的所有扩展属性放在一个单独的 xattr 块中, 由 DiskInode::xattr 指向;
+//! 没有扩展属性的索引节点不占用 xattr 块.
+//!
+//! xattr 块中依次存放每个属性:
+//!
+//! ```text
+//! | name_len: u8 | value_len: u16 (LE) | name: name_len B | value: value_len B | ... | 0 | ... | checksum: u32 |
+//! ```
+//!
+//! name_len 为 0 表示后面没有属性了. 与索引块和目录块一样, 块的最后 4 个字节是整个块的校验和,
+//! 因此所有属性加起来不能超过 block_size - 4 - 1 字节 (至少留下一个字节作为结束标记).
+
+use alloc::{string::String, vec, vec::Vec};
+
+use super::{FsError, FsResult, CHECKSUM_SIZE, XATTR_NAME_MAX};
+
+/// 每个属性的头部: name_len (1B) + value_len (2B)
+const HEADER_SIZE: usize = 3;
+
+/// 一个扩展属性
+pub type Xattr = (String, Vec<u8>);
+
+/// xattr 块中可以存放属性的字节数
+fn capacity(block_size: usize) -> usize {
+    block_size - CHECKSUM_SIZE - 1
+}
+
+/// 属性名必须非空, 且不超过 XATTR_NAME_MAX 字节
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= XATTR_NAME_MAX
+}
+
+/// 解析编号为 block_id 的 xattr 块的内容 data; 长度越界或者名字不是 UTF-8 时返回 FsError::Corrupted
+pub fn decode(block_id: usize, data: &[u8]) -> FsResult<Vec<Xattr>> {
+    let limit = capacity(data.len());
+    let corrupted = FsError::Corrupted(block_id);
+    let mut xattrs = Vec::new();
+    let mut pos = 0;
+    while pos < limit && data[pos] != 0 {
+        let name_len = data[pos] as usize;
+        let value_len = u16::from_le_bytes([data[pos + 1], data[pos + 2]]) as usize;
+        let name_start = pos + HEADER_SIZE;
+        let value_start = name_start + name_len;
+        let end = value_start + value_len;
+        if end > limit {
+            return Err(corrupted);
+        }
+        let name = core::str::from_utf8(&data[name_start..value_start]).map_err(|_| corrupted)?;
+        xattrs.push((String::from(name), data[value_start..end].to_vec()));
+        pos = end;
+    }
+    Ok(xattrs)
+}
+
+/// 将 xattrs 编码为一个 xattr 块的内容 (校验和由块缓存填写), 放不下时返回 None
+pub fn encode(xattrs: &[Xattr], block_size: usize) -> Option<Vec<u8>> {
+    let size: usize = xattrs
+        .iter()
+        .map(|(name, value)| HEADER_SIZE + name.len() + value.len())
+        .sum();
+    if size > capacity(block_size) {
+        return None;
+    }
+    let mut data = vec![0u8; block_size];
+    let mut pos = 0;
+    for (name, value) in xattrs {
+        data[pos] = name.len() as u8;
+        data[pos + 1..pos + HEADER_SIZE].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        pos += HEADER_SIZE;
+        data[pos..pos + name.len()].copy_from_slice(name.as_bytes());
+        pos += name.len();
+        data[pos..pos + value.len()].copy_from_slice(value);
+        pos += value.len();
+    }
+    Some(data)
+}
@@ -0,0 +1,256 @@
+//! 块缓存写回策略和预读的测试
+//!
+//! 块缓存是全局共享的, 其他测试加入的块会把这里的脏块替换 (也就是写回) 出去,
+//! 因此这些测试单独放在一个测试程序中, 并且用 SERIAL 保证它们不会同时运行
+
+mod common;
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{channel, Receiver, Sender},
+    Arc, Mutex,
+};
+
+use common::pattern;
+use easy_fs::{
+    block_cache_prefetch, get_block_cache, BlockDevice, BlockError, BlockKind, CacheMode,
+    DiskInodeType, FileSystem, RamDisk, BLOCK_CACHE_SIZE, SECTOR_SIZE,
+};
+
+const BS: usize = 512;
+const BLOCKS: usize = 2048;
+
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// 创建文件系统之后以 mode 重新挂载
+fn mount(mode: CacheMode) -> (Arc<RamDisk>, Arc<FileSystem>) {
+    let disk = Arc::new(RamDisk::new(BLOCKS * BS / SECTOR_SIZE));
+    let device: Arc<dyn BlockDevice> = disk.clone();
+    drop(FileSystem::create(Arc::clone(&device), BLOCKS as u32, 1, BS as u32).unwrap());
+    let fs = FileSystem::open_with(device, mode).unwrap();
+    assert_eq!(fs.cache_mode(), mode);
+    (disk, fs)
+}
+
+/// data 是否已经出现在磁盘上
+fn on_disk(disk: &RamDisk, data: &[u8]) -> bool {
+    disk.to_vec()
+        .windows(data.len())
+        .any(|window| window == data)
+}
+
+#[test]
+fn sync_mode_writes_through() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    let (disk, fs) = mount(CacheMode::Sync);
+    let file = FileSystem::root_inode(&fs)
+        .create("a", DiskInodeType::File)
+        .unwrap()
+        .unwrap();
+    let data = pattern(2 * BS, 1);
+    file.write(0, &data).unwrap();
+    assert!(on_disk(&disk, &data));
+}
+
+#[test]
+fn writeback_defers_until_fsync() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    let (disk, fs) = mount(CacheMode::WriteBack);
+    let root = FileSystem::root_inode(&fs);
+    let a = root.create("a", DiskInodeType::File).unwrap().unwrap();
+    let b = root.create("b", DiskInodeType::File).unwrap().unwrap();
+    let data_a = pattern(2 * BS, 2);
+    let data_b = pattern(2 * BS, 3);
+    a.write(0, &data_a).unwrap();
+    b.write(0, &data_b).unwrap();
+    assert!(!on_disk(&disk, &data_a));
+
+    // fsync 只写回 a 自己的块
+    a.fsync().unwrap();
+    assert!(on_disk(&disk, &data_a));
+    assert!(!on_disk(&disk, &data_b));
+
+    fs.sync().unwrap();
+    assert!(on_disk(&disk, &data_b));
+
+    // 写回之后重新挂载, 两个文件都完好
+    drop((a, b, root, fs));
+    let fs = FileSystem::open(disk).unwrap();
+    let root = FileSystem::root_inode(&fs);
+    let mut buf = vec![0u8; data_b.len()];
+    root.find("b").unwrap().unwrap().read(0, &mut buf).unwrap();
+    assert_eq!(buf, data_b);
+}
+
+#[test]
+fn periodic_flush_writes_back_old_blocks() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    let (disk, fs) = mount(CacheMode::WriteBack);
+    let file = FileSystem::root_inode(&fs)
+        .create("a", DiskInodeType::File)
+        .unwrap()
+        .unwrap();
+    let data = pattern(2 * BS, 4);
+    file.write(0, &data).unwrap();
+
+    // 在当前周期内变脏的块要等到下一个周期才写回
+    fs.flush_expired().unwrap();
+    assert!(!on_disk(&disk, &data));
+    fs.flush_expired().unwrap();
+    assert!(on_disk(&disk, &data));
+}
+
+#[test]
+fn unmount_writes_back() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    let (disk, fs) = mount(CacheMode::WriteBack);
+    let file = FileSystem::root_inode(&fs)
+        .create("a", DiskInodeType::File)
+        .unwrap()
+        .unwrap();
+    let data = pattern(2 * BS, 5);
+    file.write(0, &data).unwrap();
+    assert!(!on_disk(&disk, &data));
+    drop((file, fs));
+    assert!(on_disk(&disk, &data));
+}
+
+/// 记录读操作次数的块设备, 一次 read_blocks 只算一次
+struct CountingDevice {
+    inner: Arc<RamDisk>,
+    reads: AtomicUsize,
+}
+
+impl BlockDevice for CountingDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.read_blocks(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        self.inner.write_block(block_id, buf)
+    }
+}
+
+#[test]
+fn sequential_reads_are_batched() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    const BLOCKS_IN_FILE: usize = 64;
+    let (disk, fs) = mount(CacheMode::Sync);
+    let data = pattern(BLOCKS_IN_FILE * BS, 6);
+    FileSystem::root_inode(&fs)
+        .create("a", DiskInodeType::File)
+        .unwrap()
+        .unwrap()
+        .write(0, &data)
+        .unwrap();
+    drop(fs);
+
+    // 换一个设备对象重新挂载, 块缓存中没有它的任何块
+    let device = Arc::new(CountingDevice {
+        inner: disk,
+        reads: AtomicUsize::new(0),
+    });
+    let fs = FileSystem::open(device.clone()).unwrap();
+    let file = FileSystem::root_inode(&fs).find("a").unwrap().unwrap();
+    let before = device.reads.load(Ordering::Relaxed);
+
+    // 逐块顺序读: 没有预读时每个块都是一次读操作
+    let mut buf = vec![0u8; data.len()];
+    for (i, chunk) in buf.chunks_mut(BS).enumerate() {
+        assert_eq!(file.read(i * BS, chunk).unwrap(), BS);
+    }
+    assert_eq!(buf, data);
+    let reads = device.reads.load(Ordering::Relaxed) - before;
+    // 预读的块会把索引节点所在的块替换出去, 因此每批预读之后还要重新读一次索引节点
+    assert!(reads < BLOCKS_IN_FILE / 2, "{} reads", reads);
+
+    // 倒着读不会触发预读, 但结果同样正确
+    buf.fill(0);
+    for (i, chunk) in buf.chunks_mut(BS).enumerate().rev() {
+        assert_eq!(file.read(i * BS, chunk).unwrap(), BS);
+    }
+    assert_eq!(buf, data);
+
+    // 一次读取整个文件也是分段批量读入的
+    drop((file, fs));
+    let device = Arc::new(CountingDevice {
+        inner: Arc::clone(&device.inner),
+        reads: AtomicUsize::new(0),
+    });
+    let fs = FileSystem::open(device.clone()).unwrap();
+    let file = FileSystem::root_inode(&fs).find("a").unwrap().unwrap();
+    let before = device.reads.load(Ordering::Relaxed);
+    buf.fill(0);
+    assert_eq!(file.read(0, &mut buf).unwrap(), data.len());
+    assert_eq!(buf, data);
+    let reads = device.reads.load(Ordering::Relaxed) - before;
+    assert!(reads < BLOCKS_IN_FILE / 4, "{} reads", reads);
+}
+
+/// 第一次读取多个扇区时, 读完数据之后暂停, 直到测试允许它继续, 用来制造预读和其他线程之间的竞争
+struct GatedDevice {
+    inner: RamDisk,
+    /// (通知测试已经读完, 等待测试允许继续)
+    gate: Mutex<Option<(Sender<()>, Receiver<()>)>>,
+}
+
+impl BlockDevice for GatedDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.inner.read_block(block_id, buf)
+    }
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.inner.read_blocks(block_id, buf)?;
+        if buf.len() > SECTOR_SIZE {
+            if let Some((done, resume)) = self.gate.lock().unwrap().take() {
+                done.send(()).unwrap();
+                resume.recv().unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        self.inner.write_block(block_id, buf)
+    }
+}
+
+#[test]
+fn prefetch_does_not_resurrect_evicted_blocks() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    let (done_tx, done_rx) = channel();
+    let (resume_tx, resume_rx) = channel();
+    let device: Arc<dyn BlockDevice> = Arc::new(GatedDevice {
+        inner: RamDisk::new(BLOCKS),
+        gate: Mutex::new(Some((done_tx, resume_rx))),
+    });
+
+    std::thread::scope(|s| {
+        let prefetcher =
+            s.spawn(|| block_cache_prefetch(&[100, 101], BS, BlockKind::Data, &device));
+        // 预读已经读出了块 100 的旧内容, 但还没有放入缓存
+        done_rx.recv().unwrap();
+        get_block_cache(100, BS, BlockKind::Data, Arc::clone(&device))
+            .unwrap()
+            .lock()
+            .modify(0, |value: &mut u64| *value = 0xdead_beef);
+        // 替换出块 100, 修改被写回磁盘
+        for block_id in 200..200 + BLOCK_CACHE_SIZE {
+            get_block_cache(block_id, BS, BlockKind::Data, Arc::clone(&device)).unwrap();
+        }
+        resume_tx.send(()).unwrap();
+        prefetcher.join().unwrap();
+    });
+
+    let value = get_block_cache(100, BS, BlockKind::Data, device)
+        .unwrap()
+        .lock()
+        .read(0, |value: &u64| *value);
+    assert_eq!(value, 0xdead_beef);
+}
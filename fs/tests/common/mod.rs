@@ -0,0 +1,21 @@
+//! 测试共用的辅助函数
+
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use easy_fs::{BlockDevice, FileSystem, RamDisk, SECTOR_SIZE};
+
+/// 在一个新的 RamDisk 上创建文件系统, 共 blocks 个块, 索引节点位图占一个块
+pub fn new_fs(block_size: usize, blocks: usize) -> (Arc<dyn BlockDevice>, Arc<FileSystem>) {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(blocks * block_size / SECTOR_SIZE));
+    let fs = FileSystem::create(Arc::clone(&disk), blocks as u32, 1, block_size as u32).unwrap();
+    (disk, fs)
+}
+
+/// 生成长度为 len 的测试数据, 不同的 seed 得到不同的内容
+pub fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u32).wrapping_mul(2654435761).to_le_bytes()[1] ^ seed)
+        .collect()
+}
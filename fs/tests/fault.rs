@@ -0,0 +1,175 @@
+//! 用 FaultyDevice 注入 I/O 错误, 检查错误能够被返回给上层, 并且设备恢复之后数据没有丢失
+
+use std::sync::Arc;
+
+use easy_fs::{
+    block_cache_sync, get_block_cache, BlockDevice, BlockError, BlockKind, DiskInodeType, Fault,
+    FaultyDevice, FileSystem, FsError, RamDisk, SuperBlock, BLOCK_CACHE_SIZE, SECTOR_SIZE,
+};
+
+const BS: usize = 512;
+const BLOCKS: usize = 4096;
+
+fn new_fs() -> (Arc<FaultyDevice>, Arc<FileSystem>) {
+    let disk = Arc::new(FaultyDevice::new(Arc::new(RamDisk::new(BLOCKS))));
+    let fs = FileSystem::create(disk.clone(), BLOCKS as u32, 1, BS as u32).unwrap();
+    (disk, fs)
+}
+
+/// 数据区域的第一个块; 新文件系统中根目录的第一个目录块就是它, 之后依次分配
+fn data_area_start(disk: &Arc<FaultyDevice>) -> usize {
+    let disk: Arc<dyn BlockDevice> = disk.clone();
+    get_block_cache(0, BS, BlockKind::Super, disk)
+        .unwrap()
+        .lock()
+        .read(0, |sb: &SuperBlock| {
+            (1 + sb.inode_bitmap_blocks + sb.inode_area_blocks + sb.data_bitmap_blocks) as usize
+        })
+}
+
+/// 访问设备末尾的若干个块, 把之前的块缓存都替换出去, 迫使之后重新从设备读取
+fn evict(disk: &Arc<FaultyDevice>) {
+    let disk: Arc<dyn BlockDevice> = disk.clone();
+    for block_id in BLOCKS - BLOCK_CACHE_SIZE..BLOCKS {
+        get_block_cache(block_id, BS, BlockKind::Data, Arc::clone(&disk)).unwrap();
+    }
+}
+
+#[test]
+fn open_fails_when_superblock_unreadable() {
+    let (disk, fs) = new_fs();
+    drop(fs);
+    disk.fail(0, Fault::Read);
+    let dev: Arc<dyn BlockDevice> = disk.clone();
+    assert_eq!(
+        FileSystem::open(Arc::clone(&dev)).err(),
+        Some(FsError::Io(BlockError::Io(0)))
+    );
+    disk.heal(0);
+    assert!(FileSystem::open(dev).is_ok());
+}
+
+#[test]
+fn open_fails_when_superblock_invalid() {
+    let (disk, fs) = new_fs();
+    drop(fs);
+    let dev: Arc<dyn BlockDevice> = disk.clone();
+    // 通过块缓存修改, 写回时会重新计算校验和, 因此只有块大小不对
+    let set_block_size = |block_size: u32| {
+        get_block_cache(0, BS, BlockKind::Super, Arc::clone(&dev))
+            .unwrap()
+            .lock()
+            .modify(0, |sb: &mut SuperBlock| sb.block_size = block_size);
+        block_cache_sync(&dev).unwrap();
+    };
+    set_block_size(3000);
+    assert_eq!(
+        FileSystem::open(Arc::clone(&dev)).err(),
+        Some(FsError::Corrupted(0))
+    );
+    set_block_size(BS as u32);
+    assert!(FileSystem::open(dev).is_ok());
+}
+
+#[test]
+fn read_error_is_reported_and_recoverable() {
+    let (disk, fs) = new_fs();
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    file.write(0, &[7u8; BS]).unwrap();
+    let data_block = data_area_start(&disk) + 1;
+    evict(&disk);
+
+    disk.fail_block(data_block, BS, Fault::Read);
+    let mut buf = [0u8; BS];
+    assert_eq!(
+        file.read(0, &mut buf),
+        Err(FsError::Io(BlockError::Io(data_block * BS / SECTOR_SIZE)))
+    );
+
+    disk.heal_all();
+    assert_eq!(file.read(0, &mut buf).unwrap(), BS);
+    assert!(buf.iter().all(|&b| b == 7));
+}
+
+#[test]
+fn metadata_read_error_is_reported() {
+    let (disk, fs) = new_fs();
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    let (block_id, _) = file.inode_info();
+    evict(&disk);
+
+    disk.fail_block(block_id, BS, Fault::Read);
+    assert!(matches!(file.size(), Err(FsError::Io(_))));
+    assert!(matches!(root.ls(), Err(FsError::Io(_))));
+    disk.heal_all();
+    assert_eq!(root.ls().unwrap(), ["f"]);
+}
+
+#[test]
+fn zeroed_metadata_block_is_corrupted() {
+    let (disk, fs) = new_fs();
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    let (block_id, _) = file.inode_info();
+    evict(&disk);
+    // 绕过块缓存把块直接清零, 全零的块不能通过校验
+    let zero_block = |block_id: usize| {
+        for sector in block_id * BS / SECTOR_SIZE..(block_id + 1) * BS / SECTOR_SIZE {
+            disk.write_block(sector, &[0; SECTOR_SIZE]).unwrap();
+        }
+    };
+
+    zero_block(block_id);
+    assert_eq!(file.size(), Err(FsError::Corrupted(block_id)));
+    drop((root, file, fs));
+
+    // 索引节点位图位于 1 号块, 打开时就会读取
+    evict(&disk);
+    zero_block(1);
+    assert_eq!(
+        FileSystem::open(disk.clone()).err(),
+        Some(FsError::Corrupted(1))
+    );
+}
+
+#[test]
+fn write_error_keeps_data_until_healed() {
+    let (disk, fs) = new_fs();
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    file.write(0, &[1u8; BS]).unwrap();
+    let data_block = data_area_start(&disk) + 1;
+
+    disk.fail_block(data_block, BS, Fault::Write);
+    assert!(matches!(file.write(0, &[2u8; BS]), Err(FsError::Io(_))));
+    let dev: Arc<dyn BlockDevice> = disk.clone();
+    assert!(block_cache_sync(&dev).is_err());
+    // 写回失败的块不会被替换出去, 修改仍然在块缓存中
+    evict(&disk);
+    let mut buf = [0u8; BS];
+    file.read(0, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 2));
+
+    disk.heal_all();
+    block_cache_sync(&dev).unwrap();
+    evict(&disk);
+    drop((root, file, fs));
+    let fs = FileSystem::open(disk).unwrap();
+    let file = FileSystem::root_inode(&fs).find("f").unwrap().unwrap();
+    file.read(0, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 2));
+}
+
+#[test]
+fn ram_disk_rejects_out_of_range_sectors() {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(8));
+    let mut buf = [0u8; SECTOR_SIZE];
+    assert_eq!(disk.read_block(8, &mut buf), Err(BlockError::OutOfRange(8)));
+    assert_eq!(disk.write_block(9, &buf), Err(BlockError::OutOfRange(9)));
+    assert!(matches!(
+        get_block_cache(4, 1024, BlockKind::Data, disk),
+        Err(FsError::Io(BlockError::OutOfRange(8)))
+    ));
+}
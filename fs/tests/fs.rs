@@ -0,0 +1,638 @@
+//! FileSystem 和 Inode 的基本功能测试, 重点覆盖直接索引/一级索引/二级索引的边界
+
+mod common;
+
+use std::sync::Arc;
+
+use common::{new_fs, pattern};
+use easy_fs::{
+    get_block_cache, indirect1_bound, indirect2_bound, max_file_size, BlockDevice, BlockKind,
+    DiskInode, DiskInodeType, FileSystem, FsError, RamDisk, BLOCK_SIZES, DIRECT_BOUND, SECTOR_SIZE,
+    XATTR_NAME_MAX,
+};
+
+/// 512 字节的块, 二级索引的上界约为 8 MiB
+const BS: usize = 512;
+
+#[test]
+fn create_and_reopen() {
+    for block_size in BLOCK_SIZES {
+        let (disk, fs) = new_fs(block_size, 2048);
+        let root = FileSystem::root_inode(&fs);
+        let dir = root
+            .create("dir", DiskInodeType::Directory)
+            .unwrap()
+            .unwrap();
+        let file = dir.create("file", DiskInodeType::File).unwrap().unwrap();
+        let data = pattern(3 * block_size + 17, 1);
+        assert_eq!(file.write(0, &data).unwrap(), data.len());
+        drop((root, dir, file, fs));
+
+        let fs = FileSystem::open(disk).unwrap();
+        assert_eq!(fs.block_size, block_size);
+        let root = FileSystem::root_inode(&fs);
+        assert_eq!(root.ls().unwrap(), ["dir"]);
+        let dir = root.find("dir").unwrap().unwrap();
+        assert!(dir.is_dir().unwrap());
+        let file = dir.find("file").unwrap().unwrap();
+        assert!(!file.is_dir().unwrap());
+        let mut buf = vec![0; data.len() + 100];
+        assert_eq!(file.read(0, &mut buf).unwrap(), data.len());
+        assert_eq!(&buf[..data.len()], &data[..]);
+    }
+}
+
+#[test]
+fn create_rejects_unsupported_block_size() {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(2048 * 4096 / SECTOR_SIZE));
+    for block_size in [0, 1000, 3000, 8192] {
+        assert_eq!(
+            FileSystem::create(Arc::clone(&disk), 2048, 1, block_size).err(),
+            Some(FsError::InvalidArgument)
+        );
+    }
+}
+
+#[test]
+fn create_rejects_too_small_device() {
+    let block_size = BLOCK_SIZES[0];
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(2048 * block_size / SECTOR_SIZE));
+    // 超级块, 索引节点位图和索引节点区就占满了, 放不下数据块位图和数据块
+    for total_blocks in [0, 1, 2, 3] {
+        assert_eq!(
+            FileSystem::create(Arc::clone(&disk), total_blocks, 1, block_size as u32).err(),
+            Some(FsError::InvalidArgument)
+        );
+    }
+    assert_eq!(
+        FileSystem::create(Arc::clone(&disk), 2048, 0, block_size as u32).err(),
+        Some(FsError::InvalidArgument)
+    );
+}
+
+#[test]
+fn create_existing_name_fails() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    assert!(root.create("a", DiskInodeType::File).unwrap().is_some());
+    assert!(root.create("a", DiskInodeType::File).unwrap().is_none());
+    assert!(root
+        .create("a", DiskInodeType::Directory)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn read_write_across_index_boundaries() {
+    let (_disk, fs) = new_fs(BS, 20000);
+    let root = FileSystem::root_inode(&fs);
+    for (i, bound) in [DIRECT_BOUND, indirect1_bound(BS), indirect2_bound(BS)]
+        .into_iter()
+        .enumerate()
+    {
+        let file = root
+            .create(&format!("f{}", i), DiskInodeType::File)
+            .unwrap()
+            .unwrap();
+        // 写入一段跨过边界的数据, 其余部分为空洞
+        let offset = bound * BS - 700;
+        let data = pattern(1400, i as u8);
+        assert_eq!(file.write(offset, &data).unwrap(), data.len());
+        assert_eq!(file.size().unwrap(), offset + data.len());
+
+        let mut buf = vec![0; 2000];
+        assert_eq!(file.read(offset - 300, &mut buf).unwrap(), 1700);
+        assert!(buf[..300].iter().all(|&b| b == 0));
+        assert_eq!(&buf[300..1700], &data[..]);
+
+        // 逐字节地跨过边界读
+        let mut byte = [0u8];
+        for k in [699, 700] {
+            assert_eq!(file.read(offset + k, &mut byte).unwrap(), 1);
+            assert_eq!(byte[0], data[k]);
+        }
+        file.clear().unwrap();
+    }
+}
+
+#[test]
+fn allocated_blocks_match_total_blocks() {
+    let (_disk, fs) = new_fs(BS, 20000);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    for bound in [DIRECT_BOUND, indirect1_bound(BS), indirect2_bound(BS)] {
+        for blocks in [bound - 1, bound, bound + 1] {
+            let size = blocks * BS;
+            file.clear().unwrap();
+            file.write(0, &pattern(size, 7)).unwrap();
+            let stat = file.stat().unwrap();
+            assert_eq!(stat.size, size);
+            assert_eq!(
+                stat.blocks,
+                DiskInode::total_blocks(size as u32, BS) as usize,
+                "{} blocks",
+                blocks
+            );
+        }
+    }
+}
+
+#[test]
+fn clear_releases_all_blocks() {
+    let (_disk, fs) = new_fs(BS, 20000);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    let before = fs.statfs().used_blocks;
+
+    let size = (indirect1_bound(BS) + 300) * BS;
+    file.write(0, &pattern(size, 3)).unwrap();
+    assert_eq!(
+        fs.statfs().used_blocks - before,
+        DiskInode::total_blocks(size as u32, BS) as usize
+    );
+
+    file.clear().unwrap();
+    assert_eq!(file.size().unwrap(), 0);
+    assert_eq!(file.stat().unwrap().blocks, 0);
+    assert_eq!(fs.statfs().used_blocks, before);
+    let mut buf = [0u8; 16];
+    assert_eq!(file.read(0, &mut buf).unwrap(), 0);
+}
+
+#[test]
+fn rm_dir_entry_keeps_other_entries() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    // 40 个目录项会占用多个目录块
+    let names: Vec<String> = (0..40).map(|i| format!("file{}", i)).collect();
+    for (i, name) in names.iter().enumerate() {
+        let file = root.create(name, DiskInodeType::File).unwrap().unwrap();
+        file.write(0, name.as_bytes()).unwrap();
+        assert_eq!(file.inode_id() as usize, i + 1);
+    }
+
+    let mut expected = names.clone();
+    for removed in ["file0", "file14", "file15", "file39", "file20"] {
+        root.rm_dir_entry(removed, Arc::clone(&root)).unwrap();
+        expected.retain(|name| name != removed);
+        assert!(root.find(removed).unwrap().is_none());
+    }
+    assert_eq!(root.ls().unwrap(), expected);
+    for name in &expected {
+        let file = root.find(name).unwrap().unwrap();
+        let mut buf = vec![0; 32];
+        let len = file.read(0, &mut buf).unwrap();
+        assert_eq!(&buf[..len], name.as_bytes());
+    }
+    // 删除不存在的目录项不会影响其他目录项
+    root.rm_dir_entry("missing", Arc::clone(&root)).unwrap();
+    assert_eq!(root.ls().unwrap(), expected);
+}
+
+#[test]
+fn open_twice_shares_inode() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let a = root.create("a", DiskInodeType::File).unwrap().unwrap();
+    let b = root.find("a").unwrap().unwrap();
+    assert!(Arc::ptr_eq(&a, &b));
+    assert_eq!(fs.open_count(a.inode_id()), 2);
+    drop(b);
+    assert_eq!(fs.open_count(a.inode_id()), 1);
+}
+
+#[test]
+fn unlink_while_open_defers_release() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    // 目录删除目录项之后不会回收目录块, 先让根目录分配好目录块
+    root.create("keep", DiskInodeType::File).unwrap().unwrap();
+    let baseline = fs.statfs();
+    let file = root.create("a", DiskInodeType::File).unwrap().unwrap();
+    let data = pattern(40 * BS, 9);
+    file.write(0, &data).unwrap();
+
+    assert!(root.unlink("a").unwrap());
+    assert!(root.find("a").unwrap().is_none());
+    // 仍然打开着, 内容可以继续读写
+    let mut buf = vec![0; data.len()];
+    assert_eq!(file.read(0, &mut buf).unwrap(), data.len());
+    assert_eq!(buf, data);
+    assert!(fs.statfs().used_blocks > baseline.used_blocks);
+
+    drop(file);
+    let after = fs.statfs();
+    assert_eq!(after.used_inodes, baseline.used_inodes);
+    assert_eq!(after.used_blocks, baseline.used_blocks);
+    assert!(!root.unlink("a").unwrap());
+}
+
+#[test]
+fn concurrent_create_and_unlink_do_not_leak() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    root.create("keep", DiskInodeType::File).unwrap().unwrap();
+    let baseline = fs.statfs();
+    for _ in 0..200 {
+        let dir = root.create("d", DiskInodeType::Directory).unwrap().unwrap();
+        // 一个线程在 d 中创建文件, 另一个线程同时删除 d
+        let (created, unlinked) = std::thread::scope(|s| {
+            let creator = s.spawn(|| {
+                dir.create("f", DiskInodeType::File)
+                    .unwrap()
+                    .inspect(|file| {
+                        file.write(0, b"data").unwrap();
+                    })
+            });
+            let unlinker = s.spawn(|| root.unlink("d").unwrap());
+            (creator.join().unwrap(), unlinker.join().unwrap())
+        });
+        // 两者恰好有一个成功: 要么 d 被删除时还是空的, 要么 f 创建在删除之前
+        assert_ne!(created.is_some(), unlinked);
+        if created.is_some() {
+            assert!(dir.unlink("f").unwrap());
+            assert!(root.unlink("d").unwrap());
+        }
+        assert!(dir.create("g", DiskInodeType::File).unwrap().is_none());
+        drop((created, dir));
+        let after = fs.statfs();
+        assert_eq!(after.used_inodes, baseline.used_inodes);
+        assert_eq!(after.used_blocks, baseline.used_blocks);
+    }
+}
+
+#[test]
+fn reused_inode_ids_do_not_alias_released_inodes() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    root.create("keep", DiskInodeType::File).unwrap().unwrap();
+    let baseline = fs.statfs();
+    // 每个线程反复创建和删除自己的文件, 释放的索引节点编号马上会被其他线程重新分配
+    std::thread::scope(|s| {
+        for t in 0..4u8 {
+            let root = &root;
+            s.spawn(move || {
+                let name = format!("f{}", t);
+                for i in 0..100u8 {
+                    let file = root.create(&name, DiskInodeType::File).unwrap().unwrap();
+                    let data = pattern(BS + 7, t ^ i);
+                    file.write(0, &data).unwrap();
+                    let found = root.find(&name).unwrap().unwrap();
+                    assert!(Arc::ptr_eq(&file, &found));
+                    assert!(root.unlink(&name).unwrap());
+                    // 另一个线程持有最后一个引用, 释放和其他线程的 create 同时进行
+                    let reader = std::thread::spawn(move || {
+                        let mut buf = vec![0; data.len()];
+                        assert_eq!(found.read(0, &mut buf).unwrap(), data.len());
+                        assert_eq!(buf, data);
+                    });
+                    drop(file);
+                    reader.join().unwrap();
+                }
+            });
+        }
+    });
+    let after = fs.statfs();
+    assert_eq!(after.used_inodes, baseline.used_inodes);
+    assert_eq!(after.used_blocks, baseline.used_blocks);
+}
+
+#[test]
+fn unlink_refuses_non_empty_directory() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let dir = root.create("d", DiskInodeType::Directory).unwrap().unwrap();
+    dir.create("f", DiskInodeType::File).unwrap().unwrap();
+    assert!(!root.unlink("d").unwrap());
+    assert!(dir.unlink("f").unwrap());
+    assert!(root.unlink("d").unwrap());
+    assert!(root.ls().unwrap().is_empty());
+}
+
+#[test]
+fn rename_between_directories() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let dir = root.create("d", DiskInodeType::Directory).unwrap().unwrap();
+    let file = root.create("a", DiskInodeType::File).unwrap().unwrap();
+    file.write(0, b"hello").unwrap();
+    root.create("b", DiskInodeType::File).unwrap().unwrap();
+
+    assert!(!root.rename("a", &root, "b").unwrap());
+    assert!(root.rename("a", &root, "c").unwrap());
+    assert!(root.rename("c", &dir, "e").unwrap());
+    assert_eq!(root.ls().unwrap(), ["d", "b"]);
+    assert_eq!(dir.ls().unwrap(), ["e"]);
+    assert!(Arc::ptr_eq(&dir.find("e").unwrap().unwrap(), &file));
+}
+
+#[test]
+fn sparse_file_and_truncate() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    let offset = (indirect1_bound(BS) + 10) * BS;
+    file.write(offset, b"tail").unwrap();
+    // 一个数据块, 一个二级索引块和一个一级索引块
+    assert_eq!(file.stat().unwrap().blocks, 3);
+
+    let mut buf = vec![1u8; BS];
+    assert_eq!(file.read(BS, &mut buf).unwrap(), BS);
+    assert!(buf.iter().all(|&b| b == 0));
+
+    file.truncate(offset + 2).unwrap();
+    assert_eq!(file.size().unwrap(), offset + 2);
+    file.truncate(offset + 4).unwrap();
+    let mut tail = [0u8; 4];
+    file.read(offset, &mut tail).unwrap();
+    assert_eq!(&tail, b"ta\0\0");
+
+    file.truncate(BS).unwrap();
+    assert_eq!(file.stat().unwrap().blocks, 0);
+}
+
+#[test]
+fn sparse_write_across_indirect3_and_truncate() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    let before = fs.statfs().used_blocks;
+    // 跨过二级索引和三级索引的边界, 两个数据块之前全是空洞
+    let offset = indirect2_bound(BS) * BS - 100;
+    let data = pattern(200, 5);
+    assert_eq!(file.write(offset, &data).unwrap(), data.len());
+    assert_eq!(file.size().unwrap(), offset + data.len());
+    // 两个数据块; 二级索引块和它的一个一级子索引块; 三级索引块和它的一个二级、一个一级子索引块
+    assert_eq!(file.stat().unwrap().blocks, 7);
+
+    let mut buf = vec![1u8; 300];
+    assert_eq!(file.read(offset - 50, &mut buf).unwrap(), 250);
+    assert!(buf[..50].iter().all(|&b| b == 0));
+    assert_eq!(&buf[50..250], &data[..]);
+
+    // 截断到二级索引的范围内, 三级索引的块全部回收
+    file.truncate(offset + 50).unwrap();
+    assert_eq!(file.size().unwrap(), offset + 50);
+    assert_eq!(file.stat().unwrap().blocks, 3);
+    assert_eq!(fs.statfs().used_blocks - before, 3);
+    let mut buf = vec![1u8; 100];
+    assert_eq!(file.read(offset, &mut buf).unwrap(), 50);
+    assert_eq!(&buf[..50], &data[..50]);
+}
+
+#[test]
+fn file_too_large_is_rejected() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    let max = max_file_size(BS);
+    assert_eq!(file.write(max, b"x"), Err(FsError::FileTooLarge));
+    assert_eq!(file.write(usize::MAX, b"x"), Err(FsError::FileTooLarge));
+    assert_eq!(file.truncate(max + 1), Err(FsError::FileTooLarge));
+    assert_eq!(file.size().unwrap(), 0);
+    assert_eq!(file.stat().unwrap().blocks, 0);
+
+    // 恰好写到上限是允许的
+    file.write(max - 1, b"x").unwrap();
+    assert_eq!(file.size().unwrap(), max);
+    let mut byte = [0u8];
+    assert_eq!(file.read(max - 1, &mut byte).unwrap(), 1);
+    assert_eq!(&byte, b"x");
+
+    // 4K 的块时三级索引超过了 4 GiB, 上限是 u32::MAX
+    assert_eq!(max_file_size(4096), u32::MAX as usize);
+}
+
+#[test]
+fn corrupted_inode_is_reported() {
+    let (disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    file.write(0, b"data").unwrap();
+    let inode_id = file.inode_id();
+    let (block_id, offset) = file.inode_info();
+    drop((root, file, fs));
+
+    // 绕过块缓存直接修改磁盘上的索引节点, 再让块缓存重新读入
+    let mut sector = [0u8; 512];
+    disk.read_block(block_id, &mut sector).unwrap();
+    sector[offset] ^= 0xff;
+    disk.write_block(block_id, &sector).unwrap();
+    for other in 200..200 + easy_fs::BLOCK_CACHE_SIZE {
+        get_block_cache(other, BS, BlockKind::Data, Arc::clone(&disk)).unwrap();
+    }
+
+    let fs = FileSystem::open(disk).unwrap();
+    // 根目录和 f 的索引节点在同一个块上, 因此不能再通过根目录查找
+    let file = fs.get_inode(inode_id);
+    assert_eq!(file.size(), Err(FsError::Corrupted(block_id)));
+}
+
+#[test]
+fn statfs_counters_survive_reopen() {
+    let (disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let dir = root.create("d", DiskInodeType::Directory).unwrap().unwrap();
+    for i in 0..5 {
+        let file = dir
+            .create(&format!("f{}", i), DiskInodeType::File)
+            .unwrap()
+            .unwrap();
+        file.write(0, &pattern(i * 10 * BS, i as u8)).unwrap();
+    }
+    assert!(dir.unlink("f3").unwrap());
+    let before = fs.statfs();
+    assert_eq!(before.used_inodes, 1 + 1 + 4);
+    assert_eq!(before.used_blocks + before.free_blocks, before.total_blocks);
+    drop((root, dir, fs));
+
+    // 重新打开时从位图中重新统计, 应当与之前维护的计数一致
+    let fs = FileSystem::open(disk).unwrap();
+    let after = fs.statfs();
+    assert_eq!(after.used_inodes, before.used_inodes);
+    assert_eq!(after.used_blocks, before.used_blocks);
+}
+
+#[test]
+fn du_sums_subtree() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let baseline = fs.statfs().used_blocks;
+    let dir = root.create("d", DiskInodeType::Directory).unwrap().unwrap();
+    let sub = dir.create("s", DiskInodeType::Directory).unwrap().unwrap();
+    let a = dir.create("a", DiskInodeType::File).unwrap().unwrap();
+    a.write(0, &pattern(30 * BS, 1)).unwrap();
+    let b = sub.create("b", DiskInodeType::File).unwrap().unwrap();
+    b.write(BS * 100, b"sparse").unwrap();
+
+    let usage = root.du().unwrap();
+    assert_eq!(usage.files, 2);
+    assert_eq!(usage.dirs, 3);
+    assert_eq!(usage.bytes, 30 * BS + 100 * BS + 6);
+    // 整棵树占用的块数就是文件系统中已经分配的块数
+    assert_eq!(usage.blocks, fs.statfs().used_blocks);
+    assert!(usage.blocks > baseline);
+
+    let usage = sub.du().unwrap();
+    assert_eq!((usage.files, usage.dirs), (1, 1));
+    // 一个目录块, 以及 b 的一个数据块, 一个一级索引块
+    assert_eq!(usage.blocks, 3);
+    assert_eq!(a.du().unwrap().files, 1);
+}
+
+#[test]
+fn running_out_of_space_is_an_error() {
+    let (_disk, fs) = new_fs(BS, 1200);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("big", DiskInodeType::File).unwrap().unwrap();
+    let total = fs.statfs().total_blocks;
+    let data = pattern(total * BS, 5);
+    assert_eq!(file.write(0, &data), Err(FsError::NoSpace));
+    // 写入失败时已经分配的块都被回收
+    assert_eq!(file.size().unwrap(), 0);
+    assert_eq!(fs.statfs().used_blocks, 1);
+    assert_eq!(file.write(0, b"ok").unwrap(), 2);
+}
+
+#[test]
+fn failed_write_keeps_size() {
+    let (_disk, fs) = new_fs(BS, 1200);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    let head = pattern(3 * BS + 10, 1);
+    file.write(0, &head).unwrap();
+    let used = fs.statfs().used_blocks;
+
+    // 逐块追加直到占满磁盘, 每次失败的追加都不会改变 filler 的大小
+    let filler = root.create("filler", DiskInodeType::File).unwrap().unwrap();
+    let chunk = pattern(BS, 2);
+    while filler.write(filler.size().unwrap(), &chunk).is_ok() {}
+    let size = filler.size().unwrap();
+    assert_eq!(filler.write(size, &chunk), Err(FsError::NoSpace));
+    assert_eq!(filler.size().unwrap(), size);
+    let filled = fs.statfs().used_blocks;
+
+    for offset in [head.len(), 100 * BS] {
+        assert_eq!(
+            file.write(offset, &pattern(4 * BS, 4)),
+            Err(FsError::NoSpace)
+        );
+        assert_eq!(file.size().unwrap(), head.len());
+        assert_eq!(fs.statfs().used_blocks, filled);
+    }
+    let mut buf = vec![0; head.len() + BS];
+    assert_eq!(file.read(0, &mut buf).unwrap(), head.len());
+    assert_eq!(&buf[..head.len()], &head[..]);
+
+    filler.clear().unwrap();
+    assert_eq!(fs.statfs().used_blocks, used);
+}
+
+#[test]
+fn appends_skip_freed_holes() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    for i in 0..10 {
+        let name = format!("f{}", i);
+        let file = root.create(&name, DiskInodeType::File).unwrap().unwrap();
+        file.write(0, &pattern(BS, i)).unwrap();
+    }
+    // 每隔一个文件删除一个, 在已分配的块中间留下 5 个单独的空闲块
+    for i in (0..10).step_by(2) {
+        assert!(root.unlink(&format!("f{}", i)).unwrap());
+    }
+    let before = fs.free_extents().unwrap();
+    assert_eq!(before.count, 6);
+
+    // 逐块追加的文件不会去填补这些零散的空闲块, 而是连续地放在后面
+    let log = root.create("log", DiskInodeType::File).unwrap().unwrap();
+    let data = pattern(20 * BS, 42);
+    for (i, chunk) in data.chunks(BS).enumerate() {
+        log.write(i * BS, chunk).unwrap();
+    }
+    assert_eq!(log.extents().unwrap(), 1);
+    let after = fs.free_extents().unwrap();
+    assert_eq!(after.count, 6);
+    assert_eq!(after.largest, before.largest - 20);
+
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(log.read(0, &mut buf).unwrap(), data.len());
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn large_write_is_contiguous() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("big", DiskInodeType::File).unwrap().unwrap();
+    assert!(100 > DIRECT_BOUND && 100 < indirect1_bound(BS));
+    file.write(0, &pattern(100 * BS, 3)).unwrap();
+    // 一级索引块夹在直接索引和一级索引的数据块中间, 因此恰好分成两段
+    assert_eq!(file.extents().unwrap(), 2);
+    // 空闲空间仍然是完整的一段
+    assert_eq!(fs.free_extents().unwrap().count, 1);
+}
+
+#[test]
+fn xattrs_roundtrip() {
+    let (disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    let baseline = fs.statfs().used_blocks;
+    assert_eq!(file.list_xattr().unwrap(), Vec::<String>::new());
+    assert_eq!(file.get_xattr("user.label").unwrap(), None);
+
+    file.set_xattr("user.label", b"boot").unwrap();
+    file.set_xattr("user.build-id", &[0xde, 0xad, 0xbe, 0xef])
+        .unwrap();
+    file.set_xattr("user.label", b"kernel").unwrap();
+    // 第一个属性分配了扩展属性块, 之后的修改都在这个块中
+    assert_eq!(fs.statfs().used_blocks, baseline + 1);
+    assert_eq!(file.stat().unwrap().blocks, 1);
+    assert_eq!(
+        file.list_xattr().unwrap(),
+        vec!["user.label".to_string(), "user.build-id".to_string()]
+    );
+    drop((file, root, fs));
+
+    let fs = FileSystem::open(disk).unwrap();
+    let file = FileSystem::root_inode(&fs).find("f").unwrap().unwrap();
+    assert_eq!(file.get_xattr("user.label").unwrap().unwrap(), b"kernel");
+    assert_eq!(
+        file.get_xattr("user.build-id").unwrap().unwrap(),
+        [0xde, 0xad, 0xbe, 0xef]
+    );
+    assert!(file.remove_xattr("user.label").unwrap());
+    assert!(!file.remove_xattr("user.label").unwrap());
+    assert!(file.remove_xattr("user.build-id").unwrap());
+    // 最后一个属性被删除时回收扩展属性块
+    assert_eq!(fs.statfs().used_blocks, baseline);
+    assert_eq!(file.list_xattr().unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn xattrs_must_fit_in_one_block() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    file.set_xattr("user.a", &[1; 300]).unwrap();
+    assert_eq!(file.set_xattr("user.b", &[2; 300]), Err(FsError::NoSpace));
+    // 放不下的时候原有的属性不变
+    assert_eq!(file.list_xattr().unwrap(), vec!["user.a".to_string()]);
+    file.set_xattr("user.a", &[3; 100]).unwrap();
+    file.set_xattr("user.b", &[2; 300]).unwrap();
+    // 名字为空或者过长是参数错误
+    assert_eq!(file.set_xattr("", b"v"), Err(FsError::InvalidArgument));
+    let long_name = "n".repeat(XATTR_NAME_MAX + 1);
+    assert_eq!(
+        file.set_xattr(&long_name, b"v"),
+        Err(FsError::InvalidArgument)
+    );
+    assert_eq!(file.list_xattr().unwrap().len(), 2);
+
+    // 删除文件时连同扩展属性块一起回收
+    let used = fs.statfs().used_blocks;
+    drop(file);
+    assert!(root.unlink("f").unwrap());
+    assert_eq!(fs.statfs().used_blocks, used - 1);
+}
@@ -0,0 +1,148 @@
+//! 基于模型的随机测试: 对 easy-fs 和一个 HashMap<String, Vec<u8>> 执行同样的随机操作序列,
+//! 每一步都比较两者的结果, 并不时地重新打开文件系统, 确认修改都已经写回磁盘
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use common::{new_fs, pattern};
+use easy_fs::{indirect1_bound, DiskInodeType, FileSystem, Inode, DIRECT_BOUND};
+
+const BS: usize = 512;
+const NAMES: [&str; 6] = ["a", "b", "c", "long_file_name_0123456789", "e", "f"];
+
+/// xorshift64, 保证每次运行的操作序列相同
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// 随机的偏移, 偏向于直接索引/一级索引/二级索引的边界附近
+    fn offset(&mut self) -> usize {
+        let bound = [0, DIRECT_BOUND, indirect1_bound(BS)][self.below(3)] * BS;
+        (bound + self.below(4 * BS)).saturating_sub(2 * BS)
+    }
+}
+
+/// 比较读出的内容, 失败时只报告第一个不同的位置, 以免输出整个文件
+fn assert_same(actual: &[u8], expected: &[u8], name: &str) {
+    assert_eq!(actual.len(), expected.len(), "{}: length differs", name);
+    if let Some(pos) = actual.iter().zip(expected).position(|(a, b)| a != b) {
+        panic!(
+            "{}: byte {} is {:#x}, expected {:#x}",
+            name, pos, actual[pos], expected[pos]
+        );
+    }
+}
+
+fn check_all(root: &Inode, model: &HashMap<String, Vec<u8>>) {
+    let mut names = root.ls().unwrap();
+    names.sort();
+    let mut expected: Vec<&String> = model.keys().collect();
+    expected.sort();
+    assert_eq!(names.iter().collect::<Vec<_>>(), expected);
+    for (name, data) in model {
+        let file = root.find(name).unwrap().unwrap();
+        assert_eq!(file.size().unwrap(), data.len(), "{}", name);
+        let mut buf = vec![0xaa; data.len() + 10];
+        assert_eq!(file.read(0, &mut buf).unwrap(), data.len(), "{}", name);
+        assert_same(&buf[..data.len()], data, name);
+    }
+}
+
+fn run(seed: u64, steps: usize) {
+    let mut rng = Rng(seed);
+    let (disk, mut fs) = new_fs(BS, 8192);
+    let mut root: Arc<Inode> = FileSystem::root_inode(&fs);
+    let mut model: HashMap<String, Vec<u8>> = HashMap::new();
+    let baseline = fs.statfs().used_blocks;
+
+    for step in 0..steps {
+        let name = NAMES[rng.below(NAMES.len())];
+        match rng.below(10) {
+            // 写入, 文件不存在时先创建
+            0..=3 => {
+                let offset = rng.offset();
+                let data = pattern(rng.below(3 * BS), step as u8);
+                let file = match root.find(name).unwrap() {
+                    Some(file) => file,
+                    None => root.create(name, DiskInodeType::File).unwrap().unwrap(),
+                };
+                assert_eq!(file.write(offset, &data).unwrap(), data.len());
+                let content = model.entry(name.to_string()).or_default();
+                if content.len() < offset + data.len() {
+                    content.resize(offset + data.len(), 0);
+                }
+                content[offset..offset + data.len()].copy_from_slice(&data);
+            }
+            // 随机读一段
+            4 | 5 => {
+                let Some(content) = model.get(name) else {
+                    assert!(root.find(name).unwrap().is_none());
+                    continue;
+                };
+                let file = root.find(name).unwrap().unwrap();
+                let offset = rng.offset();
+                let mut buf = vec![0; rng.below(3 * BS)];
+                let len = file.read(offset, &mut buf).unwrap();
+                let expected = content.get(offset..).unwrap_or(&[]);
+                let expected = &expected[..expected.len().min(buf.len())];
+                assert_same(&buf[..len], expected, name);
+            }
+            6 => {
+                if let Some(content) = model.get_mut(name) {
+                    let new_len = rng.offset();
+                    root.find(name).unwrap().unwrap().truncate(new_len).unwrap();
+                    content.resize(new_len, 0);
+                }
+            }
+            7 => {
+                if let Some(content) = model.get_mut(name) {
+                    root.find(name).unwrap().unwrap().clear().unwrap();
+                    content.clear();
+                }
+            }
+            8 => {
+                assert_eq!(root.unlink(name).unwrap(), model.remove(name).is_some());
+            }
+            // 重新打开文件系统
+            _ => {
+                drop(root);
+                drop(fs);
+                fs = FileSystem::open(Arc::clone(&disk)).unwrap();
+                root = FileSystem::root_inode(&fs);
+                check_all(&root, &model);
+            }
+        }
+    }
+    check_all(&root, &model);
+
+    // 删除所有文件之后, 除了根目录的目录块之外不应该有块泄漏
+    for name in model.keys() {
+        assert!(root.unlink(name).unwrap());
+    }
+    let used = fs.statfs().used_blocks - baseline;
+    assert!(used <= 1, "{} blocks leaked", used);
+}
+
+#[test]
+fn model_small() {
+    for seed in 1..=8u64 {
+        run(seed.wrapping_mul(0x9e3779b97f4a7c15), 200);
+    }
+}
+
+#[test]
+fn model_long() {
+    run(0x2545f4914f6cdd1d, 2000);
+}
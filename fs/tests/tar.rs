@@ -0,0 +1,177 @@
+//! tar 归档导出和导入的测试
+
+mod common;
+
+use std::sync::Arc;
+
+use common::{new_fs, pattern};
+use easy_fs::{export_tar, import_tar, DiskInodeType, FileSystem, Inode, TarError};
+
+const BS: usize = 512;
+
+/// 将 inode 下的目录树整理为 (路径, 文件内容) 的列表, 目录的内容为 None
+fn walk(inode: &Inode, path: &str, out: &mut Vec<(String, Option<Vec<u8>>)>) {
+    for name in inode.ls().unwrap() {
+        let child = inode.find(&name).unwrap().unwrap();
+        let child_path = format!("{}/{}", path, name);
+        if child.is_dir().unwrap() {
+            out.push((child_path.clone(), None));
+            walk(&child, &child_path, out);
+        } else {
+            let mut buf = vec![0u8; child.size().unwrap()];
+            child.read(0, &mut buf).unwrap();
+            out.push((child_path, Some(buf)));
+        }
+    }
+}
+
+#[test]
+fn tar_roundtrip() {
+    let (_disk, fs) = new_fs(BS, 8192);
+    let root = FileSystem::root_inode(&fs);
+    let src = root
+        .create("src", DiskInodeType::Directory)
+        .unwrap()
+        .unwrap();
+    let big = src.create("big", DiskInodeType::File).unwrap().unwrap();
+    big.write(0, &pattern(100_000, 1)).unwrap();
+    big.set_xattr("user.build-id", &[0, 0xff, b'\n', b'='])
+        .unwrap();
+    src.create("empty", DiskInodeType::File).unwrap().unwrap();
+    src.set_xattr("user.label", b"tree").unwrap();
+    // 10 级 27 字节的目录名超出了 ustar 头部能容纳的路径长度, 需要 pax 扩展头
+    let mut dir = Arc::clone(&src);
+    for i in 0..10 {
+        let name = format!("{:0>27}", i);
+        dir = dir
+            .create(&name, DiskInodeType::Directory)
+            .unwrap()
+            .unwrap();
+    }
+    dir.create("deep", DiskInodeType::File)
+        .unwrap()
+        .unwrap()
+        .write(0, b"deep file")
+        .unwrap();
+
+    let mut archive = Vec::new();
+    export_tar(&src, "src", &mut archive).unwrap();
+    assert_eq!(archive.len() % 512, 0);
+    assert!(archive.ends_with(&[0; 1024]));
+
+    let dst = root
+        .create("dst", DiskInodeType::Directory)
+        .unwrap()
+        .unwrap();
+    let summary = import_tar(&dst, &mut &archive[..]).unwrap();
+    assert_eq!(summary.files, 3);
+    assert_eq!(summary.dirs, 11);
+    assert_eq!(summary.bytes, 100_000 + 9);
+    assert_eq!(summary.skipped, 0);
+
+    let (mut expected, mut actual) = (Vec::new(), Vec::new());
+    walk(&src, "", &mut expected);
+    walk(&dst.find("src").unwrap().unwrap(), "", &mut actual);
+    assert_eq!(actual, expected);
+    let copy = dst.find("src").unwrap().unwrap();
+    assert_eq!(copy.get_xattr("user.label").unwrap().unwrap(), b"tree");
+    assert_eq!(
+        copy.find("big")
+            .unwrap()
+            .unwrap()
+            .get_xattr("user.build-id")
+            .unwrap()
+            .unwrap(),
+        [0, 0xff, b'\n', b'=']
+    );
+
+    // 导出根目录时只包含其中的内容
+    let mut archive = Vec::new();
+    export_tar(&src, "", &mut archive).unwrap();
+    let dst = root
+        .create("flat", DiskInodeType::Directory)
+        .unwrap()
+        .unwrap();
+    import_tar(&dst, &mut &archive[..]).unwrap();
+    let mut flat = Vec::new();
+    walk(&dst, "", &mut flat);
+    assert_eq!(flat, expected);
+}
+
+#[test]
+fn tar_import_merges_and_overwrites() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let src = root
+        .create("src", DiskInodeType::Directory)
+        .unwrap()
+        .unwrap();
+    src.create("f", DiskInodeType::File)
+        .unwrap()
+        .unwrap()
+        .write(0, b"new")
+        .unwrap();
+    let mut archive = Vec::new();
+    export_tar(&src, "", &mut archive).unwrap();
+
+    // 已有的文件被覆盖, 目录中的其他文件保留
+    let dst = root
+        .create("dst", DiskInodeType::Directory)
+        .unwrap()
+        .unwrap();
+    let old = dst.create("f", DiskInodeType::File).unwrap().unwrap();
+    old.write(0, &pattern(4 * BS, 2)).unwrap();
+    dst.create("other", DiskInodeType::File).unwrap().unwrap();
+    import_tar(&dst, &mut &archive[..]).unwrap();
+    assert_eq!(old.size().unwrap(), 3);
+    assert_eq!(dst.ls().unwrap(), ["f", "other"]);
+
+    // 文件不能覆盖同名的目录
+    let conflict = root
+        .create("conflict", DiskInodeType::Directory)
+        .unwrap()
+        .unwrap();
+    conflict
+        .create("f", DiskInodeType::Directory)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        import_tar(&conflict, &mut &archive[..]),
+        Err(TarError::BadPath("f".to_string()))
+    );
+}
+
+#[test]
+fn tar_rejects_bad_archives() {
+    let (_disk, fs) = new_fs(BS, 4096);
+    let root = FileSystem::root_inode(&fs);
+    let file = root.create("f", DiskInodeType::File).unwrap().unwrap();
+    file.write(0, &pattern(2000, 3)).unwrap();
+    let mut archive = Vec::new();
+    export_tar(&file, "f", &mut archive).unwrap();
+    let dst = root
+        .create("dst", DiskInodeType::Directory)
+        .unwrap()
+        .unwrap();
+
+    // 头部损坏
+    let mut corrupted = archive.clone();
+    corrupted[0] ^= 1;
+    assert_eq!(
+        import_tar(&dst, &mut &corrupted[..]),
+        Err(TarError::Malformed)
+    );
+    // 文件内容不完整
+    assert_eq!(
+        import_tar(&dst, &mut &archive[..1000]),
+        Err(TarError::Malformed)
+    );
+    // 导出的文件必须有名字
+    assert!(matches!(
+        export_tar(&file, "", &mut Vec::new()),
+        Err(TarError::BadPath(_))
+    ));
+    // 可以省略结束标记
+    let end = archive.len() - 1024;
+    assert_eq!(import_tar(&dst, &mut &archive[..end]).unwrap().files, 1);
+}
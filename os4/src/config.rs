@@ -4,6 +4,10 @@ pub const USER_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
 
 pub const KERNEL_HEAP_SIZE: usize = 0x30_0000;
+/// ramdisk-swap 特性下交换区的页数。这个交换区放在内核堆中，只是把页面从物理页帧搬到了内核堆里，
+/// 并不能扩大可用的内存，仅用于测试页面置换，因此只占用内核堆的一小部分
+#[cfg(feature = "ramdisk-swap")]
+pub const RAMDISK_SWAP_PAGES: usize = 64;
 
 /// 物理页帧右边界，即内存最大物理地址
 pub const MEMORY_END: usize = 0x80800000;
@@ -17,6 +21,8 @@ pub const MAX_APP_NUM: usize = 16;
 /// TRAMPOLINE is the address of the trampoline page, which is used to store the trap context.
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
+/// mmap 的地址参数为 0 时，内核从这里开始寻找空闲的虚拟地址区间
+pub const MMAP_BASE: usize = 0x1000_0000;
 
 /// 常数 CLOCK_FREQ 是一个预先获取到的各平台不同的时钟频率，单位为赫兹，也就是一秒钟之内计数器的增量;
 /// 说是 CPU 主频，但实际就是 time 寄存器自增的频率而已，这个必须是一个稳定的值，真正 CPU 运行的频率不一定。
@@ -86,8 +86,18 @@ fn rust_main() -> ! {
     error!("[kernel] .bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
 
     mm::init();
+    // 内核还没有块设备驱动；测试页面置换时以内核堆中的一个小 RamDisk 作为交换设备
+    #[cfg(feature = "ramdisk-swap")]
+    mm::init_swap(
+        alloc::sync::Arc::new(easy_fs::RamDisk::new(
+            config::RAMDISK_SWAP_PAGES * config::PAGE_SIZE / easy_fs::SECTOR_SIZE,
+        )),
+        config::RAMDISK_SWAP_PAGES,
+    );
     println!("[kernel] back to world!");
+    info!("[kernel] physical frames: {}", mm::frame_stats());
     mm::remap_test();
+    mm::cow_test();
 
     trap::init();
     // loader::load_apps();
@@ -1,9 +1,14 @@
 //! Implementation of [`FrameAllocator`] which
 //! controls all the frames in the operating system.
+//!
+//! 内核默认使用伙伴系统 [`BuddyFrameAllocator`]，它可以分配按 2^order 页对齐的连续物理页帧，
+//! 在 FrameAllocatorImpl 中也可以换回简单的 [`StackFrameAllocator`]。
 
+use super::swap::evict_one;
 use super::{PhysAddr, PhysPageNum};
 use crate::config::MEMORY_END;
 use crate::sync::UnSafeCell;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt::{self, Debug, Formatter};
 use lazy_static::*;
@@ -34,15 +39,98 @@ impl Drop for FrameTracker {
     }
 }
 
+/// 2^order 个连续的物理页帧，与 FrameTracker 一样在 drop 时整块回收，
+/// 用于需要物理地址连续的场合，例如设备的 DMA 缓冲区和大页
+pub struct ContiguousFrames {
+    pub ppn: PhysPageNum,
+    pub order: usize,
+}
+impl ContiguousFrames {
+    /// 页帧的数量
+    pub fn pages(&self) -> usize {
+        1 << self.order
+    }
+}
+impl Debug for ContiguousFrames {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "ContiguousFrames:PPN={:#x},pages={}",
+            self.ppn.0,
+            self.pages()
+        ))
+    }
+}
+impl Drop for ContiguousFrames {
+    fn drop(&mut self) {
+        FRAME_ALLOCATOR
+            .exclusive_access()
+            .dealloc_contiguous(self.ppn, self.order);
+    }
+}
+
 /// 物理页帧管理器，以物理页号为单位进行物理页帧的分配和回收
 trait FrameAllocator {
     fn new() -> Self;
     fn alloc(&mut self) -> Option<PhysPageNum>;
     fn dealloc(&mut self, ppn: PhysPageNum);
+    /// 分配 2^order 个连续的物理页帧，返回第一个页帧的物理页号，它按 2^order 页对齐。
+    /// 不支持连续分配的管理器只能分配单个页帧
+    fn alloc_contiguous(&mut self, order: usize) -> Option<PhysPageNum> {
+        if order == 0 {
+            self.alloc()
+        } else {
+            None
+        }
+    }
+    /// 回收 alloc_contiguous 分配的 2^order 个连续的物理页帧
+    fn dealloc_contiguous(&mut self, ppn: PhysPageNum, order: usize) {
+        assert_eq!(order, 0, "contiguous frames are not supported");
+        self.dealloc(ppn);
+    }
+    /// 物理页帧的使用情况
+    fn stats(&self) -> FrameStats;
+}
+
+/// 物理页帧的使用情况，单位都是页
+#[derive(Copy, Clone, Debug)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    /// 目前一次最多能分配的连续页帧数
+    pub largest_free: usize,
+}
+
+impl FrameStats {
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+    /// 外部碎片率（百分比）：无法参与最大一次连续分配的空闲页帧所占的比例
+    pub fn fragmentation(&self) -> usize {
+        if self.free == 0 {
+            0
+        } else {
+            (self.free - self.largest_free) * 100 / self.free
+        }
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} frames, {} used, {} free, largest free block {} frames, {}% fragmented",
+            self.total,
+            self.used(),
+            self.free,
+            self.largest_free,
+            self.fragmentation()
+        )
+    }
 }
 
 /// an implementation for frame allocator
 pub struct StackFrameAllocator {
+    start: usize,   // 管理的第一个物理页号
     current: usize, // 空闲内存的起始物理页号
     end: usize,     // 空闲内存的结束物理页号
     // 物理页号区间 [ current , end ) 此前均从未被分配出去过，
@@ -52,6 +140,7 @@ pub struct StackFrameAllocator {
 impl StackFrameAllocator {
     /// 将自身的 [current, end) 初始化为可用物理页号区间
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        self.start = l.0;
         self.current = l.0;
         self.end = r.0;
     }
@@ -59,6 +148,7 @@ impl StackFrameAllocator {
 impl FrameAllocator for StackFrameAllocator {
     fn new() -> Self {
         Self {
+            start: 0,
             current: 0,
             end: 0,
             recycled: Vec::new(),
@@ -94,10 +184,157 @@ impl FrameAllocator for StackFrameAllocator {
         }
         self.recycled.push(ppn);
     }
+    fn stats(&self) -> FrameStats {
+        // 它只能分配单个页帧
+        let free = self.end - self.current + self.recycled.len();
+        FrameStats {
+            total: self.end - self.start,
+            free,
+            largest_free: free.min(1),
+        }
+    }
+}
+
+/// 伙伴系统中最大的块为 2^MAX_ORDER 页
+const MAX_ORDER: usize = 12;
+
+/// 伙伴系统中每个页帧的状态
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum BlockState {
+    /// 不是任何块的第一个页帧
+    Inside,
+    /// 一个 2^order 页的空闲块的第一个页帧
+    Free(u8),
+    /// 一个 2^order 页的已分配块的第一个页帧
+    Used(u8),
+}
+
+/// 伙伴系统物理页帧管理器。
+/// 物理内存被划分为 2^order 页大小、按 2^order 页对齐（按物理页号对齐，因此可以直接用作大页）的块，
+/// 每种大小的空闲块各有一个空闲链表。分配时从足够大的最小空闲块中切出所需的块，
+/// 回收时与同样空闲的伙伴块逐级合并。每个页帧的状态都记录在 states 中，
+/// 因此回收时检查重复回收、回收未分配的页帧，以及从空闲链表中摘除伙伴块都只需常数时间。
+pub struct BuddyFrameAllocator {
+    /// 管理的第一个物理页号，states 和 positions 都以 ppn - base 为下标
+    base: usize,
+    states: Vec<BlockState>,
+    /// 空闲块在它所在空闲链表中的下标
+    positions: Vec<usize>,
+    /// free_lists[order] 保存所有 2^order 页空闲块的第一个物理页号
+    free_lists: [Vec<usize>; MAX_ORDER + 1],
+    free: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// 将 [l, r) 切分成尽量大的对齐的块，全部加入空闲链表
+    pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        let (l, r) = (l.0, r.0);
+        self.base = l;
+        self.states = vec![BlockState::Inside; r - l];
+        self.positions = vec![0; r - l];
+        self.free = r - l;
+        let mut ppn = l;
+        while ppn < r {
+            let mut order = (ppn.trailing_zeros() as usize).min(MAX_ORDER);
+            while ppn + (1 << order) > r {
+                order -= 1;
+            }
+            self.push_free(ppn, order);
+            ppn += 1 << order;
+        }
+    }
+    fn push_free(&mut self, ppn: usize, order: usize) {
+        let index = ppn - self.base;
+        self.positions[index] = self.free_lists[order].len();
+        self.free_lists[order].push(ppn);
+        self.states[index] = BlockState::Free(order as u8);
+    }
+    /// 从空闲链表中摘除空闲块 ppn：用链表的最后一个元素填补它的位置
+    fn remove_free(&mut self, ppn: usize, order: usize) {
+        let index = ppn - self.base;
+        let position = self.positions[index];
+        let list = &mut self.free_lists[order];
+        list.swap_remove(position);
+        if let Some(&moved) = list.get(position) {
+            self.positions[moved - self.base] = position;
+        }
+        self.states[index] = BlockState::Inside;
+    }
+}
+
+impl FrameAllocator for BuddyFrameAllocator {
+    fn new() -> Self {
+        Self {
+            base: 0,
+            states: Vec::new(),
+            positions: Vec::new(),
+            free_lists: Default::default(),
+            free: 0,
+        }
+    }
+    fn alloc(&mut self) -> Option<PhysPageNum> {
+        self.alloc_contiguous(0)
+    }
+    fn dealloc(&mut self, ppn: PhysPageNum) {
+        self.dealloc_contiguous(ppn, 0)
+    }
+    fn alloc_contiguous(&mut self, order: usize) -> Option<PhysPageNum> {
+        let mut current = (order..=MAX_ORDER).find(|&k| !self.free_lists[k].is_empty())?;
+        let ppn = self.free_lists[current].pop().unwrap();
+        // 较大的块一分为二，后一半作为空闲块留下，直到大小合适为止
+        while current > order {
+            current -= 1;
+            self.push_free(ppn + (1 << current), current);
+        }
+        self.states[ppn - self.base] = BlockState::Used(order as u8);
+        self.free -= 1 << order;
+        Some(ppn.into())
+    }
+    fn dealloc_contiguous(&mut self, ppn: PhysPageNum, order: usize) {
+        let mut ppn = ppn.0;
+        let valid = ppn
+            .checked_sub(self.base)
+            .and_then(|index| self.states.get(index))
+            .map_or(false, |&state| state == BlockState::Used(order as u8));
+        if !valid {
+            panic!(
+                "Frame ppn={:#x} order={} has not been allocated",
+                ppn, order
+            );
+        }
+        self.states[ppn - self.base] = BlockState::Inside;
+        self.free += 1 << order;
+        // 伙伴块也空闲时合并成更大的块
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = ppn ^ (1 << order);
+            let buddy_free = buddy
+                .checked_sub(self.base)
+                .and_then(|index| self.states.get(index))
+                .map_or(false, |&state| state == BlockState::Free(order as u8));
+            if !buddy_free {
+                break;
+            }
+            self.remove_free(buddy, order);
+            ppn = ppn.min(buddy);
+            order += 1;
+        }
+        self.push_free(ppn, order);
+    }
+    fn stats(&self) -> FrameStats {
+        let largest_order = (0..=MAX_ORDER)
+            .rev()
+            .find(|&k| !self.free_lists[k].is_empty());
+        FrameStats {
+            total: self.states.len(),
+            free: self.free,
+            largest_free: largest_order.map_or(0, |k| 1 << k),
+        }
+    }
 }
 
 // 类型别名
-type FrameAllocatorImpl = StackFrameAllocator;
+type FrameAllocatorImpl = BuddyFrameAllocator;
 
 lazy_static! {
     /// frame allocator instance through lazy_static!
@@ -119,11 +356,27 @@ pub fn init_frame_allocator() {
 
 /// allocate a frame
 pub fn frame_alloc() -> Option<FrameTracker> {
-    FRAME_ALLOCATOR
-        .exclusive_access()
-        .alloc()
-        // 将分配来的物理页帧的物理页号作为参数传给 FrameTracker 的 new 方法来创建一个 FrameTracker 实例
-        .map(FrameTracker::new)
+    loop {
+        let ppn = FRAME_ALLOCATOR.exclusive_access().alloc();
+        if let Some(ppn) = ppn {
+            // 将分配来的物理页帧的物理页号作为参数传给 FrameTracker 的 new 方法来创建一个 FrameTracker 实例
+            return Some(FrameTracker::new(ppn));
+        }
+        // 物理页帧耗尽时换出一个用户页面腾出它的物理页帧，直到没有页面可以换出为止
+        if !evict_one() {
+            return None;
+        }
+    }
+}
+
+/// 分配 2^order 个连续的、按 2^order 页对齐的物理页帧并清零。
+/// 换出零散的用户页面不一定能腾出连续的物理内存，因此这里不会换出页面
+pub fn frame_alloc_contiguous(order: usize) -> Option<ContiguousFrames> {
+    let ppn = FRAME_ALLOCATOR.exclusive_access().alloc_contiguous(order)?;
+    for i in 0..1 << order {
+        PhysPageNum(ppn.0 + i).get_bytes_array().fill(0);
+    }
+    Some(ContiguousFrames { ppn, order })
 }
 
 /// deallocate a frame
@@ -131,6 +384,11 @@ fn frame_dealloc(ppn: PhysPageNum) {
     FRAME_ALLOCATOR.exclusive_access().dealloc(ppn);
 }
 
+/// 物理页帧的使用情况
+pub fn frame_stats() -> FrameStats {
+    FRAME_ALLOCATOR.exclusive_access().stats()
+}
+
 #[allow(unused)]
 /// a simple test for frame allocator
 pub fn frame_allocator_test() {
@@ -147,6 +405,15 @@ pub fn frame_allocator_test() {
         v.push(frame);
     }
     drop(v);
+    // 连续分配的页帧按块大小对齐，回收之后与伙伴合并，空闲页帧数恢复原状
+    let before = frame_stats();
+    let frames = frame_alloc_contiguous(3).unwrap();
+    info!("{:?}", frames);
+    assert_eq!(frames.ppn.0 % frames.pages(), 0);
+    assert_eq!(frame_stats().free, before.free - frames.pages());
+    drop(frames);
+    assert_eq!(frame_stats().free, before.free);
+    info!("{}", frame_stats());
     info!("frame_allocator_test passed!");
 }
 
@@ -1,7 +1,8 @@
 //! Implementation of [`MapArea`] and [`MemorySet`].
 
-use super::{frame_alloc, FrameTracker};
-use super::{PTEFlags, PageTable, PageTableEntry};
+use super::swap::Page;
+use super::{frame_alloc, frame_stats, FrameTracker};
+use super::{PTEFlags, PageSize, PageTable, PageTableEntry};
 use super::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use super::{StepByOne, VPNRange};
 use crate::config::{MEMORY_END, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE};
@@ -26,12 +27,16 @@ extern "C" {
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
-/// map type for memory set: identical or framed
+/// map type for memory set: identical, framed or lazy
 pub enum MapType {
     /// 恒等映射
     Identical,
     /// 随机映射
     Framed,
+    /// 按需分配：与 Framed 一样映射到随机的物理页帧，但每个页面在第一次被访问、触发缺页异常时才分配
+    Lazy,
+    /// 共享内存段：映射到共享内存段已经分配好的物理页帧，多个地址空间可以同时映射同一个段
+    Shared,
 }
 
 bitflags! {
@@ -55,12 +60,27 @@ pub struct MapArea {
     /// 当逻辑段采用 MapType::Framed 方式映射到物理内存的时候，
     /// data_frames 是一个保存了该逻辑段内的每个虚拟页面和它被映射到的物理页帧 FrameTracker 的一个键值对容器 BTreeMap 中，
     /// 这些物理页帧被用来存放实际内存数据而不是作为多级页表中的中间节点。
-    data_frames: BTreeMap<VirtPageNum, FrameTracker>,
+    /// fork 之后父子进程的逻辑段共享同一个 Page（写时复制），最后一个引用消失时物理页帧才被回收。
+    /// 用户页面可能被换出，此时它仍然在 data_frames 中，但页表项已经被清除了。
+    data_frames: BTreeMap<VirtPageNum, Arc<Page>>,
     map_type: MapType,
     map_perm: MapPermission,
+    /// mprotect 能够赋予这个逻辑段的最大权限：内核的恒等映射不能被修改，
+    /// 共享内存段不能超出 shmat 时给出的权限，其他用户逻辑段不受限制
+    max_perm: MapPermission,
 }
 
 impl MapArea {
+    /// 复制另一个逻辑段的范围、映射方式和权限，但不包括它的物理页帧
+    pub fn from_another(another: &MapArea) -> Self {
+        Self {
+            vpn_range: VPNRange::new(another.vpn_range.get_start(), another.vpn_range.get_end()),
+            data_frames: BTreeMap::new(),
+            map_type: another.map_type,
+            map_perm: another.map_perm,
+            max_perm: another.max_perm,
+        }
+    }
     pub fn new(
         start_va: VirtAddr,
         end_va: VirtAddr,
@@ -70,11 +90,17 @@ impl MapArea {
         // 传入的起始/终止虚拟地址会分别被下取整/上取整为虚拟页号并传入迭代器 vpn_range 中
         let start_vpn: VirtPageNum = start_va.floor();
         let end_vpn: VirtPageNum = end_va.ceil();
+        let max_perm = match map_type {
+            MapType::Identical => MapPermission::empty(),
+            MapType::Shared => map_perm,
+            MapType::Framed | MapType::Lazy => MapPermission::all(),
+        };
         Self {
             vpn_range: VPNRange::new(start_vpn, end_vpn),
             data_frames: BTreeMap::new(),
             map_type,
             map_perm,
+            max_perm,
         }
     }
     pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
@@ -89,37 +115,179 @@ impl MapArea {
             MapType::Identical => {
                 ppn = PhysPageNum(vpn.0);
             }
-            MapType::Framed => {
+            MapType::Shared => panic!("shared pages are attached by MemorySet::attach_shared"),
+            MapType::Framed | MapType::Lazy => {
                 let frame = frame_alloc().expect("out of memory");
                 ppn = frame.ppn;
-                self.data_frames.insert(vpn, frame);
+                let page = self.new_page(frame, page_table, vpn);
+                self.data_frames.insert(vpn, page);
             }
         }
         // 页表项的标志位来源于当前逻辑段的类型为 MapPermission 的统一配置，只需将其转换为 PTEFlags ；
-        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+        let pte_flags = self.pte_flags();
         page_table.map(vpn, ppn, pte_flags);
     }
-    #[allow(unused)]
     pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
-        // 当以 Framed 映射的时候，将虚拟页面被映射到的物理页帧 FrameTracker 从 data_frames 中移除，这样这个物理页帧才能立即被回收以备后续分配。
-        if self.map_type == MapType::Framed {
-            self.data_frames.remove(&vpn);
+        // 当以 Framed 映射的时候，将虚拟页面对应的 Page 从 data_frames 中移除，这样它的物理页帧和交换区页槽才能立即被回收以备后续分配。
+        // Lazy 逻辑段中从未被访问过的页面和已经被换出的页面都不在页表中。
+        let mapped = page_table
+            .translate(vpn)
+            .map_or(false, |pte| pte.is_valid());
+        if let Some(page) = self.data_frames.remove(&vpn) {
+            page.unshare(page_table.token());
+        }
+        if mapped {
+            page_table.unmap(vpn);
+        }
+    }
+    /// 包装刚分配给页面 vpn 的物理页帧：用户页面可以被换出，内核直接读写的页面不可以
+    fn new_page(&self, frame: FrameTracker, page_table: &PageTable, vpn: VirtPageNum) -> Arc<Page> {
+        if self.map_perm.contains(MapPermission::U) {
+            Page::new_swappable(frame, page_table.token(), vpn)
+        } else {
+            Page::new(frame)
         }
-        page_table.unmap(vpn);
+    }
+    /// 为 Lazy 逻辑段中第一次被访问的页面 vpn 分配物理页帧并建立映射，物理内存不足时返回 false
+    pub fn fault_in(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> bool {
+        assert_eq!(self.map_type, MapType::Lazy);
+        match frame_alloc() {
+            Some(frame) => {
+                let ppn = frame.ppn;
+                let page = self.new_page(frame, page_table, vpn);
+                self.data_frames.insert(vpn, page);
+                // 置上 A 位，刚分配的页面不会马上被换出
+                let pte_flags = self.pte_flags() | PTEFlags::A;
+                page_table.map(vpn, ppn, pte_flags);
+                true
+            }
+            None => false,
+        }
+    }
+    /// 第一次写入与其他地址空间共享的页面 vpn 时，为它复制一个私有的物理页帧并恢复写权限；
+    /// 如果其他地址空间已经不再共享这个页面，直接恢复写权限即可。页面必须已经在内存中，物理内存不足时返回 false
+    pub fn copy_on_write(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> bool {
+        let token = page_table.token();
+        let page = self.data_frames.get_mut(&vpn).unwrap();
+        let mut ppn = match page.swap_in() {
+            Some(ppn) => ppn,
+            None => return false,
+        };
+        if Arc::strong_count(page) > 1 {
+            // 共享的页面不会被换出，分配副本时 ppn 仍然有效
+            let copy = match frame_alloc() {
+                Some(copy) => copy,
+                None => return false,
+            };
+            copy.ppn
+                .get_bytes_array()
+                .copy_from_slice(ppn.get_bytes_array());
+            ppn = copy.ppn;
+            // 替换掉之后原来的页面少了一个引用，由剩下的共享者继续使用
+            page.unshare(token);
+            *page = Page::new_swappable(copy, token, vpn);
+        } else {
+            // 不再共享的页面又可以被换出了
+            page.set_owner((token, vpn));
+        }
+        // 调用者马上就要写入这个页面，同时置上 D 位：交换区中可能还有它被写入之前的旧副本
+        let pte_flags = self.pte_flags() | PTEFlags::A | PTEFlags::D;
+        page_table.remap(vpn, ppn, pte_flags);
+        true
     }
     /// map 将当前逻辑段 (到物理内存的映射) 加入到 (传入的该逻辑段所属的地址空间的) 多级页表中。
+    /// Lazy 逻辑段此时什么也不做，它的页面在缺页异常时由 fault_in 逐个映射。
+    /// Shared 逻辑段映射的是共享内存段中已有的物理页帧。
     pub fn map(&mut self, page_table: &mut PageTable) {
-        for vpn in self.vpn_range {
-            self.map_one(page_table, vpn);
+        match self.map_type {
+            MapType::Lazy => {}
+            MapType::Shared => {
+                let pte_flags = self.pte_flags();
+                for (&vpn, page) in self.data_frames.iter() {
+                    page_table.map(vpn, page.ppn(), pte_flags);
+                }
+            }
+            MapType::Identical => {
+                // 恒等映射尽量使用大页：按大页对齐、并且剩下的长度足够一个大页的部分用一个页表项映射，
+                // 这样可以省下页表节点，也让快表能覆盖更多的内存
+                let pte_flags = self.pte_flags();
+                let end = self.vpn_range.get_end().0;
+                let mut vpn = self.vpn_range.get_start().0;
+                while vpn < end {
+                    let size = [PageSize::Size1G, PageSize::Size2M]
+                        .iter()
+                        .copied()
+                        .find(|size| vpn % size.pages() == 0 && end - vpn >= size.pages())
+                        .unwrap_or(PageSize::Size4K);
+                    page_table.map_large(VirtPageNum(vpn), PhysPageNum(vpn), pte_flags, size);
+                    vpn += size.pages();
+                }
+            }
+            MapType::Framed => {
+                for vpn in self.vpn_range {
+                    self.map_one(page_table, vpn);
+                }
+            }
         }
     }
-    #[allow(unused)]
     /// unmap 将当前逻辑段 (到物理内存的映射) 从 (传入的该逻辑段所属的地址空间的) 多级页表中删除。
     pub fn unmap(&mut self, page_table: &mut PageTable) {
         for vpn in self.vpn_range {
             self.unmap_one(page_table, vpn);
         }
     }
+    /// 将逻辑段在虚拟页号 vpn 处一分为二：自身保留 [start, vpn)，返回 [vpn, end) 的部分，
+    /// 后一部分已经映射的物理页帧也随之移交给返回的逻辑段。页表不需要任何改动。
+    pub fn split_off(&mut self, vpn: VirtPageNum) -> Self {
+        let start = self.vpn_range.get_start();
+        let end = self.vpn_range.get_end();
+        assert!(start <= vpn && vpn <= end, "split {:?} out of area", vpn);
+        self.vpn_range = VPNRange::new(start, vpn);
+        Self {
+            vpn_range: VPNRange::new(vpn, end),
+            data_frames: self.data_frames.split_off(&vpn),
+            map_type: self.map_type,
+            map_perm: self.map_perm,
+            max_perm: self.max_perm,
+        }
+    }
+    /// 将逻辑段的权限改为 perm，并改写其中已经映射的页面的页表项；
+    /// 尚未分配和已经被换出的页面在建立映射时自然会使用新的权限。
+    pub fn set_perm(&mut self, page_table: &mut PageTable, perm: MapPermission) {
+        self.map_perm = perm;
+        let pte_flags = self.pte_flags();
+        for (&vpn, page) in self.data_frames.iter() {
+            let pte = match page_table.find_pte(vpn) {
+                Some(pte) if pte.is_valid() => pte,
+                _ => continue,
+            };
+            let mut flags = pte_flags;
+            // 写时复制共享的页面仍然保持只读，第一次写入时再复制
+            if self.map_type != MapType::Shared && Arc::strong_count(page) > 1 {
+                flags.remove(PTEFlags::W);
+            }
+            // 保留 A/D 位，页面置换依赖它们
+            flags |= pte.flags() & (PTEFlags::A | PTEFlags::D);
+            *pte = PageTableEntry::new(pte.ppn(), flags | PTEFlags::V);
+        }
+    }
+    /// 逻辑段中页面的页表项标志位。
+    /// R/W/X 全为 0 的有效页表项在 Sv39 中表示指向下一级页表，因此没有任何访问权限（PROT_NONE）的逻辑段
+    /// 映射为不带 U 标志的只读页面：页面仍然留在页表中，置换算法照常使用它的 A/D 位，而用户态的任何访问都会触发缺页
+    fn pte_flags(&self) -> PTEFlags {
+        let flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+        if flags.intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X) {
+            flags
+        } else {
+            PTEFlags::R
+        }
+    }
+    /// 逻辑段与 [start, end) 重叠的页数
+    fn overlap(&self, start: VirtPageNum, end: VirtPageNum) -> usize {
+        let l = self.vpn_range.get_start().0.max(start.0);
+        let r = self.vpn_range.get_end().0.min(end.0);
+        r.saturating_sub(l)
+    }
     /// copy_data 方法将切片 data 中的数据拷贝到 当前逻辑段 对应的（实际被内核放置在的） 各物理页帧上，从而在地址空间中通过该逻辑段就能访问这些数据。
     /// data: start-aligned but maybe with shorter length.
     /// assume that all frames were cleared before
@@ -133,13 +301,20 @@ impl MapArea {
         loop {
             // 调用它的时候需要满足：切片 data 中的数据大小不超过当前逻辑段的总大小，且切片中的数据会被对齐到逻辑段的开头，然后逐页拷贝到实际的物理页帧上
             let src = &data[start..len.min(start + PAGE_SIZE)];
-            // 当确定目标切片 dst 的时候，从传入的当前逻辑段所属的地址空间的多级页表中，手动查找迭代到的虚拟页号被映射到的物理页帧
+            // 当确定目标切片 dst 的时候，从 data_frames 中找到迭代到的虚拟页号对应的页面所在的物理页帧
             // 并通过 get_bytes_array 方法获取该物理页帧的字节数组型可变引用，最后再获取它的切片用于数据拷贝。
-            let dst = &mut page_table
+            // 物理内存不足时，前面的页面可能在 map 之后就被换出了，此时先换入并重新建立映射。
+            let page = &self.data_frames[&current_vpn];
+            let ppn = page.swap_in().expect("out of memory");
+            if !page_table
                 .translate(current_vpn)
-                .unwrap()
-                .ppn()
-                .get_bytes_array()[..src.len()];
+                .map_or(false, |pte| pte.is_valid())
+            {
+                // 交换区中的旧副本已经过时，置上 D 位让它再次换出时重新写入
+                let pte_flags = self.pte_flags() | PTEFlags::D;
+                page_table.map(current_vpn, ppn, pte_flags);
+            }
+            let dst = &mut ppn.get_bytes_array()[..src.len()];
 
             dst.copy_from_slice(src);
 
@@ -154,13 +329,14 @@ impl MapArea {
 
 /// memory set structure, controls virtual-memory space.
 /// MemorySet 控制虚拟内存空间,
-/// 它包含了该地址空间的多级页表 page_table 和一个逻辑段 MapArea 的向量 areas 。
+/// 它包含了该地址空间的多级页表 page_table 和按起始虚拟页号排序的逻辑段 areas 。
 /// 这两部分合在一起构成了一个地址空间所需的所有物理页帧。
 pub struct MemorySet {
     /// PageTable 下挂着所有多级页表的节点所在的物理页帧
     page_table: PageTable,
     /// 每个 MapArea 下则挂着对应逻辑段中的数据所在的物理页帧，
-    areas: Vec<MapArea>,
+    /// 逻辑段之间互不相交，以起始虚拟页号为键，这样检查重叠和寻找空闲区间都只需 O(log n) 次比较
+    areas: BTreeMap<VirtPageNum, MapArea>,
 }
 
 impl MemorySet {
@@ -168,7 +344,7 @@ impl MemorySet {
     pub fn new_bare() -> Self {
         Self {
             page_table: PageTable::new(),
-            areas: Vec::new(),
+            areas: BTreeMap::new(),
         }
     }
     /// 内核页表的起始物理地址
@@ -192,19 +368,147 @@ impl MemorySet {
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
     }
-    /// Assume that no conflicts.
     /// 在当前地址空间插入一个 Framed 方式映射到物理内存的逻辑段。
-    /// 注意该方法的调用者要保证同一地址空间内的任意两个逻辑段不能存在交集。
+    /// 如果 [start_va, end_va) 与已有的逻辑段或者跳板页面重叠，什么也不做并返回 false。
     pub fn insert_framed_area(
         &mut self,
         start_va: VirtAddr,
         end_va: VirtAddr,
         permission: MapPermission,
-    ) {
-        self.push(
-            MapArea::new(start_va, end_va, MapType::Framed, permission),
-            None,
+    ) -> bool {
+        self.insert_area(MapArea::new(start_va, end_va, MapType::Framed, permission))
+    }
+    /// 与 insert_framed_area 相同，但逻辑段中的页面在第一次被访问时才分配物理页帧。
+    pub fn insert_lazy_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) -> bool {
+        self.insert_area(MapArea::new(start_va, end_va, MapType::Lazy, permission))
+    }
+    /// 将共享内存段的页面 pages 以 permission 权限映射到从 start_va 开始的区间，
+    /// 之后 mprotect 也不能超出这个权限。与已有的逻辑段或者跳板页面重叠时返回 false。
+    pub fn attach_shared(
+        &mut self,
+        start_va: VirtAddr,
+        pages: Vec<Arc<Page>>,
+        permission: MapPermission,
+    ) -> bool {
+        let start_vpn = start_va.floor();
+        let end_vpn = match start_vpn.0.checked_add(pages.len()) {
+            Some(end) => VirtPageNum(end),
+            None => return false,
+        };
+        let mut map_area = MapArea::new(
+            start_vpn.into(),
+            end_vpn.into(),
+            MapType::Shared,
+            permission,
         );
+        map_area.data_frames = map_area.vpn_range.into_iter().zip(pages).collect();
+        self.insert_area(map_area)
+    }
+    /// 解除从 start_va 开始的共享内存段映射，这里没有这样的映射时返回 false。
+    /// 页面仍然被共享内存段或者其他地址空间使用时物理页帧不会被回收。
+    pub fn detach_shared(&mut self, start_va: VirtAddr) -> bool {
+        let start_vpn = start_va.floor();
+        match self.areas.get(&start_vpn) {
+            Some(area) if area.map_type == MapType::Shared => {}
+            _ => return false,
+        }
+        let mut area = self.areas.remove(&start_vpn).unwrap();
+        area.unmap(&mut self.page_table);
+        true
+    }
+    fn insert_area(&mut self, map_area: MapArea) -> bool {
+        if self.overlaps(map_area.vpn_range) {
+            return false;
+        }
+        self.push(map_area, None);
+        true
+    }
+
+    /// 处理应用对 va 的缺页异常，access 为这次访问需要的权限（R、W 或 X）。
+    /// va 必须属于某个用户可访问、且允许这种访问的逻辑段，此时有三种合法的缺页：
+    /// 1. Lazy 逻辑段中的页面第一次被访问，分配物理页帧并建立映射；
+    /// 2. 页面已经被换出到交换区，换入之后重新建立映射；
+    /// 3. 写入 fork 之后写时复制的页面：逻辑段可写，页表项却是只读的，复制一份物理页帧。
+    ///
+    /// 处理成功返回 true；否则返回 false，由调用者结束该应用。
+    pub fn handle_page_fault(&mut self, va: VirtAddr, access: MapPermission) -> bool {
+        let vpn = va.floor();
+        let page_table = &mut self.page_table;
+        let area = match self.areas.range_mut(..=vpn).next_back() {
+            Some((_, area))
+                if area.vpn_range.get_end() > vpn
+                    && area.map_perm.contains(access | MapPermission::U) =>
+            {
+                area
+            }
+            _ => return false,
+        };
+        if !area.data_frames.contains_key(&vpn) {
+            return area.map_type == MapType::Lazy && area.fault_in(page_table, vpn);
+        }
+        match page_table.translate(vpn).filter(|pte| pte.is_valid()) {
+            Some(pte) => {
+                if access != MapPermission::W || pte.writable() {
+                    return false;
+                }
+            }
+            None => {
+                let page = &area.data_frames[&vpn];
+                let ppn = match page.swap_in() {
+                    Some(ppn) => ppn,
+                    None => return false,
+                };
+                // 仍然与其他地址空间共享的页面只读映射，由写时复制处理写入
+                let shared = Arc::strong_count(page) > 1;
+                let mut pte_flags = area.pte_flags() | PTEFlags::A;
+                if shared {
+                    pte_flags.remove(PTEFlags::W);
+                } else {
+                    page.set_owner((page_table.token(), vpn));
+                }
+                page_table.map(vpn, ppn, pte_flags);
+                if access != MapPermission::W || !shared {
+                    return true;
+                }
+            }
+        }
+        area.copy_on_write(page_table, vpn)
+    }
+
+    /// 内核代替应用访问 [start_va, end_va) 之前调用，为其中尚未分配的页面分配物理页帧，
+    /// 这样之后才能通过页表找到它们；内核通过恒等映射直接写物理页帧，不会触发写时复制，
+    /// 因此要写入的共享页面也在这里提前复制。区间中有不属于用户逻辑段的页面、不允许 access 访问，
+    /// 或者物理内存不足时返回 false。
+    pub fn populate(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        access: MapPermission,
+    ) -> bool {
+        let flags = PTEFlags::from_bits((access | MapPermission::U).bits).unwrap();
+        // 内核访问页面不会设置页表项的 A/D 位，在这里代为设置：
+        // 置换算法会给这些页面第二次机会，被内核写过的页面换出时也不会丢失内容
+        let mut accessed = PTEFlags::A;
+        if access == MapPermission::W {
+            accessed |= PTEFlags::D;
+        }
+        for vpn in VPNRange::new(start_va.floor(), end_va.ceil()) {
+            // 页表项已经允许这种访问时不会发生缺页
+            let ready = self
+                .translate(vpn)
+                .map_or(false, |pte| pte.is_valid() && pte.flags().contains(flags));
+            if !ready && !self.handle_page_fault(vpn.into(), access) {
+                return false;
+            }
+            let pte = self.page_table.find_pte(vpn).unwrap();
+            pte.bits |= accessed.bits() as usize;
+        }
+        true
     }
 
     // lab2
@@ -212,26 +516,204 @@ impl MemorySet {
         self.translate(vpn).is_some()
     }
 
-    /// 在当前地址空间插入一个新的逻辑段 map_area
+    /// 区间 vpn_range 是否与已有的逻辑段或者跳板页面重叠
+    pub fn overlaps(&self, vpn_range: VPNRange) -> bool {
+        let (start, end) = (vpn_range.get_start(), vpn_range.get_end());
+        if start == end {
+            return false;
+        }
+        // 跳板页面不在 areas 中，但同样不能被覆盖
+        if end > VirtAddr::from(TRAMPOLINE).floor() {
+            return true;
+        }
+        // 逻辑段互不相交，起始页号小于 end 的逻辑段中最靠后的一个结束得也最晚，只需检查它
+        self.areas
+            .range(..end)
+            .next_back()
+            .map_or(false, |(_, area)| area.vpn_range.get_end() > start)
+    }
+
+    /// 从 from 开始寻找第一段连续 pages 页都没有被映射的区间，返回它的起始虚拟页号
+    pub fn find_free_area(&self, from: VirtPageNum, pages: usize) -> Option<VirtPageNum> {
+        let mut start = from;
+        // 从包含 from 的逻辑段（如果有的话）开始，依次跳过放不下的空隙
+        let first = self
+            .areas
+            .range(..=from)
+            .next_back()
+            .map_or(from, |(&vpn, _)| vpn);
+        for area in self.areas.range(first..).map(|(_, area)| area) {
+            if area.vpn_range.get_end() <= start {
+                continue;
+            }
+            if area.vpn_range.get_start().0 >= start.0.checked_add(pages)? {
+                break;
+            }
+            start = area.vpn_range.get_end();
+        }
+        let end = start.0.checked_add(pages)?;
+        if end <= VirtAddr::from(TRAMPOLINE).floor().0 {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// 在当前地址空间插入一个新的逻辑段 map_area，调用者要保证它不与已有的逻辑段重叠
     fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
+        // 空的逻辑段不占用任何页面，也不应该占用 areas 中的键
+        if map_area.vpn_range.get_start() == map_area.vpn_range.get_end() {
+            return;
+        }
+        assert!(
+            !self.overlaps(map_area.vpn_range),
+            "area [{:?}, {:?}) overlaps an existing one",
+            map_area.vpn_range.get_start(),
+            map_area.vpn_range.get_end()
+        );
         map_area.map(&mut self.page_table);
         if let Some(data) = data {
             map_area.copy_data(&mut self.page_table, data);
         }
-        self.areas.push(map_area);
+        self.areas.insert(map_area.vpn_range.get_start(), map_area);
     }
 
-    pub fn remove_framed_area(&mut self, start_va: VirtAddr, end_va: VirtAddr) {
-        self.remove(start_va, end_va);
+    /// 取消 [start_va, end_va) 的映射并回收其中的物理页帧。
+    /// 区间中的每一页都必须属于某个用户可访问的逻辑段，否则（包括 end_va 小于 start_va 时）什么也不做并返回 false。
+    pub fn remove_framed_area(&mut self, start_va: VirtAddr, end_va: VirtAddr) -> bool {
+        if end_va < start_va {
+            return false;
+        }
+        self.remove(start_va.floor(), end_va.ceil())
     }
 
-    fn remove(&mut self, start_va: VirtAddr, end_va: VirtAddr) {
-        let start_vpn = start_va.floor();
-        let end_vpn = end_va.ceil();
-        let vpn_range: VPNRange = VPNRange::new(start_vpn, end_vpn);
-        for vpn in vpn_range {
-            self.page_table.unmap(vpn);
+    /// 与区间 [start_vpn, end_vpn) 重叠的逻辑段会被删除、缩短或者从中间分成两段，
+    /// 被删除部分的 FrameTracker 随之 drop，物理页帧立即归还给物理页帧管理器。
+    fn remove(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        let starts = match self.isolate(start_vpn, end_vpn) {
+            Some(starts) => starts,
+            None => return false,
+        };
+        for vpn in starts {
+            let mut area = self.areas.remove(&vpn).unwrap();
+            area.unmap(&mut self.page_table);
         }
+        true
+    }
+
+    /// 将从 heap_bottom 开始的堆从 [heap_bottom, old_brk) 调整为 [heap_bottom, new_brk)。
+    /// 堆按页增长和收缩，新增的页面在第一次访问时才分配；与其他逻辑段重叠或者要释放的页面已经被取消映射时返回 false。
+    pub fn set_brk(&mut self, heap_bottom: VirtAddr, old_brk: VirtAddr, new_brk: VirtAddr) -> bool {
+        let (old_end, new_end) = (old_brk.ceil(), new_brk.ceil());
+        if new_end <= old_end {
+            return new_end == old_end || self.remove(new_end, old_end);
+        }
+        if self.overlaps(VPNRange::new(old_end, new_end)) {
+            return false;
+        }
+        let heap_perm = MapPermission::R | MapPermission::W | MapPermission::U;
+        // 直接延长堆顶的逻辑段，避免每次 sbrk 都新建一个逻辑段；堆底之下紧挨着的 ELF 数据段不能被延长
+        match self.areas.range_mut(..old_end).next_back() {
+            Some((_, area))
+                if old_end > heap_bottom.floor()
+                    && area.vpn_range.get_end() == old_end
+                    && area.map_type == MapType::Lazy
+                    && area.map_perm == heap_perm =>
+            {
+                area.vpn_range = VPNRange::new(area.vpn_range.get_start(), new_end);
+            }
+            _ => self.push(
+                MapArea::new(old_end.into(), new_end.into(), MapType::Lazy, heap_perm),
+                None,
+            ),
+        }
+        true
+    }
+
+    /// 将 [start_va, end_va) 的访问权限改为 permission。区间中的每一页都必须属于某个用户可访问的逻辑段，
+    /// 并且 permission 不能超出这些逻辑段的类型所允许的权限，否则什么也不做并返回 false。
+    pub fn protect(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) -> bool {
+        let (start_vpn, end_vpn) = (start_va.floor(), end_va.ceil());
+        if self
+            .overlapping(start_vpn, end_vpn)
+            .any(|area| !area.max_perm.contains(permission))
+        {
+            return false;
+        }
+        let starts = match self.isolate(start_vpn, end_vpn) {
+            Some(starts) => starts,
+            None => return false,
+        };
+        for vpn in starts {
+            let area = self.areas.get_mut(&vpn).unwrap();
+            area.set_perm(&mut self.page_table, permission);
+        }
+        // 快表中可能还缓存着旧的权限。返回用户态时切换 satp 也会刷新快表，这里不依赖这一点
+        unsafe {
+            core::arch::asm!("sfence.vma");
+        }
+        true
+    }
+
+    /// 与区间 [start_vpn, end_vpn) 重叠的逻辑段，按起始页号从大到小排列
+    fn overlapping(
+        &self,
+        start_vpn: VirtPageNum,
+        end_vpn: VirtPageNum,
+    ) -> impl Iterator<Item = &MapArea> {
+        // 与区间重叠的逻辑段是起始页号小于 end_vpn 的逻辑段中的最后几个
+        self.areas
+            .range(..end_vpn)
+            .rev()
+            .map(|(_, area)| area)
+            .take_while(move |area| area.vpn_range.get_end() > start_vpn)
+    }
+
+    /// 在 start_vpn 和 end_vpn 处切开跨越它们的逻辑段，返回切分之后恰好落在区间内的各逻辑段的起始页号。
+    /// 区间中有不属于用户可访问逻辑段的页面，或者 end_vpn 小于 start_vpn 时什么也不做并返回 None。
+    fn isolate(
+        &mut self,
+        start_vpn: VirtPageNum,
+        end_vpn: VirtPageNum,
+    ) -> Option<Vec<VirtPageNum>> {
+        if end_vpn < start_vpn {
+            return None;
+        }
+        // 逻辑段之间互不相交，因此只需比较重叠的页数就能知道区间是否被完整地映射。
+        // 不带 U 标志的逻辑段（如 Trap 上下文）不允许被用户程序修改。
+        let mapped: usize = self
+            .overlapping(start_vpn, end_vpn)
+            .filter(|area| area.map_perm.contains(MapPermission::U))
+            .map(|area| area.overlap(start_vpn, end_vpn))
+            .sum();
+        if mapped != end_vpn.0 - start_vpn.0 {
+            return None;
+        }
+        let starts: Vec<VirtPageNum> = self
+            .overlapping(start_vpn, end_vpn)
+            .map(|area| area.vpn_range.get_start())
+            .collect();
+        let mut isolated = Vec::with_capacity(starts.len());
+        for vpn in starts {
+            let mut area = self.areas.remove(&vpn).unwrap();
+            // 逻辑段在区间之前和之后的部分各自成为新的逻辑段
+            if area.vpn_range.get_start() < start_vpn {
+                let rest = area.split_off(start_vpn);
+                self.areas.insert(vpn, area);
+                area = rest;
+            }
+            if end_vpn < area.vpn_range.get_end() {
+                self.areas.insert(end_vpn, area.split_off(end_vpn));
+            }
+            isolated.push(area.vpn_range.get_start());
+            self.areas.insert(area.vpn_range.get_start(), area);
+        }
+        Some(isolated)
     }
     /// Mention that trampoline is not collected by areas.
     /// 将内核的 trampoline 代码段映射到虚拟地址 TRAMPOLINE 上.
@@ -332,23 +814,28 @@ impl MemorySet {
         memory_set
     }
     /// Include sections in elf and trampoline and TrapContext and user stack,
-    /// also returns user_sp and entry point.
+    /// also returns user_sp, heap bottom and entry point.
     /// from_elf 分析应用的 ELF 文件格式的内容，解析出各数据段并生成对应的地址空间。
-    /// 返回应用地址空间 memory_set 、用户栈虚拟地址 user_stack_top 以及从解析 ELF 得到的该应用入口点地址，它们将被我们用来创建应用的任务控制块。
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
+    /// 返回应用地址空间 memory_set 、用户栈虚拟地址 user_stack_top 、堆底 heap_bottom 以及从解析 ELF 得到的该应用入口点地址，
+    /// 它们将被我们用来创建应用的任务控制块。
+    /// ELF 文件不合法（无法解析、LOAD 段相互重叠或者延伸到用户栈之上）时返回 None，由调用者拒绝加载这个应用。
+    pub fn from_elf(elf_data: &[u8]) -> Option<(Self, usize, usize, usize)> {
         let mut memory_set = Self::new_bare();
 
         // map_trampoline 会将内核的 trampoline 代码段映射到内核地址空间的最高处
         memory_set.map_trampoline();
 
         // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
+        let elf = xmas_elf::ElfFile::new(elf_data).ok()?;
 
         let elf_header = elf.header;
 
         let magic = elf_header.pt1.magic;
         // 检查 elf 文件是否为正确的 elf 文件 (magic number: see https://en.wikipedia.org/wiki/Executable_and_Linkable_Format)
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
+        if magic != [0x7f, 0x45, 0x4c, 0x46] {
+            error!("invalid elf: bad magic {:x?}", magic);
+            return None;
+        }
 
         // 获取 program header 的数量
         let ph_count = elf_header.pt2.ph_count();
@@ -356,14 +843,15 @@ impl MemorySet {
         let mut max_end_vpn = VirtPageNum(0);
 
         for i in 0..ph_count {
-            let ph = elf.program_header(i).unwrap();
+            let ph = elf.program_header(i).ok()?;
 
             // program header 的类型是 LOAD ，这表明它有被内核加载的必要
             // 此时不必理会其他类型的 program header
-            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
+            if ph.get_type().ok()? == xmas_elf::program::Type::Load {
                 // 通过 ph.virtual_addr() 和 ph.mem_size() 来计算这一区域在应用地址空间中的位置
                 let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
-                let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
+                let end_va: VirtAddr =
+                    (ph.virtual_addr().checked_add(ph.mem_size())? as usize).into();
 
                 let mut map_perm = MapPermission::U;
 
@@ -381,32 +869,57 @@ impl MemorySet {
                 }
 
                 let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
+                if memory_set.overlaps(map_area.vpn_range) {
+                    error!("invalid elf: overlapping LOAD segments at {:?}", start_va);
+                    return None;
+                }
+
+                // 当前 program header 数据被存放的位置可以通过 ph.offset() 和 ph.file_size() 来找到
+                // 这里不使用 ph.mem_size 而是 ph.file_size 的原因：
+                // 当存在一部分零初始化的时候， ph.file_size() 将会小于 ph.mem_size() ，因为这些零出于缩减可执行文件大小的原因不应该实际出现在 ELF 数据中
+                if ph.file_size() > ph.mem_size() {
+                    error!(
+                        "invalid elf: LOAD segment at {:?} has file size larger than memory size",
+                        start_va
+                    );
+                    return None;
+                }
+                let data = elf
+                    .input
+                    .get(ph.offset() as usize..ph.offset().checked_add(ph.file_size())? as usize)?;
 
-                max_end_vpn = map_area.vpn_range.get_end();
+                // LOAD 段不一定按地址顺序排列，堆要从所有段中最靠后的结尾开始
+                max_end_vpn = max_end_vpn.max(map_area.vpn_range.get_end());
 
-                memory_set.push(
-                    map_area,
-                    // 当前 program header 数据被存放的位置可以通过 ph.offset() 和 ph.file_size() 来找到
-                    // 这里不使用 ph.mem_size 而是 ph.file_size 的原因：
-                    // 当存在一部分零初始化的时候， ph.file_size() 将会小于 ph.mem_size() ，因为这些零出于缩减可执行文件大小的原因不应该实际出现在 ELF 数据中
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
+                memory_set.push(map_area, Some(data));
             }
         }
-        // map user stack with U flags
-        // 在前面加载各个 program header 的时候，我们就已经维护了 max_end_vpn 记录目前涉及到的最大的虚拟页号，只需紧接着在它上面再放置一个保护页面和用户栈即可。
+        // 在前面加载各个 program header 的时候，我们就已经维护了 max_end_vpn 记录目前涉及到的最大的虚拟页号。
+        // 堆从所有 LOAD 段中最靠后的结尾开始，初始为空，由 sbrk 按页向上扩展和收缩 (see MemorySet::set_brk)
         // vpn -> va -> usize
         let max_end_va: VirtAddr = max_end_vpn.into();
-        let mut user_stack_bottom: usize = max_end_va.into();
+        let heap_bottom: usize = max_end_va.into();
 
-        // guard page
-        user_stack_bottom += PAGE_SIZE;
-        let user_stack_top = user_stack_bottom + USER_STACK_SIZE;
+        // map user stack with U flags
+        // 为了给堆留出增长的空间，用户栈放在 Trap 上下文之下，中间隔着一个保护页面
+        let user_stack_top = TRAP_CONTEXT - PAGE_SIZE;
+        let user_stack_bottom = user_stack_top - USER_STACK_SIZE;
+        // 堆的第一个页面不能落在任何 LOAD 段中，ELF 映像也不能延伸到用户栈及其之上的区域
+        if memory_set.overlaps(VPNRange::new(max_end_vpn, VirtPageNum(max_end_vpn.0 + 1)))
+            || heap_bottom > user_stack_bottom
+        {
+            error!(
+                "invalid elf: image ends at {:#x}, overlapping the heap or the user stack",
+                heap_bottom
+            );
+            return None;
+        }
+        // 用户栈按需分配，应用用到多深就分配多少页
         memory_set.push(
             MapArea::new(
                 user_stack_bottom.into(),
                 user_stack_top.into(),
-                MapType::Framed,
+                MapType::Lazy,
                 MapPermission::R | MapPermission::W | MapPermission::U,
             ),
             None,
@@ -424,12 +937,78 @@ impl MemorySet {
             None,
         );
 
-        // 返回应用地址空间 memory_set 、用户栈虚拟地址 user_stack_top 以及从解析 ELF 得到的该应用入口点地址，它们将被我们用来创建应用的任务控制块。
-        (
+        // 返回应用地址空间 memory_set 、用户栈虚拟地址 user_stack_top 、堆底 heap_bottom 以及从解析 ELF 得到的该应用入口点地址，它们将被我们用来创建应用的任务控制块。
+        Some((
             memory_set,
             user_stack_top,
+            heap_bottom,
             elf.header.pt2.entry_point() as usize,
-        )
+        ))
+    }
+    /// 为 fork 复制用户地址空间 user_space。
+    /// 用户逻辑段的物理页帧不会立即复制，而是由父子进程共享：可写页面在双方的页表中都改为只读，
+    /// 哪一方先写入，就在缺页异常中为它复制一份 (see MapArea::copy_on_write)，
+    /// 这样 fork 之后紧接着 exec 的进程几乎不需要复制任何页面。
+    /// 不带 U 标志的逻辑段（Trap 上下文）由内核直接读写，不经过写时复制，因此立即复制。
+    /// 共享的页面同时出现在两个页表中，在恢复独占之前不能被换出；已经被换出的页面双方都不映射，由先访问的一方换入。
+    pub fn from_existed_user(user_space: &mut MemorySet) -> MemorySet {
+        let mut memory_set = Self::new_bare();
+        memory_set.map_trampoline();
+        let token = memory_set.page_table.token();
+        for area in user_space.areas.values() {
+            let mut new_area = MapArea::from_another(area);
+            if area.map_type == MapType::Shared {
+                // 共享内存段在子进程中仍然共享，不需要写时复制
+                new_area.data_frames = area.data_frames.clone();
+                memory_set.push(new_area, None);
+                continue;
+            }
+            if !area.map_perm.contains(MapPermission::U) {
+                memory_set.push(new_area, None);
+                for vpn in area.vpn_range {
+                    let src_ppn = user_space.page_table.translate(vpn).unwrap().ppn();
+                    let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
+                    dst_ppn
+                        .get_bytes_array()
+                        .copy_from_slice(src_ppn.get_bytes_array());
+                }
+                continue;
+            }
+            let mut pte_flags = area.pte_flags();
+            pte_flags.remove(PTEFlags::W);
+            for (&vpn, page) in area.data_frames.iter() {
+                page.share(token, vpn);
+                new_area.data_frames.insert(vpn, Arc::clone(page));
+                // 为子进程分配页表节点时可能换出父进程尚未处理的页面，因此逐个检查
+                if let Some(pte) = user_space
+                    .page_table
+                    .translate(vpn)
+                    .filter(|pte| pte.is_valid())
+                {
+                    // 保留 A/D 位：恢复独占之后置换算法依赖它们，D 位为 1 的页面换出时不能丢弃
+                    let flags = pte_flags | (pte.flags() & (PTEFlags::A | PTEFlags::D));
+                    user_space.page_table.remap(vpn, pte.ppn(), flags);
+                    memory_set.page_table.map(vpn, pte.ppn(), flags);
+                }
+            }
+            memory_set
+                .areas
+                .insert(new_area.vpn_range.get_start(), new_area);
+        }
+        memory_set
+    }
+}
+
+impl Drop for MemorySet {
+    /// 地址空间被回收时，它与其他地址空间写时复制共享的页面少了一个共享者，
+    /// 只剩一个共享者的页面重新归它独占 (see Page::unshare)
+    fn drop(&mut self) {
+        let token = self.page_table.token();
+        for area in self.areas.values() {
+            for page in area.data_frames.values() {
+                page.unshare(token);
+            }
+        }
     }
 }
 
@@ -454,9 +1033,102 @@ pub fn remap_test() {
         .translate(mid_data.floor())
         .unwrap()
         .executable());
+    // 物理内存中按 2MiB 对齐的部分用大页映射，大页中的每个页面仍然恒等映射、可读写但不可执行
+    let huge_pages = PageSize::Size2M.pages();
+    let huge_start =
+        (PhysAddr::from(ekernel as usize).ceil().0 + huge_pages - 1) / huge_pages * huge_pages;
+    if huge_start + huge_pages <= PhysAddr::from(MEMORY_END).floor().0 {
+        let vpn = VirtPageNum(huge_start);
+        assert_eq!(
+            kernel_space.page_table.page_size(vpn),
+            Some(PageSize::Size2M)
+        );
+        for offset in [0, 1, huge_pages - 1] {
+            let pte = kernel_space
+                .page_table
+                .translate(VirtPageNum(huge_start + offset))
+                .unwrap();
+            assert_eq!(pte.ppn().0, huge_start + offset);
+            assert!(pte.writable() && !pte.executable());
+        }
+    }
+    // 物理内存末尾不足一个大页的部分仍然使用普通页面
+    let last = VirtAddr::from(MEMORY_END - PAGE_SIZE).floor();
+    assert_eq!(
+        kernel_space.page_table.translate(last).unwrap().ppn().0,
+        last.0
+    );
     info!("remap_test passed!");
 }
 
+/// 复制一个用户地址空间并分别写入，检查写时复制：复制之后父子共享只读的物理页帧，
+/// 先写入的一方得到一份副本，最后一个使用者写入时直接恢复写权限，两个地址空间都回收之后没有泄漏物理页帧
+#[allow(unused)]
+pub fn cow_test() {
+    let initial = frame_stats().used();
+    let mut parent = MemorySet::new_bare();
+    let start_va = VirtAddr::from(0x1000_0000usize);
+    let end_va = VirtAddr::from(0x1000_0000usize + 2 * PAGE_SIZE);
+    assert!(parent.insert_framed_area(
+        start_va,
+        end_va,
+        MapPermission::R | MapPermission::W | MapPermission::U
+    ));
+    let vpns = [start_va.floor(), VirtPageNum(start_va.floor().0 + 1)];
+    for (i, &vpn) in vpns.iter().enumerate() {
+        let ppn = parent.translate(vpn).unwrap().ppn();
+        ppn.get_bytes_array().fill(i as u8 + 1);
+    }
+    // 内核直接写物理页帧不会置上 D 位，这里代为设置
+    parent.page_table.find_pte(vpns[1]).unwrap().bits |= PTEFlags::D.bits() as usize;
+
+    let mut child = MemorySet::from_existed_user(&mut parent);
+    for &vpn in vpns.iter() {
+        let (p, c) = (
+            parent.translate(vpn).unwrap(),
+            child.translate(vpn).unwrap(),
+        );
+        assert_eq!(p.ppn(), c.ppn());
+        assert!(!p.writable() && !c.writable());
+    }
+    // 共享期间保留 A/D 位，页面不能被换出
+    for pte in [parent.translate(vpns[1]), child.translate(vpns[1])] {
+        assert!(pte.unwrap().flags().contains(PTEFlags::D));
+    }
+    let page = Arc::clone(&parent.areas[&vpns[0]].data_frames[&vpns[1]]);
+    assert!(page.owner().is_none());
+
+    // 子进程先写第一页：得到一份内容相同的副本，父进程的页面不受影响
+    let before = frame_stats().used();
+    assert!(child.handle_page_fault(start_va, MapPermission::W));
+    let (p, c) = (
+        parent.translate(vpns[0]).unwrap(),
+        child.translate(vpns[0]).unwrap(),
+    );
+    assert_ne!(p.ppn(), c.ppn());
+    assert!(c.writable() && !p.writable());
+    assert_eq!(frame_stats().used(), before + 1);
+    assert!(c.ppn().get_bytes_array().iter().all(|&b| b == 1));
+    c.ppn().get_bytes_array().fill(0xff);
+    assert!(p.ppn().get_bytes_array().iter().all(|&b| b == 1));
+
+    // 父进程已经是这一页唯一的使用者，写入时不再复制
+    assert!(parent.handle_page_fault(start_va, MapPermission::W));
+    let p = parent.translate(vpns[0]).unwrap();
+    assert!(p.writable());
+    assert_eq!(frame_stats().used(), before + 1);
+
+    // 子进程退出之后，第二页仍然属于父进程，并且又可以被换出了
+    drop(child);
+    let p = parent.translate(vpns[1]).unwrap();
+    assert!(p.ppn().get_bytes_array().iter().all(|&b| b == 2));
+    assert_eq!(page.owner(), Some((parent.token(), vpns[1])));
+    drop(page);
+    drop(parent);
+    assert_eq!(frame_stats().used(), initial);
+    info!("cow_test passed!");
+}
+
 // 恒等映射
 //
 // 恒等映射的作用范围: 恒等映射方式主要是用在启用多级页表之后，内核仍能够在虚存地址空间中访问一个特定的物理地址指向的物理内存。
@@ -11,14 +11,20 @@ mod frame_allocator;
 mod heap_allocator;
 mod memory_set;
 mod page_table;
+mod shm;
+mod swap;
 
 pub use address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use address::{StepByOne, VPNRange};
-pub use frame_allocator::{frame_alloc, FrameTracker};
-pub use memory_set::remap_test;
+use alloc::sync::Arc;
+use easy_fs::BlockDevice;
+pub use frame_allocator::{frame_alloc, frame_alloc_contiguous, frame_stats, FrameTracker};
+pub use frame_allocator::{ContiguousFrames, FrameStats};
+pub use memory_set::{cow_test, remap_test};
 pub use memory_set::{MapPermission, MemorySet, KERNEL_SPACE};
 pub use page_table::{translated_byte_buffer, translated_mut, PageTableEntry};
-pub use page_table::{PTEFlags, PageTable};
+pub use page_table::{PTEFlags, PageSize, PageTable};
+pub use shm::{shm_get, shm_pages, shm_remove};
 
 /// initiate heap allocator, frame allocator and kernel space
 pub fn init() {
@@ -33,3 +39,10 @@ pub fn init() {
     // lock 返回一个 MutexGuard，它是一个智能指针，生命周期结束后互斥锁就会被释放
     KERNEL_SPACE.lock().activate();
 }
+
+/// 以块设备 device 开头的 pages 个页面大小的空间作为交换区，此后物理页帧耗尽时会换出用户页面。
+/// 必须在 init 之后调用；不调用时物理页帧耗尽就是真的耗尽了
+#[allow(unused)]
+pub fn init_swap(device: Arc<dyn BlockDevice>, pages: usize) {
+    swap::init(device, pages);
+}
@@ -59,6 +59,48 @@ impl PageTableEntry {
         // self.flags().contains(PTEFlags:X)
         (self.flags() & PTEFlags::X) != PTEFlags::empty()
     }
+    /// R、W、X 不全为 0 的合法页表项是叶子节点，直接指向一个页面；否则指向下一级页表
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid()
+            && self
+                .flags()
+                .intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X)
+    }
+}
+
+/// 叶子页表项映射的页面大小。
+/// SV39 中除了第三级页表，第一、二级页表中的页表项也可以是叶子节点，分别映射 1GiB 和 2MiB 的大页
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    /// 一个页面包含多少个 4KiB 的页面，大页的虚拟页号和物理页号都必须按它对齐
+    pub const fn pages(self) -> usize {
+        match self {
+            PageSize::Size4K => 1,
+            PageSize::Size2M => 1 << 9,
+            PageSize::Size1G => 1 << 18,
+        }
+    }
+    /// 映射这种页面的页表项在第几级页表中，根节点为 0
+    const fn level(self) -> usize {
+        match self {
+            PageSize::Size4K => 2,
+            PageSize::Size2M => 1,
+            PageSize::Size1G => 0,
+        }
+    }
+    const fn from_level(level: usize) -> Self {
+        match level {
+            0 => PageSize::Size1G,
+            1 => PageSize::Size2M,
+            _ => PageSize::Size4K,
+        }
+    }
 }
 
 /// page table structure
@@ -79,15 +121,19 @@ impl PageTable {
             frames: vec![frame],
         }
     }
-    /// 通过 vpn 在多级页表中查找页表项
-    fn find_pte_or_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    /// 通过 vpn 在多级页表中查找映射 size 大小页面的页表项，途中缺少的页表节点会被创建
+    fn find_pte_or_create(
+        &mut self,
+        vpn: VirtPageNum,
+        size: PageSize,
+    ) -> Option<&mut PageTableEntry> {
         let mut idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
         let mut result: Option<&mut PageTableEntry> = None;
 
         for (i, idx) in idxs.iter_mut().enumerate() {
             let pte = &mut ppn.get_pte_array()[*idx];
-            if i == 2 {
+            if i == size.level() {
                 result = Some(pte);
                 break;
             }
@@ -96,19 +142,22 @@ impl PageTable {
                 *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
                 self.frames.push(frame);
             }
+            assert!(!pte.is_leaf(), "vpn {:?} is inside a huge page", vpn);
             ppn = pte.ppn();
         }
         result
     }
-    pub fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    /// 查找映射 vpn 的叶子页表项和它映射的页面大小，途中遇到不合法的页表项时返回 None。
+    /// 第三级页表中的页表项即使不合法也会被返回
+    fn find_leaf(&self, vpn: VirtPageNum) -> Option<(&mut PageTableEntry, PageSize)> {
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
-        let mut result: Option<&mut PageTableEntry> = None;
+        let mut result: Option<(&mut PageTableEntry, PageSize)> = None;
 
         for (i, idx) in idxs.iter().enumerate() {
             let pte = &mut ppn.get_pte_array()[*idx];
-            if i == 2 {
-                result = Some(pte);
+            if i == 2 || pte.is_leaf() {
+                result = Some((pte, PageSize::from_level(i)));
                 break;
             }
             if !pte.is_valid() {
@@ -118,14 +167,48 @@ impl PageTable {
         }
         result
     }
+    /// 查找映射 vpn 的页表项。vpn 位于大页中时返回的是整个大页的页表项，修改它会影响整个大页
+    pub fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+        self.find_leaf(vpn).map(|(pte, _)| pte)
+    }
+    /// vpn 所在页面的大小，没有映射时返回 None
+    pub fn page_size(&self, vpn: VirtPageNum) -> Option<PageSize> {
+        self.find_leaf(vpn)
+            .filter(|(pte, _)| pte.is_valid())
+            .map(|(_, size)| size)
+    }
     /// 通过 map 方法来在多级页表中插入一个键值对。注意这里将物理页号 ppn 和页表项标志位 flags 作为不同的参数传入
     pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        let pte = self.find_pte_or_create(vpn).unwrap();
+        self.map_large(vpn, ppn, flags, PageSize::Size4K);
+    }
+    /// 将从 vpn 开始的一个 size 大小的页面映射到从 ppn 开始的物理内存，vpn 和 ppn 都必须按页面大小对齐
+    pub fn map_large(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: PTEFlags,
+        size: PageSize,
+    ) {
+        assert!(
+            vpn.0 % size.pages() == 0 && ppn.0 % size.pages() == 0,
+            "{:?} page at vpn {:?} is not aligned",
+            size,
+            vpn
+        );
+        let pte = self.find_pte_or_create(vpn, size).unwrap();
 
         assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
 
         *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
     }
+    /// 修改一个已经存在的映射，例如写时复制之后换成新的物理页帧，或者修改访问权限
+    pub fn remap(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
+        let pte = self.find_pte(vpn).unwrap();
+
+        assert!(pte.is_valid(), "vpn {:?} is invalid before remapping", vpn);
+
+        *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+    }
     #[allow(unused)]
     /// 通过 unmap 方法来删除一个键值对，在调用时仅需给出作为索引的虚拟页号
     pub fn unmap(&mut self, vpn: VirtPageNum) {
@@ -144,8 +227,12 @@ impl PageTable {
     }
     /// 查找一个虚拟页号对应的页表项
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-        // 如果能够找到页表项，那么它会将页表项拷贝一份并返回，否则返回 None
-        self.find_pte(vpn).copied()
+        // 如果能够找到页表项，那么它会将页表项拷贝一份并返回，否则返回 None。
+        // 大页的页表项中是大页开头的物理页号，加上 vpn 在大页中的偏移才是 vpn 对应的物理页号
+        self.find_leaf(vpn).map(|(pte, size)| {
+            let offset = vpn.0 & (size.pages() - 1);
+            PageTableEntry::new(PhysPageNum(pte.ppn().0 + offset), pte.flags())
+        })
     }
     /// token 会按照 satp CSR 格式要求 构造一个无符号 64 位无符号整数，
     /// 使得其分页模式为 SV39 ，且将当前多级页表的根节点所在的物理页号填充进去。
@@ -0,0 +1,78 @@
+//! 共享内存段
+//!
+//! 共享内存段是一组物理页帧，由键 key 标识，可以被多个应用同时映射（attach）到各自的地址空间中，
+//! 每个映射有自己的访问权限。段和映射它的逻辑段都通过 Arc<Page> 持有这些页帧：
+//! 删除段只是让它不能再被映射，解除映射也只是减少引用，最后一个引用消失时物理页帧才被回收。
+//!
+//! 共享内存段中的页面同时出现在多个页表中，不会被换出。
+
+use super::frame_alloc;
+use super::swap::Page;
+use crate::config::PAGE_SIZE;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::*;
+use spin::Mutex;
+
+/// 键为 IPC_PRIVATE 时总是创建一个新的段，其他应用只能通过段的编号找到它
+pub const IPC_PRIVATE: usize = 0;
+
+struct ShmSegment {
+    key: usize,
+    pages: Vec<Arc<Page>>,
+}
+
+/// 所有尚未删除的共享内存段，以段的编号为键
+struct ShmTable {
+    next_id: usize,
+    segments: BTreeMap<usize, ShmSegment>,
+}
+
+lazy_static! {
+    static ref SHM_TABLE: Mutex<ShmTable> = Mutex::new(ShmTable {
+        next_id: 0,
+        segments: BTreeMap::new(),
+    });
+}
+
+/// 返回键为 key 的共享内存段的编号，不存在时创建一个 size 字节的新段。
+/// 已有的段比 size 小、size 为 0 或者物理内存不足时返回 None
+pub fn shm_get(key: usize, size: usize) -> Option<usize> {
+    let mut table = SHM_TABLE.lock();
+    if key != IPC_PRIVATE {
+        if let Some((&id, segment)) = table.segments.iter().find(|(_, seg)| seg.key == key) {
+            return if segment.pages.len() * PAGE_SIZE >= size {
+                Some(id)
+            } else {
+                None
+            };
+        }
+    }
+    let pages = size.checked_add(PAGE_SIZE - 1)? / PAGE_SIZE;
+    if pages == 0 {
+        return None;
+    }
+    // 创建时就分配全部的物理页帧（已经清零），之后映射到任何地址空间都看到同样的内容
+    let pages = (0..pages)
+        .map(|_| frame_alloc().map(Page::new))
+        .collect::<Option<Vec<_>>>()?;
+    let id = table.next_id;
+    table.next_id += 1;
+    table.segments.insert(id, ShmSegment { key, pages });
+    Some(id)
+}
+
+/// 编号为 id 的共享内存段的全部页面
+pub fn shm_pages(id: usize) -> Option<Vec<Arc<Page>>> {
+    SHM_TABLE
+        .lock()
+        .segments
+        .get(&id)
+        .map(|segment| segment.pages.clone())
+}
+
+/// 删除编号为 id 的共享内存段，已有的映射不受影响。段不存在时返回 false
+pub fn shm_remove(id: usize) -> bool {
+    SHM_TABLE.lock().segments.remove(&id).is_some()
+}
@@ -0,0 +1,262 @@
+//! 交换区与页面置换
+//!
+//! 物理页帧耗尽时，frame_alloc 调用 [`evict_one`] 按 CLOCK（二次机会）算法选出一个用户页面，
+//! 将它的内容写到交换区所在的块设备上并释放它的物理页帧；
+//! 应用再次访问这个页面时触发缺页异常，由 [`Page::swap_in`] 重新分配物理页帧并读回内容。
+//!
+//! 交换区可以是任何实现了 easy_fs::BlockDevice 的块设备，由 mm::init_swap 指定。内核目前还没有 virtio-blk 驱动，
+//! 默认没有交换区；开启 ramdisk-swap 特性时以内核堆中的一个小 RamDisk 作为交换区，仅用于测试页面置换。
+//!
+//! 只有被一个地址空间独占的用户页面才会被换出；fork 之后写时复制共享的页面同时出现在多个页表中，不参与置换。
+
+use super::VirtPageNum;
+use super::{frame_alloc, FrameTracker, PTEFlags, PageTable, PageTableEntry, PhysPageNum};
+use crate::config::PAGE_SIZE;
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use easy_fs::{BlockDevice, SECTOR_SIZE};
+use lazy_static::*;
+use spin::Mutex;
+
+/// 每个页面在交换区中占用的扇区数
+const SECTORS_PER_PAGE: usize = PAGE_SIZE / SECTOR_SIZE;
+
+/// 交换区：所在的块设备和空闲的页槽
+struct SwapSpace {
+    device: Arc<dyn BlockDevice>,
+    /// 空闲页槽的编号，第 i 个页槽占用扇区 [i * SECTORS_PER_PAGE, (i + 1) * SECTORS_PER_PAGE)
+    free: Vec<usize>,
+}
+
+lazy_static! {
+    /// 交换区，swap::init 之前为 None，此时物理页帧耗尽就是真的耗尽了
+    static ref SWAP_SPACE: Mutex<Option<SwapSpace>> = Mutex::new(None);
+    static ref CLOCK: Mutex<Clock> = Mutex::new(Clock {
+        queue: VecDeque::new(),
+        prune_at: CLOCK_PRUNE_MIN,
+    });
+}
+
+/// 时钟队列至少增长到这个长度才清理已经被释放的页面
+const CLOCK_PRUNE_MIN: usize = 64;
+
+/// CLOCK 算法的环形队列，队首就是时钟指针指向的页面
+struct Clock {
+    queue: VecDeque<Weak<Page>>,
+    /// 已经被释放的页面在指针扫过时移除；没有内存压力时指针不会移动，
+    /// 因此队列长度达到 prune_at 时也清理一次，之后 prune_at 变为剩下的页面数的两倍
+    prune_at: usize,
+}
+
+impl Clock {
+    fn push(&mut self, page: &Arc<Page>) {
+        if self.queue.len() >= self.prune_at {
+            self.queue.retain(|page| page.strong_count() > 0);
+            self.prune_at = (2 * self.queue.len()).max(CLOCK_PRUNE_MIN);
+        }
+        self.queue.push_back(Arc::downgrade(page));
+    }
+}
+
+/// 以块设备 device 开头的 pages 个页面大小的空间作为交换区
+pub fn init(device: Arc<dyn BlockDevice>, pages: usize) {
+    *SWAP_SPACE.lock() = Some(SwapSpace {
+        device,
+        free: (0..pages).rev().collect(),
+    });
+}
+
+/// 交换区中的一个页槽，drop 时归还给交换区
+struct SwapSlot(usize);
+
+impl SwapSlot {
+    fn alloc() -> Option<Self> {
+        SWAP_SPACE.lock().as_mut()?.free.pop().map(SwapSlot)
+    }
+    fn write(&self, data: &[u8]) -> bool {
+        let swap = SWAP_SPACE.lock();
+        let device = &swap.as_ref().unwrap().device;
+        data.chunks_exact(SECTOR_SIZE)
+            .enumerate()
+            .all(|(i, sector)| {
+                device
+                    .write_block(self.0 * SECTORS_PER_PAGE + i, sector)
+                    .is_ok()
+            })
+    }
+    fn read(&self, data: &mut [u8]) -> bool {
+        let swap = SWAP_SPACE.lock();
+        let device = &swap.as_ref().unwrap().device;
+        device.read_blocks(self.0 * SECTORS_PER_PAGE, data).is_ok()
+    }
+}
+
+impl Drop for SwapSlot {
+    fn drop(&mut self) {
+        if let Some(swap) = SWAP_SPACE.lock().as_mut() {
+            swap.free.push(self.0);
+        }
+    }
+}
+
+/// 逻辑段中的一个页面：在内存中时占有一个物理页帧，被换出之后内容保存在交换区的页槽中。
+/// 逻辑段通过 Arc<Page> 持有页面，fork 之后父子进程共享同一个 Page，最后一个引用消失时物理页帧和页槽才被回收。
+pub struct Page(Mutex<PageState>);
+
+struct PageState {
+    frame: Option<FrameTracker>,
+    /// 交换区中的副本。换入之后仍然保留，页面没有被写过（D 位为 0）时再次换出就不需要重新写入
+    slot: Option<SwapSlot>,
+    /// 唯一映射这个页面的地址空间（页表的 token）和虚拟页号，None 表示这个页面不能被换出
+    owner: Option<(usize, VirtPageNum)>,
+    /// fork 之后写时复制共享这个页面的各个地址空间和虚拟页号，只剩一个时它重新成为 owner
+    sharers: Vec<(usize, VirtPageNum)>,
+}
+
+impl Page {
+    /// 不会被换出的页面，例如内核直接读写的 Trap 上下文和内核栈
+    pub fn new(frame: FrameTracker) -> Arc<Self> {
+        Arc::new(Self(Mutex::new(PageState {
+            frame: Some(frame),
+            slot: None,
+            owner: None,
+            sharers: Vec::new(),
+        })))
+    }
+    /// 被 token 对应的地址空间独占地映射在 vpn 处的用户页面，物理页帧不足时可以被换出
+    pub fn new_swappable(frame: FrameTracker, token: usize, vpn: VirtPageNum) -> Arc<Self> {
+        let page = Arc::new(Self(Mutex::new(PageState {
+            frame: Some(frame),
+            slot: None,
+            owner: Some((token, vpn)),
+            sharers: Vec::new(),
+        })));
+        CLOCK.lock().push(&page);
+        page
+    }
+    /// 页面所在的物理页号，只能用于不会被换出的页面
+    pub fn ppn(&self) -> PhysPageNum {
+        self.0
+            .lock()
+            .frame
+            .as_ref()
+            .expect("page has been swapped out")
+            .ppn
+    }
+    /// 独占这个页面的地址空间和虚拟页号，None 表示这个页面不能被换出
+    pub fn owner(&self) -> Option<(usize, VirtPageNum)> {
+        self.0.lock().owner
+    }
+    /// 页面重新被 owner 独占，又可以被换出了
+    pub fn set_owner(&self, owner: (usize, VirtPageNum)) {
+        let mut state = self.0.lock();
+        state.owner = Some(owner);
+        state.sharers.clear();
+    }
+    /// fork 时 token 对应的地址空间也在 vpn 处映射了这个页面：共享期间页面不能被换出
+    pub fn share(&self, token: usize, vpn: VirtPageNum) {
+        let mut state = self.0.lock();
+        if let Some(owner) = state.owner.take() {
+            state.sharers.push(owner);
+        }
+        state.sharers.push((token, vpn));
+    }
+    /// token 对应的地址空间不再映射这个页面（写时复制、解除映射或者地址空间被回收），
+    /// 只剩一个共享者时它重新成为 owner，这样页面又可以被换出
+    pub fn unshare(&self, token: usize) {
+        let mut state = self.0.lock();
+        state.sharers.retain(|&(sharer, _)| sharer != token);
+        if state.sharers.len() == 1 {
+            state.owner = state.sharers.pop();
+        }
+    }
+    /// 确保页面在内存中并返回它的物理页号：被换出的页面重新分配物理页帧（可能因此换出别的页面）并读回内容。
+    /// 物理内存和交换区都已耗尽或者读取失败时返回 None
+    pub fn swap_in(self: &Arc<Self>) -> Option<PhysPageNum> {
+        let mut state = self.0.lock();
+        if let Some(frame) = &state.frame {
+            return Some(frame.ppn);
+        }
+        let frame = frame_alloc()?;
+        if !state
+            .slot
+            .as_ref()
+            .unwrap()
+            .read(frame.ppn.get_bytes_array())
+        {
+            return None;
+        }
+        let ppn = frame.ppn;
+        state.frame = Some(frame);
+        drop(state);
+        CLOCK.lock().push(self);
+        Some(ppn)
+    }
+}
+
+impl PageState {
+    /// 时钟指针扫过这个页面：A 位为 1 说明它最近被访问过，清除 A 位给它第二次机会；
+    /// 否则换出它，D 位为 1 或者交换区中还没有副本时先写入交换区。换出成功返回 true
+    fn try_evict(&mut self) -> bool {
+        let (token, vpn) = match (self.owner, &self.frame) {
+            (Some(owner), Some(_)) => owner,
+            _ => return false,
+        };
+        let ppn = self.frame.as_ref().unwrap().ppn;
+        let page_table = PageTable::from_token(token);
+        let pte = match page_table.find_pte(vpn) {
+            Some(pte) if pte.is_valid() && pte.ppn() == ppn => pte,
+            _ => return false,
+        };
+        let flags = pte.flags();
+        if flags.contains(PTEFlags::A) {
+            pte.bits &= !(PTEFlags::A.bits() as usize);
+            return false;
+        }
+        if flags.contains(PTEFlags::D) || self.slot.is_none() {
+            if self.slot.is_none() {
+                self.slot = SwapSlot::alloc();
+            }
+            match &self.slot {
+                Some(slot) if slot.write(ppn.get_bytes_array()) => {}
+                _ => return false,
+            }
+        }
+        // 被修改的都不是当前正在使用的页表（我们在内核地址空间中），返回用户态之前 __restore 会刷新快表
+        *pte = PageTableEntry::empty();
+        self.frame = None;
+        true
+    }
+}
+
+/// 按 CLOCK 算法换出一个用户页面并释放它的物理页帧，没有可以换出的页面时返回 false
+pub fn evict_one() -> bool {
+    if SWAP_SPACE.lock().is_none() {
+        return false;
+    }
+    let mut clock = CLOCK.lock();
+    let clock = &mut clock.queue;
+    // 每个页面至多被扫过两次：第一次清除 A 位，第二次就会被换出
+    for _ in 0..2 * clock.len() {
+        let weak = match clock.pop_front() {
+            Some(weak) => weak,
+            None => break,
+        };
+        let page = match weak.upgrade() {
+            Some(page) => page,
+            None => continue,
+        };
+        // 拿不到锁的页面正在被换入
+        let evicted = match page.0.try_lock() {
+            Some(mut state) => state.try_evict(),
+            None => false,
+        };
+        if evicted {
+            // 被换出的页面不在队列中，换入时重新加入
+            return true;
+        }
+        clock.push_back(weak);
+    }
+    false
+}
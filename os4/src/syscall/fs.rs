@@ -1,5 +1,5 @@
-use crate::mm::translated_byte_buffer;
-use crate::task::current_user_token;
+use crate::mm::{translated_byte_buffer, MapPermission};
+use crate::task::{current_user_token, populate_user_buffer};
 
 const FD_STDOUT: usize = 1;
 
@@ -10,6 +10,9 @@ const FD_STDOUT: usize = 1;
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     match fd {
         FD_STDOUT => {
+            if !populate_user_buffer(buf as usize, len, MapPermission::R) {
+                return -1;
+            }
             let buffers = translated_byte_buffer(current_user_token(), buf, len);
             for buf in buffers {
                 let s = core::str::from_utf8(buf).unwrap();
@@ -1,10 +1,10 @@
 use super::timer::TimeVal;
 use crate::config::MAX_SYSCALL_NUM;
-use crate::mm::translated_mut;
+use crate::mm::{shm_get, shm_remove, translated_mut, MapPermission};
 use crate::task::{
     current_user_token, exit_current_and_run_next, get_curr_task_running_time,
-    get_curr_task_status, get_curr_task_syscall_times, mmap, munmap, suspend_current_and_run_next,
-    TaskStatus,
+    get_curr_task_status, get_curr_task_syscall_times, mmap, mprotect, munmap,
+    populate_user_buffer, sbrk, shmat, shmdt, suspend_current_and_run_next, TaskStatus,
 };
 use crate::timer::get_time_val;
 
@@ -35,6 +35,13 @@ pub fn sys_get_time(mut ts: *mut TimeVal, _tz: usize) -> isize {
     // lab2
     // debug!("sys_get_time: ts={:x}", ts as usize);
 
+    if !populate_user_buffer(
+        ts as usize,
+        core::mem::size_of::<TimeVal>(),
+        MapPermission::W,
+    ) {
+        return -1;
+    }
     let token = current_user_token();
     ts = translated_mut(token, ts);
     unsafe { *ts = get_time_val() }
@@ -54,13 +61,14 @@ pub fn sys_set_priority(_prio: isize) -> isize {
 // len 映射字节长度，可以为 0
 // port：第 0 位表示是否可读，第 1 位表示是否可写，第 2 位表示是否可执行。其他位无效且必须为 0
 // 返回值：执行成功则返回 0，错误返回 -1
+// start 为 0 时由内核选择一段空闲的虚存，执行成功则返回它的起始地址
 // 说明：
 // 为了简单，目标虚存区间要求按页对齐，len 可直接按页向上取整，不考虑分配失败时的页回收。
 // 可能的错误：
 // start 没有按页大小对齐
 // port & !0x7 != 0 (port 其余位必须为0)
 // port & 0x7 = 0 (这样的内存无意义)
-// [start, start + len) 中存在已经被映射的页 (如何判断？)
+// [start, start + len) 中存在已经被映射的页 (用 MemorySet::overlaps 检查整个区间)
 // 物理内存不足 （如何判断？）
 // 一定要注意 mmap 是的页表项，注意 riscv 页表项的格式与 port 的区别。
 // 增加 PTE_U
@@ -79,9 +87,80 @@ pub fn sys_munmap(start: usize, len: usize) -> isize {
     munmap(start, len)
 }
 
+// syscall ID：214
+// 将程序断点（堆顶）移动 increment 字节，increment 为负数时收缩堆，被释放的整页立即回收
+// 返回值：执行成功则返回原来的程序断点，错误返回 -1
+// 可能的错误：
+// 程序断点低于堆底；扩展的堆与已有的映射重叠
+pub fn sys_sbrk(increment: isize) -> isize {
+    sbrk(increment)
+}
+
+// syscall ID：226
+// 将 [start, start + len) 虚存的访问权限改为 port，port 的含义与 mmap 相同
+// 跨越区间边界的逻辑段会被切分，区间外的部分保持原来的权限
+// 返回值：执行成功则返回 0，错误返回 -1
+// 可能的错误：
+// start 没有按页大小对齐；port 不合法；[start, start + len) 中存在未被映射的虚存；
+// port 超出了逻辑段允许的权限（例如共享内存段不能执行）
+pub fn sys_mprotect(start: usize, len: usize, port: usize) -> isize {
+    mprotect(start, len, port)
+}
+
+/// shmctl 的命令：删除共享内存段
+const IPC_RMID: usize = 0;
+
+// syscall ID：194
+// 返回键为 key 的共享内存段的编号，不存在时创建一个 size 字节的段（内容为 0）
+// key 为 IPC_PRIVATE(0) 时总是创建新的段
+// 返回值：段的编号，错误返回 -1
+// 可能的错误：
+// 已有的段小于 size；size 为 0；物理内存不足
+pub fn sys_shmget(key: usize, size: usize) -> isize {
+    match shm_get(key, size) {
+        Some(id) => id as isize,
+        None => -1,
+    }
+}
+
+// syscall ID：196
+// 将编号为 id 的共享内存段映射到 start 开始的虚存，port 的含义与 mmap 相同
+// start 为 0 时由内核选择一段空闲的虚存
+// 返回值：映射的起始地址，错误返回 -1
+// 可能的错误：
+// 段不存在；start 没有按页大小对齐；port 不合法；映射区间中存在已经被映射的页
+pub fn sys_shmat(id: usize, start: usize, port: usize) -> isize {
+    shmat(id, start, port)
+}
+
+// syscall ID：197
+// 解除 start 处的共享内存段映射，仍然被其他应用使用的物理页帧不会被回收
+// 返回值：执行成功则返回 0，错误返回 -1
+pub fn sys_shmdt(start: usize) -> isize {
+    shmdt(start)
+}
+
+// syscall ID：195
+// 目前只支持 IPC_RMID：删除共享内存段，之后不能再映射它，已有的映射不受影响
+// 返回值：执行成功则返回 0，错误返回 -1
+pub fn sys_shmctl(id: usize, cmd: usize) -> isize {
+    if cmd == IPC_RMID && shm_remove(id) {
+        0
+    } else {
+        -1
+    }
+}
+
 /// 获取当前任务的信息
 // YOUR JOB: 引入虚地址后重写 sys_task_info
 pub fn sys_task_info(mut ti: *mut TaskInfo) -> isize {
+    if !populate_user_buffer(
+        ti as usize,
+        core::mem::size_of::<TaskInfo>(),
+        MapPermission::W,
+    ) {
+        return -1;
+    }
     let token = current_user_token();
     ti = translated_mut(token, ti);
     unsafe {
@@ -17,6 +17,7 @@ mod task;
 
 use crate::config::{MAX_APP_NUM, MAX_SYSCALL_NUM};
 use crate::loader::{get_app_data, get_num_apps};
+use crate::mm::MapPermission;
 use crate::sync::UnSafeCell;
 use crate::timer::get_time_micro;
 use crate::trap::TrapContext;
@@ -54,13 +55,17 @@ lazy_static! {
         // 在全局任务管理器 TASK_MANAGER 初始化的时候，只需使用 loader 子模块提供的 get_num_app 和 get_app_data
         // 分别获取链接到内核的应用数量和每个应用的 ELF 文件格式的数据，然后依次给每个应用创建任务控制块并加入到向量中即可。
 
+        // ELF 文件不合法的应用不会被加载，也不会被调度
         let mut tasks: Vec<TaskControlBlock> = Vec::with_capacity(MAX_APP_NUM);
         for i in 0..num_apps {
-            tasks.push(TaskControlBlock::new(get_app_data(i), i));
+            match TaskControlBlock::new(get_app_data(i), i) {
+                Some(task) => tasks.push(task),
+                None => error!("[kernel] app {} is not a valid ELF, refusing to load it", i),
+            }
         }
 
         TaskManager {
-            num_apps,
+            num_apps: tasks.len(),
             inner: unsafe {
                 UnSafeCell::new(TaskManagerInner {
                     tasks,
@@ -245,6 +250,49 @@ impl TaskManager {
         let task_id = inner.current_task;
         inner.tasks[task_id].munmap(va, size)
     }
+
+    fn sbrk(&self, increment: isize) -> isize {
+        let mut inner = self.inner.exclusive_access();
+        let task_id = inner.current_task;
+        inner.tasks[task_id].sbrk(increment)
+    }
+
+    fn mprotect(&self, va: usize, size: usize, mark: usize) -> isize {
+        let mut inner = self.inner.exclusive_access();
+        let task_id = inner.current_task;
+        inner.tasks[task_id].mprotect(va, size, mark)
+    }
+
+    fn shmat(&self, id: usize, va: usize, mark: usize) -> isize {
+        let mut inner = self.inner.exclusive_access();
+        let task_id = inner.current_task;
+        inner.tasks[task_id].shmat(id, va, mark)
+    }
+
+    fn shmdt(&self, va: usize) -> isize {
+        let mut inner = self.inner.exclusive_access();
+        let task_id = inner.current_task;
+        inner.tasks[task_id].shmdt(va)
+    }
+
+    fn handle_page_fault(&self, va: usize, access: MapPermission) -> bool {
+        let mut inner = self.inner.exclusive_access();
+        let task_id = inner.current_task;
+        inner.tasks[task_id]
+            .memory_set
+            .handle_page_fault(va.into(), access)
+    }
+
+    fn populate_user_buffer(&self, ptr: usize, len: usize, access: MapPermission) -> bool {
+        let mut inner = self.inner.exclusive_access();
+        let task_id = inner.current_task;
+        match ptr.checked_add(len) {
+            Some(end) => inner.tasks[task_id]
+                .memory_set
+                .populate(ptr.into(), end.into(), access),
+            None => false,
+        }
+    }
 }
 
 /// call from rust_main,
@@ -317,3 +365,30 @@ pub fn mmap(va: usize, size: usize, mark: usize) -> isize {
 pub fn munmap(va: usize, size: usize) -> isize {
     TASK_MANAGER.munmap(va, size)
 }
+
+pub fn sbrk(increment: isize) -> isize {
+    TASK_MANAGER.sbrk(increment)
+}
+
+pub fn mprotect(va: usize, size: usize, mark: usize) -> isize {
+    TASK_MANAGER.mprotect(va, size, mark)
+}
+
+pub fn shmat(id: usize, va: usize, mark: usize) -> isize {
+    TASK_MANAGER.shmat(id, va, mark)
+}
+
+pub fn shmdt(va: usize) -> isize {
+    TASK_MANAGER.shmdt(va)
+}
+
+/// 处理当前应用对 va 的缺页异常，返回 false 表示这是一次非法访问
+pub fn handle_page_fault(va: usize, access: MapPermission) -> bool {
+    TASK_MANAGER.handle_page_fault(va, access)
+}
+
+/// 系统调用通过页表访问应用的缓冲区 [ptr, ptr + len) 之前，先为其中按需分配的页面分配物理页帧；
+/// 缓冲区不合法时返回 false
+pub fn populate_user_buffer(ptr: usize, len: usize, access: MapPermission) -> bool {
+    TASK_MANAGER.populate_user_buffer(ptr, len, access)
+}
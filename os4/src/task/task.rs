@@ -1,6 +1,8 @@
 use super::TaskContext;
-use crate::config::{kernel_stack_position, MAX_SYSCALL_NUM, TRAP_CONTEXT};
-use crate::mm::{MapPermission, MemorySet, PageTable, PhysPageNum, VirtAddr, KERNEL_SPACE};
+use crate::config::{kernel_stack_position, MAX_SYSCALL_NUM, MMAP_BASE, PAGE_SIZE, TRAP_CONTEXT};
+use crate::mm::{
+    shm_pages, MapPermission, MemorySet, PageTable, PhysPageNum, VirtAddr, KERNEL_SPACE,
+};
 use crate::trap::{trap_handler, TrapContext};
 
 pub struct TaskControlBlock {
@@ -17,9 +19,11 @@ pub struct TaskControlBlock {
     pub memory_set: MemorySet,
     // 应用地址空间次高页的 Trap 上下文被实际存放在物理页帧的物理页号 trap_cx_ppn ，它能够方便我们对于 Trap 上下文进行访问
     pub trap_cx_ppn: PhysPageNum,
-    // base_size 统计了应用数据的大小，也就是在应用地址空间中从 0x0 开始到用户栈结束一共包含多少字节(用户栈顶位置)。
-    // 它后续还应该包含用于应用动态内存分配的堆空间的大小，但目前暂不支持
+    // base_size 统计了应用数据的大小，也就是在应用地址空间中从 0x0 开始到 ELF 映像结束一共包含多少字节(按页对齐)。
     pub base_size: usize,
+    // 应用的堆紧接在 ELF 映像之后，从 heap_bottom 开始到程序断点 program_brk 为止，由 sbrk 调整
+    pub heap_bottom: usize,
+    pub program_brk: usize,
 }
 
 impl TaskControlBlock {
@@ -31,10 +35,11 @@ impl TaskControlBlock {
         self.memory_set.token()
     }
 
-    pub fn new(elf_data: &[u8], app_id: usize) -> Self {
+    /// ELF 文件不合法时返回 None (see MemorySet::from_elf)
+    pub fn new(elf_data: &[u8], app_id: usize) -> Option<Self> {
         // 解析传入的 ELF 格式数据构造应用的地址空间 memory_set 并获得其他信息
         // memory_set with elf program headers/trampoline/trap context/ user stack
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let (memory_set, user_sp, heap_bottom, entry_point) = MemorySet::from_elf(elf_data)?;
 
         // 从地址空间 memory_set 中查多级页表找到应用地址空间中的 Trap 上下文实际被放在哪个物理页帧
         let trap_cx_ppn = memory_set
@@ -47,10 +52,14 @@ impl TaskControlBlock {
         // 根据传入的应用 ID app_id 调用在 config 子模块中定义的 kernel_stack_position 找到 应用的内核栈预计放在内核地址空间 KERNEL_SPACE 中的哪个位置，
         // 并通过 insert_framed_area 实际将这个逻辑段 加入到内核地址空间中
         let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(app_id);
-        KERNEL_SPACE.lock().insert_framed_area(
-            kernel_stack_bottom.into(),
-            kernel_stack_top.into(),
-            MapPermission::R | MapPermission::W,
+        assert!(
+            KERNEL_SPACE.lock().insert_framed_area(
+                kernel_stack_bottom.into(),
+                kernel_stack_top.into(),
+                MapPermission::R | MapPermission::W,
+            ),
+            "kernel stack of app {} overlaps",
+            app_id
         );
 
         let task_control_block = Self {
@@ -70,7 +79,9 @@ impl TaskControlBlock {
 
             memory_set,
             trap_cx_ppn,
-            base_size: user_sp,
+            base_size: heap_bottom,
+            heap_bottom,
+            program_brk: heap_bottom,
         };
 
         // prepare TrapContext in user space
@@ -89,12 +100,14 @@ impl TaskControlBlock {
             trap_handler as usize,
         );
 
-        task_control_block
+        Some(task_control_block)
     }
 }
 
 // lab2
 impl TaskControlBlock {
+    /// va 为 0 时由内核从 MMAP_BASE 开始寻找一段足够大的空闲区间，成功时返回它的起始地址；
+    /// 否则映射到 va 处，成功时返回 0。
     pub fn mmap(&mut self, va: usize, size: usize, mark: usize) -> isize {
         let va_ = VirtAddr::from(va);
         if !va_.is_aligned() {
@@ -121,24 +134,41 @@ impl TaskControlBlock {
         // }
         // debug!("test pte is_some end");
 
-        let vpn = va_.floor();
-        debug!("vpn: {:?}", vpn);
-        if self.memory_set.is_vpn_mapped(vpn) {
-            // panic!("va is already mapped");
-            debug!("va is already mapped");
-            return -1;
-        }
-
         // mark 与内核定义的 MapPermission 不同
         let mark = mark << 1;
         let mark_ = MapPermission::from_bits_truncate(mark as u8) | MapPermission::U;
 
-        let start_va = va_;
-        let end_va = VirtAddr::from(va + size);
+        let pages = match size.checked_add(PAGE_SIZE - 1) {
+            Some(size) => size / PAGE_SIZE,
+            None => return -1,
+        };
+        let start_va = if va == 0 {
+            match self
+                .memory_set
+                .find_free_area(VirtAddr::from(MMAP_BASE).floor(), pages)
+            {
+                Some(vpn) => VirtAddr::from(vpn),
+                None => return -1,
+            }
+        } else {
+            va_
+        };
+        let end_va = match usize::from(start_va).checked_add(pages * PAGE_SIZE) {
+            Some(end) => VirtAddr::from(end),
+            None => return -1,
+        };
 
-        self.memory_set.insert_framed_area(start_va, end_va, mark_);
+        // 整个区间都不能与已有的映射重叠；物理页帧在第一次访问时才分配
+        if !self.memory_set.insert_lazy_area(start_va, end_va, mark_) {
+            debug!("[{:?}, {:?}) is already mapped", start_va, end_va);
+            return -1;
+        }
 
-        0
+        if va == 0 {
+            usize::from(start_va) as isize
+        } else {
+            0
+        }
     }
 
     pub fn munmap(&mut self, va: usize, size: usize) -> isize {
@@ -149,10 +179,100 @@ impl TaskControlBlock {
         }
 
         let start_va = va_;
-        let end_va = VirtAddr::from(va + size);
+        let end_va = match va.checked_add(size) {
+            Some(end) => VirtAddr::from(end),
+            None => return -1,
+        };
 
-        self.memory_set.remove_framed_area(start_va, end_va);
+        if !self.memory_set.remove_framed_area(start_va, end_va) {
+            debug!("[{:?}, {:?}) is not fully mapped", start_va, end_va);
+            return -1;
+        }
+
+        0
+    }
+
+    /// 将程序断点移动 increment 字节，成功时返回原来的程序断点。
+    /// 断点不能低于堆底，堆也不能与其他逻辑段重叠
+    pub fn sbrk(&mut self, increment: isize) -> isize {
+        let old_brk = self.program_brk;
+        let new_brk = if increment >= 0 {
+            old_brk.checked_add(increment as usize)
+        } else {
+            old_brk.checked_sub(increment.unsigned_abs())
+        };
+        let new_brk = match new_brk {
+            Some(brk) if brk >= self.heap_bottom => brk,
+            _ => return -1,
+        };
+        if !self
+            .memory_set
+            .set_brk(self.heap_bottom.into(), old_brk.into(), new_brk.into())
+        {
+            return -1;
+        }
+        self.program_brk = new_brk;
+        old_brk as isize
+    }
 
+    /// 将 [va, va + size) 的访问权限改为 mark（含义与 mmap 相同）。
+    /// mark 为 0 时页面仍然保留，但任何访问都会出错；RISC-V 不支持只写的页面，可写的页面总是同时可读
+    pub fn mprotect(&mut self, va: usize, size: usize, mut mark: usize) -> isize {
+        let va_ = VirtAddr::from(va);
+        if !va_.is_aligned() || (mark & !0x7) != 0 {
+            return -1;
+        }
+        if mark & 0x2 != 0 {
+            mark |= 0x1;
+        }
+        let mark_ = MapPermission::from_bits_truncate((mark << 1) as u8) | MapPermission::U;
+        let end_va = match va.checked_add(size) {
+            Some(end) => VirtAddr::from(end),
+            None => return -1,
+        };
+        if !self.memory_set.protect(va_, end_va, mark_) {
+            debug!("cannot protect [{:?}, {:?}) as {:?}", va_, end_va, mark_);
+            return -1;
+        }
+        0
+    }
+
+    /// 将编号为 id 的共享内存段以 mark 权限（含义与 mmap 相同）映射到 va 处，
+    /// va 为 0 时由内核从 MMAP_BASE 开始寻找空闲区间。成功时返回映射的起始地址。
+    pub fn shmat(&mut self, id: usize, va: usize, mark: usize) -> isize {
+        let va_ = VirtAddr::from(va);
+        if !va_.is_aligned() || (mark & !0x7) != 0 || (mark & 0x7) == 0 {
+            return -1;
+        }
+        let mark_ = MapPermission::from_bits_truncate((mark << 1) as u8) | MapPermission::U;
+        let pages = match shm_pages(id) {
+            Some(pages) => pages,
+            None => return -1,
+        };
+        let start_va = if va == 0 {
+            match self
+                .memory_set
+                .find_free_area(VirtAddr::from(MMAP_BASE).floor(), pages.len())
+            {
+                Some(vpn) => VirtAddr::from(vpn),
+                None => return -1,
+            }
+        } else {
+            va_
+        };
+        if !self.memory_set.attach_shared(start_va, pages, mark_) {
+            debug!("shm {} overlaps at {:?}", id, start_va);
+            return -1;
+        }
+        usize::from(start_va) as isize
+    }
+
+    /// 解除 va 处的共享内存段映射
+    pub fn shmdt(&mut self, va: usize) -> isize {
+        let va_ = VirtAddr::from(va);
+        if !va_.is_aligned() || !self.memory_set.detach_shared(va_) {
+            return -1;
+        }
         0
     }
 }
@@ -1,9 +1,11 @@
 mod context;
 
 use crate::config::{TRAMPOLINE, TRAP_CONTEXT};
+use crate::mm::MapPermission;
 use crate::syscall::syscall;
 use crate::task::{
-    current_trap_cx, current_user_token, exit_current_and_run_next, suspend_current_and_run_next,
+    current_trap_cx, current_user_token, exit_current_and_run_next, handle_page_fault,
+    suspend_current_and_run_next,
 };
 use crate::timer::set_next_trigger;
 
@@ -88,11 +90,28 @@ pub fn trap_handler() -> ! {
             cx.x[10] = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12]]) as usize;
             // x10(a0) 保存返回值; 这里修改的是用户态，应用程序上下文，a0 作为返回值
         }
+        // 缺页异常：先看看是不是按需分配的页面第一次被访问，是的话分配物理页帧之后重新执行这条指令
+        Trap::Exception(
+            e @ (Exception::LoadPageFault
+            | Exception::StorePageFault
+            | Exception::InstructionPageFault),
+        ) => {
+            let access = match e {
+                Exception::LoadPageFault => MapPermission::R,
+                Exception::StorePageFault => MapPermission::W,
+                _ => MapPermission::X,
+            };
+            if !handle_page_fault(stval, access) {
+                error!(
+                    "[kernel] {:?} in application: bad addr = {:#x} is not mapped or not {:?}, bad instruction = {:#x}, core dumped.",
+                    e, stval, access, cx.sepc
+                );
+                exit_current_and_run_next();
+            }
+        }
         // 分别处理应用程序出现访存错误和非法指令错误的情形。
         // 此时需要打印错误信息并调用 run_next_app 直接切换并运行下一个应用程序。
-        Trap::Exception(Exception::StoreFault)
-        | Trap::Exception(Exception::StorePageFault)
-        | Trap::Exception(Exception::LoadPageFault) => {
+        Trap::Exception(Exception::StoreFault) | Trap::Exception(Exception::LoadFault) => {
             // 写入内存错误
             error!(
                 "[kernel] PageFault in application, bad addr = {:#x}, bad instruction = {:#x}, core dumped.",
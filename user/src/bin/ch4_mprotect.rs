@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{mmap, mprotect, munmap, shmat, shmdt, shmget, shmrm, IPC_PRIVATE};
+
+/*
+理想结果：输出 Test 04_mprotect OK!
+像 JIT 编译器一样先在可读写的页面中生成代码，再把它改为可读可执行后调用。
+*/
+
+/// addi a0, zero, 42
+const LI_A0_42: u32 = 0x02a0_0513;
+/// jalr zero, 0(ra)
+const RET: u32 = 0x0000_8067;
+
+#[no_mangle]
+fn main() -> i32 {
+    let start: usize = 0x10000000;
+    let len: usize = 4096;
+    assert_eq!(mmap(start, len * 3, 3), 0);
+
+    // 生成代码，然后将中间一页改为 RX：原来的逻辑段被切成三段
+    let code = (start + len) as *mut u32;
+    unsafe {
+        code.write_volatile(LI_A0_42);
+        code.add(1).write_volatile(RET);
+    }
+    assert_eq!(mprotect(start + len, len, 5), 0);
+    let f: extern "C" fn() -> usize = unsafe { core::mem::transmute(code) };
+    unsafe {
+        core::arch::asm!("fence.i");
+    }
+    assert_eq!(f(), 42);
+
+    // 前后两页仍然可以读写
+    for addr in [start, start + len * 2] {
+        let p = addr as *mut u8;
+        unsafe {
+            p.write_volatile(7);
+            assert_eq!(p.read_volatile(), 7);
+        }
+    }
+
+    // 改回 RW 之后可以重新生成代码
+    assert_eq!(mprotect(start + len, len, 3), 0);
+    unsafe {
+        code.write_volatile(LI_A0_42 + (1 << 20));
+    }
+    assert_eq!(mprotect(start + len, len, 5), 0);
+    unsafe {
+        core::arch::asm!("fence.i");
+    }
+    assert_eq!(f(), 43);
+
+    // 不合法的参数和未映射的区间
+    assert_eq!(mprotect(start + 1, len, 3), -1);
+    assert_eq!(mprotect(start, len, 8), -1);
+    assert_eq!(mprotect(start, len * 4, 3), -1);
+    assert_eq!(mprotect(start - len, len * 2, 3), -1);
+
+    // 没有任何权限的页面仍然保留着内容，恢复权限之后可以继续访问
+    let p = start as *mut u8;
+    assert_eq!(mprotect(start, len, 0), 0);
+    assert_eq!(mprotect(start, len, 3), 0);
+    unsafe {
+        assert_eq!(p.read_volatile(), 7);
+    }
+    // 只写的页面同时也是可读的
+    assert_eq!(mprotect(start, len, 2), 0);
+    unsafe {
+        p.write_volatile(8);
+        assert_eq!(p.read_volatile(), 8);
+    }
+
+    // 可以同时修改相邻的多个逻辑段，munmap 也能跨越它们
+    assert_eq!(mprotect(start, len * 3, 1), 0);
+    assert_eq!(munmap(start, len * 3), 0);
+
+    // 共享内存段的权限不能超出 shmat 时给出的权限
+    let id = shmget(IPC_PRIVATE, len);
+    assert!(id >= 0);
+    let shm = shmat(id as usize, 0, 3);
+    assert!(shm > 0);
+    assert_eq!(mprotect(shm as usize, len, 5), -1);
+    assert_eq!(mprotect(shm as usize, len, 1), 0);
+    assert_eq!(mprotect(shm as usize, len, 3), 0);
+    assert_eq!(shmdt(shm as usize), 0);
+    // 只读映射的共享内存段不能再变为可写
+    let shm = shmat(id as usize, 0, 1);
+    assert!(shm > 0);
+    assert_eq!(mprotect(shm as usize, len, 3), -1);
+    assert_eq!(shmdt(shm as usize), 0);
+    assert_eq!(shmrm(id as usize), 0);
+
+    println!("Test 04_mprotect OK!");
+    0
+}
@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::vec::Vec;
+use user_lib::sbrk;
+
+/*
+理想结果：输出 Test 04_sbrk OK!
+*/
+
+extern "C" {
+    /// .bss 的末尾，也就是 ELF 映像的末尾，由 linker.ld 给出
+    fn end_bss();
+}
+
+#[no_mangle]
+fn main() -> i32 {
+    let page: isize = 4096;
+
+    // 堆从 ELF 映像末尾所在页面之后开始
+    let origin = sbrk(0);
+    let image_end = end_bss as usize;
+    assert_eq!(origin as usize, (image_end + 4095) / 4096 * 4096);
+
+    // 直接使用 sbrk 扩展和收缩堆
+    assert_eq!(sbrk(page * 2), origin);
+    assert_eq!(sbrk(0), origin + page * 2);
+    for addr in origin..origin + page * 2 {
+        unsafe { (addr as *mut u8).write_volatile(addr as u8) };
+    }
+    for addr in origin..origin + page * 2 {
+        assert_eq!(unsafe { (addr as *const u8).read_volatile() }, addr as u8);
+    }
+    assert_eq!(sbrk(-page), origin + page * 2);
+    assert_eq!(sbrk(-page), origin + page);
+    assert_eq!(sbrk(0), origin);
+
+    // 堆顶不能低于堆底
+    assert_eq!(sbrk(-origin), -1);
+    assert_eq!(sbrk(0), origin);
+
+    // 超出初始的 16 KiB 之后，用户堆通过 sbrk 自动扩展
+    let mut v: Vec<usize> = Vec::new();
+    for i in 0..64 * 1024 {
+        v.push(i);
+    }
+    for (i, x) in v.iter().enumerate() {
+        assert_eq!(*x, i);
+    }
+    assert!(sbrk(0) > origin);
+    println!("Test 04_sbrk OK!");
+    0
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use user_lib::{get_time, shmat, shmdt, shmget, shmrm, yield_};
+
+/*
+与 ch4_shm_producer 一起运行，理想结果：输出 Test 04_shm consumer OK!
+同时输出通过共享内存传递数据的速度。
+*/
+
+const SHM_KEY: usize = 0x5348_4d;
+const PAGE_SIZE: usize = 4096;
+const DATA_PAGES: usize = 16;
+const ROUNDS: usize = 64;
+
+const EMPTY: usize = 0;
+const FULL: usize = 1;
+const DONE: usize = 2;
+
+#[no_mangle]
+fn main() -> i32 {
+    let id = shmget(SHM_KEY, (DATA_PAGES + 1) * PAGE_SIZE);
+    assert!(id >= 0);
+    let base = shmat(id as usize, 0, 3);
+    assert!(base > 0);
+    let base = base as usize;
+    // 同一个段可以在一个地址空间中映射多次，两个映射看到同样的内容
+    let alias = shmat(id as usize, 0, 1);
+    assert!(alias > 0 && alias as usize != base);
+    let state = unsafe { &*(base as *const AtomicUsize) };
+    let data = unsafe {
+        core::slice::from_raw_parts((base + PAGE_SIZE) as *const u8, DATA_PAGES * PAGE_SIZE)
+    };
+    let alias_data = unsafe {
+        core::slice::from_raw_parts(
+            (alias as usize + PAGE_SIZE) as *const u8,
+            DATA_PAGES * PAGE_SIZE,
+        )
+    };
+    let start = get_time();
+    for round in 0..ROUNDS {
+        while state.load(Ordering::Acquire) != FULL {
+            yield_();
+        }
+        for (i, byte) in data.iter().enumerate() {
+            assert_eq!(*byte, (round + i) as u8);
+        }
+        assert_eq!(alias_data[round], data[round]);
+        state.store(EMPTY, Ordering::Release);
+    }
+    while state.load(Ordering::Acquire) != DONE {
+        yield_();
+    }
+    let elapsed = get_time() - start;
+    println!(
+        "shm: {} KiB in {} ms",
+        ROUNDS * DATA_PAGES * PAGE_SIZE / 1024,
+        elapsed
+    );
+    assert_eq!(shmdt(alias as usize), 0);
+    assert_eq!(shmdt(base), 0);
+    // 已经解除映射的地址不能再次解除
+    assert_eq!(shmdt(base), -1);
+    assert_eq!(shmrm(id as usize), 0);
+    assert_eq!(shmat(id as usize, 0, 3), -1);
+    println!("Test 04_shm consumer OK!");
+    0
+}
@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use user_lib::{shmat, shmdt, shmget, yield_};
+
+/*
+与 ch4_shm_consumer 一起运行，理想结果：输出 Test 04_shm producer OK!
+两个应用通过键 SHM_KEY 找到同一个共享内存段：第一页的开头是状态字，之后的页面是数据区。
+生产者在状态字为 EMPTY 时填满数据区并置为 FULL，消费者检查数据之后再置回 EMPTY，全程没有任何拷贝。
+*/
+
+const SHM_KEY: usize = 0x5348_4d;
+const PAGE_SIZE: usize = 4096;
+const DATA_PAGES: usize = 16;
+const ROUNDS: usize = 64;
+
+const EMPTY: usize = 0;
+const FULL: usize = 1;
+const DONE: usize = 2;
+
+#[no_mangle]
+fn main() -> i32 {
+    let id = shmget(SHM_KEY, (DATA_PAGES + 1) * PAGE_SIZE);
+    assert!(id >= 0);
+    let base = shmat(id as usize, 0, 3);
+    assert!(base > 0);
+    let base = base as usize;
+    let state = unsafe { &*(base as *const AtomicUsize) };
+    let data = unsafe {
+        core::slice::from_raw_parts_mut((base + PAGE_SIZE) as *mut u8, DATA_PAGES * PAGE_SIZE)
+    };
+    for round in 0..ROUNDS {
+        while state.load(Ordering::Acquire) != EMPTY {
+            yield_();
+        }
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = (round + i) as u8;
+        }
+        state.store(FULL, Ordering::Release);
+    }
+    while state.load(Ordering::Acquire) != EMPTY {
+        yield_();
+    }
+    state.store(DONE, Ordering::Release);
+    // 消费者仍然映射着这个段，解除映射不会回收它的物理页帧
+    assert_eq!(shmdt(base), 0);
+    println!("Test 04_shm producer OK!");
+    0
+}
@@ -16,14 +16,52 @@ extern crate bitflags;
 use alloc::vec::Vec;
 use buddy_system_allocator::LockedHeap;
 pub use console::{flush, STDIN, STDOUT};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::NonNull;
 pub use syscall::*;
 
 const USER_HEAP_SIZE: usize = 16384;
 
 static mut HEAP_SPACE: [u8; USER_HEAP_SIZE] = [0; USER_HEAP_SIZE];
 
+/// 堆每次至少通过 sbrk 扩展这么多字节
+const HEAP_GROW_SIZE: usize = 4096;
+
+/// 先使用 HEAP_SPACE，空间不足时通过 sbrk 扩展的堆
+struct GrowableHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        loop {
+            if let Ok(ptr) = heap.alloc(layout) {
+                return ptr.as_ptr();
+            }
+            // 伙伴分配器中 2^k 字节的块必须按 2^k 对齐，扩展两倍的空间才能保证其中有一个完整的块
+            let block = layout
+                .size()
+                .max(layout.align())
+                .max(HEAP_GROW_SIZE)
+                .next_power_of_two();
+            let increment = match block.checked_mul(2) {
+                Some(increment) if increment <= isize::MAX as usize => increment,
+                _ => return core::ptr::null_mut(),
+            };
+            let start = sys_sbrk(increment as isize);
+            if start < 0 {
+                // 由 handle_alloc_error 报告
+                return core::ptr::null_mut();
+            }
+            heap.add_to_heap(start as usize, start as usize + increment);
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().dealloc(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
 #[global_allocator]
-static HEAP: LockedHeap = LockedHeap::empty();
+static HEAP: GrowableHeap = GrowableHeap(LockedHeap::empty());
 
 #[alloc_error_handler]
 pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
@@ -49,7 +87,8 @@ fn clear_bss() {
 pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
     clear_bss();
     unsafe {
-        HEAP.lock()
+        HEAP.0
+            .lock()
             .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
     }
     let mut v: Vec<&'static str> = Vec::new();
@@ -296,6 +335,35 @@ pub fn munmap(start: usize, len: usize) -> isize {
     sys_munmap(start, len)
 }
 
+/// 将程序断点移动 increment 字节，返回原来的程序断点，失败时返回 -1
+pub fn sbrk(increment: isize) -> isize {
+    sys_sbrk(increment)
+}
+
+pub fn mprotect(start: usize, len: usize, prot: usize) -> isize {
+    sys_mprotect(start, len, prot)
+}
+
+/// shmget 的键为 IPC_PRIVATE 时总是创建新的共享内存段
+pub const IPC_PRIVATE: usize = 0;
+const IPC_RMID: usize = 0;
+
+pub fn shmget(key: usize, size: usize) -> isize {
+    sys_shmget(key, size)
+}
+
+pub fn shmat(id: usize, start: usize, prot: usize) -> isize {
+    sys_shmat(id, start, prot)
+}
+
+pub fn shmdt(start: usize) -> isize {
+    sys_shmdt(start)
+}
+
+pub fn shmrm(id: usize) -> isize {
+    sys_shmctl(id, IPC_RMID)
+}
+
 pub fn spawn(path: &str) -> isize {
     sys_spawn(path)
 }